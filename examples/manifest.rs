@@ -1,12 +1,27 @@
+use opencontainers::distribution::cassette::CassetteTransport;
 use opencontainers::image::ImagePlatformSelector;
 use opencontainers::Registry;
 
+use std::sync::Arc;
+
 fn main() {
     pretty_env_logger::init();
 
-    let registry = Registry::new("https://registry-1.docker.io");
+    // Replay a synthetic pull of hello-world instead of talking to a live
+    // registry. Use `Registry::new` to pull from a real one instead.
+    let transport = CassetteTransport::replay_file(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/image/test/synthetic-hello-world.cassette.json"
+    ))
+    .expect("Could not load cassette");
+
+    let registry = Registry::builder("https://registry.example.com")
+        .transport(Arc::new(transport))
+        .build()
+        .expect("Could not build registry");
+
     let image = registry
-        .image::<ImagePlatformSelector>("library/hello-world", "latest")
+        .image::<ImagePlatformSelector>(("library/hello-world", "latest"))
         .expect("Could not get image");
 
     println!("{:#?}", image.manifest());
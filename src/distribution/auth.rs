@@ -1,26 +1,79 @@
+use crate::distribution::token_cache::{TokenCache, TokenKey};
+use crate::distribution::transport::{HttpRequest, Transport};
 use crate::distribution::RegistryError;
 
 use chrono::{DateTime, Utc};
 use hyperx::header::Header;
-use reqwest::{self, Client};
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{self, Method, Url};
 use www_authenticate::{RawChallenge, WwwAuthenticate};
 
+use std::convert::TryFrom;
 use std::fmt;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+/// Lifetime of tokens without `expires_in`, as defined in the [token
+/// specification](https://docs.docker.com/registry/spec/auth/token/).
+const DEFAULT_TOKEN_LIFETIME: u64 = 60;
+
+/// Client ID sent to authorization services using OAuth2.
+const OAUTH_CLIENT_ID: &str = "opencontainers-rs";
+
+#[derive(Clone, PartialEq)]
 pub enum Credential {
+    /// A bearer token obtained from an authorization service.
     Token(Token),
+
+    /// Username and password, used for registries requiring Basic
+    /// authentication and to obtain tokens for private repositories.
+    Basic { username: String, password: String },
+
+    /// A refresh token, as stored by `docker login` for registries using
+    /// OAuth2, which is exchanged for tokens with the authorization service.
+    IdentityToken(String),
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credential::Token(t) => f.debug_tuple("Token").field(t).finish(),
+            Credential::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Credential::IdentityToken(_) => {
+                f.debug_tuple("IdentityToken").field(&"<redacted>").finish()
+            }
+        }
+    }
 }
 
 pub trait Authenticate {
     fn authenticate(self, auth: &Credential) -> Self;
 }
 
-impl Authenticate for reqwest::RequestBuilder {
-    fn authenticate(self, auth: &Credential) -> Self {
-        match auth {
-            Credential::Token(t) => self.bearer_auth(t),
+impl Authenticate for HttpRequest {
+    fn authenticate(mut self, auth: &Credential) -> Self {
+        let value = match auth {
+            Credential::Token(t) => format!("Bearer {}", t),
+            Credential::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(&format!("{}:{}", username, password))
+            ),
+            // Identity tokens are only accepted by the authorization service.
+            Credential::IdentityToken(_) => return self,
+        };
+
+        match HeaderValue::from_str(&value) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                self.headers.insert(AUTHORIZATION, value);
+            }
+            Err(_) => warn!("Credential can not be sent in a header, ignoring"),
         }
+
+        self
     }
 }
 
@@ -31,6 +84,33 @@ struct BearerChallenge {
     pub scopes: Option<Vec<String>>,
 }
 
+impl BearerChallenge {
+    /// Return a copy of this challenge requesting additional scopes.
+    fn with_scopes(&self, scopes: &[String]) -> Self {
+        let mut chall = self.clone();
+
+        if !scopes.is_empty() {
+            let requested = chall.scopes.get_or_insert_with(Vec::new);
+            for scope in scopes {
+                if !requested.contains(scope) {
+                    requested.push(scope.clone());
+                }
+            }
+        }
+
+        chall
+    }
+
+    /// Return the key under which tokens for this challenge are cached.
+    fn cache_key(&self) -> Option<TokenKey> {
+        Some(TokenKey {
+            realm: self.realm.clone()?,
+            service: self.service.clone(),
+            scopes: self.scopes.iter().flatten().cloned().collect(),
+        })
+    }
+}
+
 impl www_authenticate::Challenge for BearerChallenge {
     fn challenge_name() -> &'static str {
         "Bearer"
@@ -76,33 +156,218 @@ impl www_authenticate::Challenge for BearerChallenge {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "TokenResponse")]
 pub struct Token {
-    // FIXME: allow accesss_token here.
-    //
-    // From the spec (https://docs.docker.com/registry/spec/auth/token/):
-    // For compatibility with OAuth 2.0, we will also accept token under the
-    // name access_token. At least one of these fields must be specified, but
-    // both may also appear (for compatibility with older clients).
-    // When both are specified, they should be equivalent; if they differ
-    // the client's choice is undefined.
     pub token: String,
     pub expires_in: Option<u64>,
     pub issued_at: Option<DateTime<Utc>>,
     pub refresh_token: Option<String>,
 }
 
+/// Response of an authorization service.
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+    issued_at: Option<DateTime<Utc>>,
+    refresh_token: Option<String>,
+}
+
+impl TryFrom<TokenResponse> for Token {
+    type Error = &'static str;
+
+    fn try_from(response: TokenResponse) -> Result<Self, Self::Error> {
+        // From the spec (https://docs.docker.com/registry/spec/auth/token/):
+        // For compatibility with OAuth 2.0, we will also accept token under the
+        // name access_token. At least one of these fields must be specified, but
+        // both may also appear (for compatibility with older clients).
+        // When both are specified, they should be equivalent; if they differ
+        // the client's choice is undefined.
+        let token = response
+            .token
+            .or(response.access_token)
+            .ok_or("missing field `token` or `access_token`")?;
+
+        Ok(Token {
+            token,
+            expires_in: response.expires_in,
+            issued_at: response.issued_at,
+            refresh_token: response.refresh_token,
+        })
+    }
+}
+
+/// Grant used to obtain a token using OAuth2.
+enum Grant<'a> {
+    Password(&'a str, &'a str),
+    RefreshToken(&'a str),
+}
+
 impl Token {
-    fn get(client: &Client, chall: &BearerChallenge) -> Result<Token, RegistryError> {
+    /// Return the remaining lifetime of the token.
+    ///
+    /// Tokens without `issued_at` are assumed to have been issued just now.
+    pub fn lifetime(&self) -> Duration {
+        let lifetime = Duration::from_secs(self.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME));
+
+        let elapsed = self
+            .issued_at
+            .and_then(|issued_at| Utc::now().signed_duration_since(issued_at).to_std().ok())
+            .unwrap_or_default();
+
+        lifetime.checked_sub(elapsed).unwrap_or_default()
+    }
+
+    /// Obtain a token for a challenge.
+    ///
+    /// Like Docker, the token is requested using the [OAuth2
+    /// flow](https://docs.docker.com/registry/spec/auth/oauth/) if a refresh
+    /// token is available, or if `force_oauth` is set and a username and
+    /// password are available. Otherwise, or if the authorization service
+    /// rejects the OAuth2 request, the GET flow is used. Refresh tokens
+    /// returned by the authorization service are kept in `cache` for later
+    /// requests.
+    fn get(
+        transport: &dyn Transport,
+        chall: &BearerChallenge,
+        login: Option<&Credential>,
+        force_oauth: bool,
+        cache: &TokenCache,
+    ) -> Result<Token, RegistryError> {
         #[allow(clippy::or_fun_call)]
         let realm = chall
             .realm
-            .clone()
+            .as_ref()
             .ok_or(RegistryError::InvalidAuthenticationChallenge(
                 "No Realm provided".into(),
             ))?;
 
-        let request = client.get(&realm);
+        // Authorization services may rotate refresh tokens with every grant.
+        let keep_refresh_token = |token: &Token| {
+            if let Some(ref refresh_token) = token.refresh_token {
+                cache.set_refresh_token(realm, chall.service.as_ref(), refresh_token);
+            }
+        };
+
+        if let Some(refresh_token) = cache.refresh_token(realm, chall.service.as_ref()) {
+            match Self::get_oauth(
+                transport,
+                realm,
+                chall,
+                &Grant::RefreshToken(&refresh_token),
+            ) {
+                Ok(token) => {
+                    keep_refresh_token(&token);
+                    return Ok(token);
+                }
+                Err(e) => {
+                    info!("Could not refresh token, logging in again: {}", e);
+                    cache.remove_refresh_token(realm, chall.service.as_ref());
+                }
+            }
+        }
+
+        let grant = match login {
+            Some(Credential::IdentityToken(refresh_token)) => {
+                Some(Grant::RefreshToken(refresh_token))
+            }
+            Some(Credential::Basic { username, password }) if force_oauth => {
+                Some(Grant::Password(username, password))
+            }
+            _ => None,
+        };
+
+        if let Some(grant) = grant {
+            match Self::get_oauth(transport, realm, chall, &grant) {
+                Ok(token) => {
+                    keep_refresh_token(&token);
+                    return Ok(token);
+                }
+                Err(RegistryError::CouldNotGetToken(status)) if status.is_client_error() => {
+                    info!(
+                        "OAuth2 request rejected by {} with {}, using GET",
+                        realm, status
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Self::get_basic(transport, realm, chall, login)
+    }
+
+    /// Obtain a token using a POST request with an OAuth2 grant.
+    fn get_oauth(
+        transport: &dyn Transport,
+        realm: &str,
+        chall: &BearerChallenge,
+        grant: &Grant,
+    ) -> Result<Token, RegistryError> {
+        let scope = chall.scopes.iter().flatten().cloned().collect::<Vec<_>>();
+        let scope = scope.join(" ");
+
+        let mut form: Vec<(&str, &str)> = vec![
+            ("client_id", OAUTH_CLIENT_ID),
+            ("access_type", "offline"),
+            ("scope", &scope),
+        ];
+
+        if let Some(ref service) = chall.service {
+            form.push(("service", service));
+        }
+
+        match grant {
+            Grant::Password(username, password) => {
+                form.push(("grant_type", "password"));
+                form.push(("username", username));
+                form.push(("password", password));
+            }
+            Grant::RefreshToken(refresh_token) => {
+                form.push(("grant_type", "refresh_token"));
+                form.push(("refresh_token", refresh_token));
+            }
+        }
+
+        let url = Url::parse(realm).map_err(RegistryError::UrlParseError)?;
+        let mut request = HttpRequest::new(Method::POST, url);
+        request.headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        request.body = Some(
+            serde_urlencoded::to_string(&form)
+                .expect("Form is serializable")
+                .into_bytes(),
+        );
+
+        let mut response = transport.send(request)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(RegistryError::CouldNotGetToken(status));
+        }
+
+        response.json().map_err(RegistryError::ReqwestError)
+    }
+
+    /// Obtain a token using a GET request, passing a username and password
+    /// if available.
+    fn get_basic(
+        transport: &dyn Transport,
+        realm: &str,
+        chall: &BearerChallenge,
+        login: Option<&Credential>,
+    ) -> Result<Token, RegistryError> {
+        let url = Url::parse(realm).map_err(RegistryError::UrlParseError)?;
+        let mut request = HttpRequest::new(Method::GET, url);
+
+        // Static credentials are passed to the token endpoint, which then
+        // decides which scopes to grant.
+        if let Some(login @ Credential::Basic { .. }) = login {
+            request = request.authenticate(login);
+        }
 
         let mut query_params: Vec<(&str, &str)> = vec![];
 
@@ -119,9 +384,11 @@ impl Token {
             query_params.push(("service", &service));
         }
 
-        let request = request.query(&query_params);
+        if !query_params.is_empty() {
+            request.url.query_pairs_mut().extend_pairs(query_params);
+        }
 
-        let mut response = request.send().map_err(RegistryError::ReqwestError)?;
+        let mut response = transport.send(request)?;
 
         let status = response.status();
         if !status.is_success() {
@@ -140,28 +407,290 @@ impl fmt::Display for Token {
     }
 }
 
-pub fn do_challenge(
-    client: &Client,
+/// Answer an authentication challenge.
+///
+/// `extra_scopes` are requested in addition to the scopes given in the
+/// challenge, e.g. to pull from a second repository in the same request.
+///
+/// `login` holds static credentials for the registry, if any. These are
+/// used to answer `Basic` challenges, and are passed on to the authorization
+/// service when answering `Bearer` challenges. Usernames and passwords are
+/// only sent using the OAuth2 password grant if `force_oauth` is set.
+///
+/// Tokens are taken from `cache` if possible, and newly obtained tokens are
+/// added to it. Each credential is returned along with its cache key, if it
+/// is a token.
+pub(crate) fn do_challenge(
+    transport: &dyn Transport,
     authenticate: &reqwest::header::HeaderValue,
-) -> Result<Vec<Credential>, RegistryError> {
+    extra_scopes: &[String],
+    login: Option<&Credential>,
+    force_oauth: bool,
+    cache: &TokenCache,
+) -> Result<Vec<(Option<TokenKey>, Credential)>, RegistryError> {
     let raw: hyperx::header::Raw = authenticate.as_bytes().into();
 
-    #[allow(clippy::or_fun_call)]
-    let challenges = WwwAuthenticate::parse_header(&raw)
-        .map_err(|_| RegistryError::InvalidAuthenticationChallenge(format!("{:?}", authenticate)))?
-        .get::<BearerChallenge>()
-        .ok_or(RegistryError::InvalidAuthenticationChallenge(
-            "No Bearer Challenge provided".into(),
-        ))?;
+    let challenges = WwwAuthenticate::parse_header(&raw).map_err(|_| {
+        RegistryError::InvalidAuthenticationChallenge(format!("{:?}", authenticate))
+    })?;
+
+    if let Some(bearer) = challenges.get::<BearerChallenge>() {
+        let auths: Vec<(Option<TokenKey>, Credential)> = bearer
+            .iter()
+            .map(|c| c.with_scopes(extra_scopes))
+            .filter_map(|c| {
+                let key = c.cache_key();
+
+                if let Some(token) = key.as_ref().and_then(|key| cache.get(key)) {
+                    debug!("Using cached token for {:?}", key);
+                    return Some((key, Credential::Token(token)));
+                }
+
+                let token = Token::get(transport, &c, login, force_oauth, cache).ok()?;
+
+                if let Some(ref key) = key {
+                    cache.insert(key.clone(), token.clone());
+                }
+
+                Some((key, Credential::Token(token)))
+            })
+            .collect();
+
+        info!("got credentials: {:?}", auths);
+
+        return Ok(auths);
+    }
+
+    if challenges.get_raw("Basic").is_some() {
+        return match login {
+            Some(login @ Credential::Basic { .. }) => Ok(vec![(None, login.clone())]),
+            _ => Err(RegistryError::CouldNotAuthenticate),
+        };
+    }
+
+    Err(RegistryError::InvalidAuthenticationChallenge(
+        "No Bearer or Basic Challenge provided".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::transport::{json_response, MemoryTransport};
+
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_challenge_with_scopes() {
+        let chall = BearerChallenge {
+            realm: Some("https://auth.docker.io/token".into()),
+            service: Some("registry.docker.io".into()),
+            scopes: Some(vec!["repository:foo:pull,push".into()]),
+        };
+
+        let extended = chall.with_scopes(&[
+            "repository:bar:pull".into(),
+            "repository:foo:pull,push".into(),
+        ]);
+
+        assert_eq!(
+            extended.scopes,
+            Some(vec![
+                "repository:foo:pull,push".into(),
+                "repository:bar:pull".into()
+            ])
+        );
+        assert_eq!(chall.with_scopes(&[]), chall);
+    }
+
+    #[test]
+    fn test_cached_bearer_challenge() {
+        let transport = MemoryTransport::new();
+        let authenticate = "Bearer realm=\"https://auth.example.com/token\",\
+                            service=\"registry.example.com\",\
+                            scope=\"repository:foo:pull\""
+            .parse()
+            .unwrap();
+
+        let key = TokenKey {
+            realm: "https://auth.example.com/token".into(),
+            service: Some("registry.example.com".into()),
+            scopes: vec!["repository:foo:pull".to_owned()].into_iter().collect(),
+        };
+        let token = Token {
+            token: "cached".into(),
+            expires_in: Some(300),
+            issued_at: Some(Utc::now()),
+            refresh_token: None,
+        };
+
+        let cache = TokenCache::new();
+        cache.insert(key.clone(), token.clone());
+
+        // The token is not requested from the (unreachable) realm.
+        let credentials = do_challenge(&transport, &authenticate, &[], None, false, &cache)
+            .expect("Could not answer Bearer challenge");
+        assert_eq!(credentials, vec![(Some(key), Credential::Token(token))]);
+    }
+
+    #[test]
+    fn test_token_response() {
+        let token: Token = serde_json::from_str(r#"{"token": "foo", "expires_in": 300}"#)
+            .expect("Could not parse token");
+        assert_eq!(token.token, "foo");
+        assert_eq!(token.expires_in, Some(300));
 
-    let auths: Vec<Credential> = challenges
-        .iter()
-        .map(|c| Token::get(&client, c))
-        .filter_map(Result::ok)
-        .map(Credential::Token)
-        .collect();
+        let token: Token = serde_json::from_str(
+            r#"{"access_token": "bar", "refresh_token": "baz", "scope": "repository:foo:pull"}"#,
+        )
+        .expect("Could not parse OAuth2 token");
+        assert_eq!(token.token, "bar");
+        assert_eq!(token.refresh_token.as_deref(), Some("baz"));
 
-    info!("got credentials: {:?}", auths);
+        let token: Token = serde_json::from_str(r#"{"token": "foo", "access_token": "foo"}"#)
+            .expect("Could not parse token with both fields");
+        assert_eq!(token.token, "foo");
 
-    Ok(auths)
+        serde_json::from_str::<Token>(r#"{"expires_in": 300}"#)
+            .expect_err("Parsing token without token succeeded");
+    }
+
+    #[test]
+    fn test_token_lifetime() {
+        let mut token = Token {
+            token: "secret".into(),
+            expires_in: None,
+            issued_at: None,
+            refresh_token: None,
+        };
+        assert_eq!(token.lifetime(), Duration::from_secs(60));
+
+        token.expires_in = Some(300);
+        token.issued_at = Some(Utc::now() - chrono::Duration::seconds(100));
+        let lifetime = token.lifetime().as_secs();
+        assert!(lifetime <= 200 && lifetime >= 190, "{}", lifetime);
+
+        token.issued_at = Some(Utc::now() - chrono::Duration::seconds(400));
+        assert_eq!(token.lifetime(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_basic_challenge() {
+        let transport = MemoryTransport::new();
+        let authenticate = "Basic realm=\"Registry Realm\"".parse().unwrap();
+        let login = Credential::Basic {
+            username: "user".into(),
+            password: "secret".into(),
+        };
+
+        let cache = TokenCache::new();
+
+        let credentials = do_challenge(&transport, &authenticate, &[], Some(&login), false, &cache)
+            .expect("Could not answer Basic challenge");
+        assert_eq!(credentials, vec![(None, login)]);
+
+        match do_challenge(&transport, &authenticate, &[], None, false, &cache) {
+            Err(RegistryError::CouldNotAuthenticate) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_credential_debug_redacts_password() {
+        let login = Credential::Basic {
+            username: "user".into(),
+            password: "secret".into(),
+        };
+
+        assert!(!format!("{:?}", login).contains("secret"));
+
+        let login = Credential::IdentityToken("secret".into());
+        assert!(!format!("{:?}", login).contains("secret"));
+    }
+
+    #[test]
+    fn test_token_flow() {
+        let realm = "https://auth.example.com/token";
+        let authenticate = "Bearer realm=\"https://auth.example.com/token\",\
+                            service=\"registry.example.com\""
+            .parse()
+            .unwrap();
+        let login = Credential::Basic {
+            username: "user".into(),
+            password: "secret".into(),
+        };
+
+        let get_token = |login: &Credential, force_oauth: bool| {
+            let transport = MemoryTransport::new()
+                .route(Method::GET, realm, |_| {
+                    json_response(StatusCode::OK, r#"{"token": "get"}"#)
+                })
+                .route(Method::POST, realm, |request| {
+                    let body = request.body.as_deref().unwrap_or_default();
+                    if body.windows(8).any(|w| w == b"password") {
+                        json_response(StatusCode::UNAUTHORIZED, r#"{"error": "invalid_grant"}"#)
+                    } else {
+                        json_response(StatusCode::OK, r#"{"access_token": "post"}"#)
+                    }
+                });
+
+            let credentials = do_challenge(
+                &transport,
+                &authenticate,
+                &[],
+                Some(login),
+                force_oauth,
+                &TokenCache::new(),
+            )
+            .expect("Could not answer Bearer challenge");
+
+            let methods: Vec<_> = transport.requests().into_iter().map(|r| r.method).collect();
+            match credentials.as_slice() {
+                [(_, Credential::Token(token))] => (token.token.clone(), methods),
+                other => panic!("unexpected credentials: {:?}", other),
+            }
+        };
+
+        // Usernames and passwords are sent using GET unless asked otherwise
+        assert_eq!(get_token(&login, false), ("get".into(), vec![Method::GET]));
+
+        // Rejected OAuth2 requests fall back to GET
+        assert_eq!(
+            get_token(&login, true),
+            ("get".into(), vec![Method::POST, Method::GET])
+        );
+
+        let identity = Credential::IdentityToken("refresh-me".into());
+        assert_eq!(
+            get_token(&identity, false),
+            ("post".into(), vec![Method::POST])
+        );
+    }
+
+    #[test]
+    fn test_token_flow_rotated_refresh_token() {
+        let realm = "https://auth.example.com/token";
+        let service = Some("registry.example.com".to_owned());
+        let authenticate = "Bearer realm=\"https://auth.example.com/token\",\
+                            service=\"registry.example.com\""
+            .parse()
+            .unwrap();
+
+        let transport = MemoryTransport::new().route(Method::POST, realm, |_| {
+            json_response(
+                StatusCode::OK,
+                r#"{"access_token": "post", "refresh_token": "rotated"}"#,
+            )
+        });
+
+        let cache = TokenCache::new();
+        cache.set_refresh_token(realm, service.as_ref(), "stale");
+
+        do_challenge(&transport, &authenticate, &[], None, false, &cache)
+            .expect("Could not answer Bearer challenge");
+        assert_eq!(
+            cache.refresh_token(realm, service.as_ref()).as_deref(),
+            Some("rotated")
+        );
+    }
 }
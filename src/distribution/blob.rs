@@ -0,0 +1,411 @@
+//! Downloading blobs, resuming interrupted downloads using `Range` requests.
+
+use crate::distribution::{Registry, RegistryError};
+use crate::image::manifest::{Digest, DigestAlgorithm};
+
+use failure::Fail;
+use sha2::Digest as _;
+
+use reqwest::header::{HeaderMap, CONTENT_RANGE, RANGE};
+use reqwest::{Method, StatusCode};
+
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Reader for the contents of a blob.
+///
+/// If the connection is interrupted, the download is resumed from the last
+/// byte received, retrying as configured by the registry's
+/// [RetryPolicy](crate::distribution::RetryPolicy).
+///
+/// The contents are hashed as they are read. As soon as the whole blob was
+/// read, reading fails with an [io::ErrorKind::InvalidData] error if they do
+/// not match the digest of the blob, or the size given to
+/// [expect_size](BlobReader::expect_size).
+pub struct BlobReader<'a> {
+    registry: RegistryRef<'a>,
+    url: String,
+    response: reqwest::Response,
+    offset: u64,
+    length: Option<u64>,
+    retries: u32,
+    digest: Digest,
+    hasher: sha2::Sha256,
+    expected_size: Option<u64>,
+    resume_error: Option<RegistryError>,
+}
+
+impl<'a> std::fmt::Debug for BlobReader<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "BlobReader {{ url: {}, offset: {}, length: {:?} }}",
+            self.url, self.offset, self.length
+        )
+    }
+}
+
+impl<'a> BlobReader<'a> {
+    /// Return the number of bytes read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Return the size of the blob, if the registry sent it.
+    pub fn size(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Expect the blob to have the given size, as recorded in its
+    /// descriptor.
+    ///
+    /// Reading fails as soon as the blob turns out to be longer.
+    pub fn expect_size(mut self, size: u64) -> Self {
+        self.expected_size = Some(size);
+        self.length.get_or_insert(size);
+        self
+    }
+
+    /// Check the contents read so far against the digest and the expected
+    /// size of the blob.
+    fn verify(&self) -> Result<(), RegistryError> {
+        match self.expected_size {
+            Some(size) if size != self.offset => {
+                return Err(RegistryError::SizeMismatch(size, self.offset))
+            }
+            _ => {}
+        }
+
+        let actual = match self.digest.algorithm {
+            DigestAlgorithm::Sha256 => Digest {
+                algorithm: DigestAlgorithm::Sha256,
+                hex: format!("{:x}", self.hasher.clone().result()),
+            },
+        };
+
+        if actual != self.digest {
+            return Err(RegistryError::DigestMismatch(self.digest.clone(), actual));
+        }
+
+        Ok(())
+    }
+
+    /// Request the rest of the blob, starting at the current offset.
+    fn resume(&mut self) -> Result<(), RegistryError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RANGE,
+            format!("bytes={}-", self.offset)
+                .parse()
+                .expect("Range header is valid"),
+        );
+
+        let mut response = self
+            .registry
+            .request(Method::GET, &self.url, Some(&headers), None)?;
+
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            let start = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .ok_or_else(|| RegistryError::MissingHeader("Content-Range".into()))
+                .and_then(|value| {
+                    content_range_start(value.to_str().unwrap_or_default())
+                        .ok_or_else(|| RegistryError::InvalidHeader(format!("{:?}", value)))
+                })?;
+
+            if start != self.offset {
+                return Err(RegistryError::InvalidHeader(format!(
+                    "Content-Range starts at {}, expected {}",
+                    start, self.offset
+                )));
+            }
+        } else {
+            // The registry does not support ranges, skip what we already have.
+            let skipped = io::copy(&mut (&mut response).take(self.offset), &mut io::sink())
+                .map_err(RegistryError::IoError)?;
+
+            if skipped != self.offset {
+                return Err(RegistryError::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Blob shorter than before",
+                )));
+            }
+        }
+
+        self.response = response;
+
+        Ok(())
+    }
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Once resuming failed, the old response must not be read from
+            // anymore, since it does not continue at the current offset.
+            let result = match self.resume_error {
+                Some(ref e) => Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    e.to_string(),
+                )),
+                None => self.response.read(buf),
+            };
+
+            let error = match result {
+                Ok(0)
+                    if !buf.is_empty() && matches!(self.length, Some(len) if self.offset < len) =>
+                {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed before the end of the blob",
+                    )
+                }
+                Ok(0) if !buf.is_empty() => {
+                    return self.verify().map(|_| 0).map_err(invalid_data);
+                }
+                Ok(n) => {
+                    self.hasher.input(&buf[..n]);
+                    self.offset += n as u64;
+                    if n > 0 {
+                        self.retries = 0;
+                    }
+
+                    // Readers such as decompressors stop at their own end
+                    // marker, so check as soon as the whole blob was read
+                    // instead of waiting for the end of the stream.
+                    match self.expected_size.or(self.length) {
+                        Some(size) if self.offset >= size => {
+                            return self.verify().map(|_| n).map_err(invalid_data);
+                        }
+                        _ => return Ok(n),
+                    }
+                }
+                Err(e) => e,
+            };
+
+            let policy = self.registry.retry_policy();
+            if self.retries >= policy.max_retries {
+                return Err(error);
+            }
+
+            warn!(
+                "Download of {} interrupted after {} bytes, resuming: {}",
+                self.url, self.offset, error
+            );
+
+            std::thread::sleep(policy.backoff(self.retries));
+            self.retries += 1;
+
+            self.resume_error = self.resume().err();
+            if let Some(ref e) = self.resume_error {
+                warn!("Could not resume download of {}: {}", self.url, e);
+            }
+        }
+    }
+}
+
+impl Registry {
+    /// Download a blob from a repository.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    /// use std::io::Read;
+    ///
+    ///# let registry = Registry::new("https://registry-1.docker.io");
+    /// let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b"
+    ///     .parse()
+    ///     .expect("Could not parse digest");
+    /// let mut data = Vec::new();
+    /// registry.get_blob("library/hello-world", &digest)
+    ///     .expect("Could not get blob")
+    ///     .read_to_end(&mut data)
+    ///     .expect("Could not read blob");
+    /// ```
+    pub fn get_blob(&self, name: &str, digest: &Digest) -> Result<BlobReader<'_>, RegistryError> {
+        BlobReader::open(RegistryRef::Borrowed(self), name, digest)
+    }
+
+    /// Download a blob, sharing the registry with the reader so that it can
+    /// be moved between threads.
+    pub(crate) fn get_blob_shared(
+        self: Arc<Self>,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<BlobReader<'static>, RegistryError> {
+        BlobReader::open(RegistryRef::Shared(self), name, digest)
+    }
+}
+
+/// Registry a [BlobReader] sends its requests with.
+enum RegistryRef<'a> {
+    Borrowed(&'a Registry),
+    Shared(Arc<Registry>),
+}
+
+impl<'a> Deref for RegistryRef<'a> {
+    type Target = Registry;
+
+    fn deref(&self) -> &Registry {
+        match self {
+            RegistryRef::Borrowed(registry) => registry,
+            RegistryRef::Shared(registry) => registry,
+        }
+    }
+}
+
+impl<'a> BlobReader<'a> {
+    fn open(registry: RegistryRef<'a>, name: &str, digest: &Digest) -> Result<Self, RegistryError> {
+        let url = format!("{}/v2/{}/blobs/{}", registry.url, name, digest);
+        let response = registry.request(Method::GET, &url, None, None)?;
+        let length = response.content_length();
+
+        Ok(BlobReader {
+            registry,
+            url,
+            response,
+            offset: 0,
+            length,
+            retries: 0,
+            digest: digest.clone(),
+            hasher: sha2::Sha256::new(),
+            expected_size: None,
+            resume_error: None,
+        })
+    }
+}
+
+/// Wrap an error found while verifying a blob, so that it can be recovered
+/// with [RegistryError::from_io].
+fn invalid_data(error: RegistryError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.compat())
+}
+
+/// Return the first byte of a `Content-Range` such as `bytes 100-199/200`.
+fn content_range_start(content_range: &str) -> Option<u64> {
+    content_range
+        .trim_start_matches("bytes ")
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::transport::{response, MemoryTransport};
+    use crate::distribution::RetryPolicy;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Create a registry serving `hello` as the blob with the given digest.
+    fn registry(digest: &Digest) -> Registry {
+        let url = format!("https://registry.example.com/v2/foo/blobs/{}", digest);
+        let transport =
+            MemoryTransport::new().route(Method::GET, &url, |_| response(StatusCode::OK, "hello"));
+        Registry::builder("https://registry.example.com")
+            .transport(Arc::new(transport))
+            .build()
+            .expect("Could not build registry")
+    }
+
+    fn read_blob(digest: &Digest, size: Option<u64>) -> Result<Vec<u8>, RegistryError> {
+        let registry = registry(digest);
+        let mut reader = registry.get_blob("foo", digest)?;
+        if let Some(size) = size {
+            reader = reader.expect_size(size);
+        }
+
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(RegistryError::from_io)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_verify_blob() {
+        let digest = Digest::sha256(b"hello");
+        assert_eq!(read_blob(&digest, None).unwrap(), b"hello");
+        assert_eq!(read_blob(&digest, Some(5)).unwrap(), b"hello");
+
+        match read_blob(&Digest::sha256(b"world"), None) {
+            Err(RegistryError::DigestMismatch(expected, got)) => {
+                assert_eq!(expected, Digest::sha256(b"world"));
+                assert_eq!(got, digest);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match read_blob(&digest, Some(3)) {
+            Err(RegistryError::SizeMismatch(3, got)) => assert!(got > 3),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match read_blob(&digest, Some(6)) {
+            Err(RegistryError::SizeMismatch(6, 5)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_blob_without_eof() {
+        let digest = Digest::sha256(b"world");
+        let registry = registry(&digest);
+        let mut reader = registry.get_blob("foo", &digest).unwrap();
+
+        // Reading exactly the size of the blob is enough to detect tampering.
+        let error = reader.read_exact(&mut [0; 5]).unwrap_err();
+        match RegistryError::from_io(error) {
+            RegistryError::DigestMismatch(expected, _) => assert_eq!(expected, digest),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_content_range_start() {
+        assert_eq!(content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(content_range_start("bytes 0-0/*"), Some(0));
+        assert_eq!(content_range_start("bytes */200"), None);
+    }
+
+    #[test]
+    fn test_failed_resume() {
+        let digest = Digest::sha256(b"hello!");
+        let url = format!("https://registry.example.com/v2/foo/blobs/{}", digest);
+        let transport = Arc::new(MemoryTransport::new().route(Method::GET, &url, |request| {
+            match request.headers.get(RANGE) {
+                Some(_) => response(StatusCode::RANGE_NOT_SATISFIABLE, ""),
+                // Without a Content-Length, the blob is expected to have
+                // the size from its descriptor.
+                None => http::Response::new(b"hello".to_vec()),
+            }
+        }));
+
+        let registry = Registry::builder("https://registry.example.com")
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy {
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .build()
+            .expect("Could not build registry");
+
+        let mut reader = registry.get_blob("foo", &digest).unwrap().expect_size(6);
+        let mut data = Vec::new();
+        let error = reader.read_to_end(&mut data).unwrap_err();
+        assert!(error.to_string().contains("416"), "{}", error);
+        assert_eq!(data, b"hello");
+        assert_eq!(transport.requests().len(), 3);
+
+        let error = reader.read(&mut [0; 16]).unwrap_err();
+        assert!(error.to_string().contains("416"), "{}", error);
+        assert_eq!(transport.requests().len(), 3);
+    }
+}
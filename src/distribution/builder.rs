@@ -0,0 +1,527 @@
+//! Configuration of the HTTP client used to talk to a registry.
+
+use crate::distribution::token_cache::TokenCache;
+use crate::distribution::transport::{HttpRequest, ReqwestTransport, Transport};
+use crate::distribution::{Registry, RegistryError, RetryPolicy};
+
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use reqwest::{Certificate, Client, Identity, Proxy, Url};
+
+use std::error::Error as StdError;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// User-Agent sent to registries unless configured otherwise.
+const DEFAULT_USER_AGENT: &str = concat!("opencontainers-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Marks the beginning of a certificate in a PEM bundle.
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Builder for a [Registry] with a custom HTTP client configuration.
+///
+/// Registries on `localhost` or a loopback address, as well as registries
+/// marked as [insecure](RegistryBuilder::insecure), are contacted using
+/// plain HTTP if the TLS handshake with them fails, like the Docker daemon
+/// does.
+///
+/// # Example
+/// ```
+///# extern crate opencontainers;
+///# use opencontainers::distribution::RegistryBuilder;
+/// use std::time::Duration;
+///
+/// let registry = RegistryBuilder::new("https://registry.example.com")
+///     .connect_timeout(Duration::from_secs(5))
+///     .user_agent("my-tool/1.0")
+///     .build()
+///     .expect("Could not build registry client");
+///# assert_eq!(registry.url, "https://registry.example.com");
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegistryBuilder {
+    url: String,
+    ca_certificates: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, String)>,
+    insecure: bool,
+    plain_http: bool,
+    proxy: Option<String>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    user_agent: String,
+    force_oauth: bool,
+    retry: RetryPolicy,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl RegistryBuilder {
+    /// Create a builder for the registry at the given URL.
+    ///
+    /// Note: The URL should **not** contain a trailing slash.
+    pub fn new(url: &str) -> Self {
+        RegistryBuilder {
+            url: url.into(),
+            ca_certificates: Vec::new(),
+            identity: None,
+            insecure: false,
+            plain_http: false,
+            proxy: None,
+            connect_timeout: None,
+            read_timeout: None,
+            user_agent: DEFAULT_USER_AGENT.into(),
+            force_oauth: false,
+            retry: RetryPolicy::default(),
+            transport: None,
+        }
+    }
+
+    /// Trust the certificates in a PEM encoded CA bundle in addition to the
+    /// system's trusted certificates.
+    pub fn ca_certificates(mut self, pem: &[u8]) -> Self {
+        self.ca_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Authenticate with a client certificate, given as a DER encoded PKCS#12
+    /// archive containing the certificate and its private key.
+    pub fn client_identity(mut self, pkcs12: &[u8], password: &str) -> Self {
+        self.identity = Some((pkcs12.to_vec(), password.into()));
+        self
+    }
+
+    /// Accept invalid certificates, and fall back to plain HTTP if the
+    /// registry cannot be reached using HTTPS.
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Always contact the registry using plain HTTP.
+    pub fn plain_http(mut self, plain_http: bool) -> Self {
+        self.plain_http = plain_http;
+        self
+    }
+
+    /// Send all requests through a proxy, such as `http://proxy:3128`.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Set the timeout for connecting to the registry.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for reading from and writing to the registry.
+    ///
+    /// Note that the backing client applies this timeout to connecting as
+    /// well.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the User-Agent sent to the registry.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Request tokens for a username and password using the OAuth2 password
+    /// grant instead of passing them to the authorization service in a GET
+    /// request, like `docker login` does for registries requiring it.
+    ///
+    /// If the authorization service rejects the OAuth2 request, the GET
+    /// request is still sent.
+    pub fn force_oauth(mut self, force_oauth: bool) -> Self {
+        self.force_oauth = force_oauth;
+        self
+    }
+
+    /// Set the policy for retrying failed requests and resuming interrupted
+    /// downloads.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Send requests through a custom transport, such as a
+    /// [MemoryTransport](crate::distribution::transport::MemoryTransport).
+    ///
+    /// The TLS, proxy, timeout and User-Agent options only apply to the
+    /// default transport, and are ignored if a custom transport is used.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Build the client and the registry interface.
+    ///
+    /// The registry is not contacted. If falling back to plain HTTP is
+    /// allowed for the registry, whether it supports HTTPS is found out by
+    /// the first request sent to it.
+    pub fn build(self) -> Result<Registry, RegistryError> {
+        let mut transport = match self.transport {
+            Some(ref transport) => transport.clone(),
+            None => Arc::new(ReqwestTransport::new(self.client()?)),
+        };
+
+        let parsed = Url::parse(&self.url).map_err(RegistryError::UrlParseError)?;
+        let https = parsed.scheme() == "https";
+
+        let url = if https && self.plain_http {
+            format!("http://{}", self.url.trim_start_matches("https://"))
+        } else {
+            self.url
+        };
+
+        if https && !self.plain_http && (self.insecure || is_localhost(&parsed)) {
+            transport = Arc::new(HttpFallback::new(transport, parsed));
+        }
+
+        Ok(Registry {
+            url,
+            transport,
+            tokens: TokenCache::new(),
+            login: None,
+            force_oauth: self.force_oauth,
+            retry: self.retry,
+        })
+    }
+
+    /// Build the HTTP client.
+    fn client(&self) -> Result<Client, RegistryError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(&self.user_agent)
+                .map_err(|_| RegistryError::InvalidHeader(self.user_agent.clone()))?,
+        );
+
+        let mut builder = Client::builder()
+            .gzip(true)
+            .default_headers(headers)
+            .danger_accept_invalid_certs(self.insecure);
+
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        for bundle in &self.ca_certificates {
+            for pem in pem_certificates(bundle) {
+                let certificate =
+                    Certificate::from_pem(pem).map_err(RegistryError::ReqwestError)?;
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some((ref pkcs12, ref password)) = self.identity {
+            let identity =
+                Identity::from_pkcs12_der(pkcs12, password).map_err(RegistryError::ReqwestError)?;
+            builder = builder.identity(identity);
+        }
+
+        if let Some(ref proxy) = self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).map_err(RegistryError::ReqwestError)?);
+        }
+
+        builder.build().map_err(RegistryError::ReqwestError)
+    }
+}
+
+/// Check whether a URL points to the local host.
+fn is_localhost(url: &Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return false,
+    };
+
+    match host.parse::<IpAddr>() {
+        Ok(address) => address.is_loopback(),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    }
+}
+
+/// Transport falling back to plain HTTP for a registry that does not speak
+/// TLS.
+///
+/// The first request to the registry that either succeeds or fails the TLS
+/// handshake decides which protocol is used for all following requests to
+/// it. Other errors, such as timeouts, leave the choice open. Requests to
+/// other hosts, such as authorization services, are sent unchanged.
+#[derive(Debug)]
+struct HttpFallback {
+    inner: Arc<dyn Transport>,
+    registry: Url,
+    use_http: Mutex<Option<bool>>,
+}
+
+impl HttpFallback {
+    fn new(inner: Arc<dyn Transport>, registry: Url) -> Self {
+        HttpFallback {
+            inner,
+            registry,
+            use_http: Mutex::new(None),
+        }
+    }
+
+    /// Check whether a request is sent to the registry using HTTPS.
+    fn is_registry(&self, url: &Url) -> bool {
+        url.scheme() == "https"
+            && url.host_str() == self.registry.host_str()
+            && url.port_or_known_default() == self.registry.port_or_known_default()
+    }
+}
+
+impl Transport for HttpFallback {
+    fn send(&self, mut request: HttpRequest) -> Result<reqwest::Response, RegistryError> {
+        if !self.is_registry(&request.url) {
+            return self.inner.send(request);
+        }
+
+        let use_http = *self.use_http.lock().expect("Protocol choice poisoned");
+        match use_http {
+            Some(false) => return self.inner.send(request),
+            Some(true) => {}
+            None => match self.inner.send(request.clone()) {
+                Ok(response) => {
+                    *self.use_http.lock().expect("Protocol choice poisoned") = Some(false);
+                    return Ok(response);
+                }
+                Err(ref e) if is_tls_error(e) => {
+                    warn!(
+                        "{} can not be reached using HTTPS, using plain HTTP: {}",
+                        self.registry, e
+                    );
+                    *self.use_http.lock().expect("Protocol choice poisoned") = Some(true);
+                }
+                Err(e) => return Err(e),
+            },
+        }
+
+        request
+            .url
+            .set_scheme("http")
+            .expect("HTTPS URLs can use plain HTTP");
+        self.inner.send(request)
+    }
+}
+
+/// Check whether a request failed during the TLS handshake, e.g. because
+/// the server only speaks plain HTTP.
+fn is_tls_error(error: &RegistryError) -> bool {
+    let mut source: Option<&(dyn StdError + 'static)> = match error {
+        RegistryError::ReqwestError(e) => e.get_ref().map(|e| e as &(dyn StdError + 'static)),
+        RegistryError::IoError(e) => Some(e),
+        _ => None,
+    };
+
+    while let Some(error) = source {
+        // The TLS connector reports failed handshakes as I/O errors of kind
+        // `Other`, unlike failed connections, lookups or timeouts.
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return error.kind() == io::ErrorKind::Other;
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+/// Split a PEM bundle into the individual certificates.
+fn pem_certificates(bundle: &[u8]) -> Vec<&[u8]> {
+    let text = match std::str::from_utf8(bundle) {
+        Ok(text) => text,
+        Err(_) => return vec![bundle],
+    };
+
+    let starts: Vec<usize> = text
+        .match_indices(PEM_CERTIFICATE_BEGIN)
+        .map(|(start, _)| start)
+        .collect();
+
+    if starts.is_empty() {
+        return vec![bundle];
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).cloned().unwrap_or(bundle.len());
+            &bundle[start..end]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::transport::{json_response, MemoryTransport};
+
+    use reqwest::{Method, StatusCode};
+
+    /// Transport for a registry only serving plain HTTP, failing HTTPS
+    /// requests with the given kind of error.
+    #[derive(Debug)]
+    struct PlainHttpOnly(MemoryTransport, io::ErrorKind);
+
+    impl Transport for PlainHttpOnly {
+        fn send(&self, request: HttpRequest) -> Result<reqwest::Response, RegistryError> {
+            if request.url.scheme() == "https" {
+                return Err(RegistryError::IoError(io::Error::from(self.1)));
+            }
+
+            self.0.send(request)
+        }
+    }
+
+    #[test]
+    fn test_is_localhost() {
+        let localhost = |url: &str| is_localhost(&Url::parse(url).unwrap());
+
+        assert!(localhost("https://localhost:5000"));
+        assert!(localhost("https://registry.localhost"));
+        assert!(localhost("https://127.0.0.1:5000"));
+        assert!(localhost("https://[::1]:5000"));
+        assert!(!localhost("https://registry-1.docker.io"));
+        assert!(!localhost("https://10.0.0.1:5000"));
+    }
+
+    #[test]
+    fn test_pem_certificates() {
+        let bundle = b"# Some CA\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                       -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+
+        let certificates = pem_certificates(bundle);
+        assert_eq!(certificates.len(), 2);
+        assert!(certificates[0].starts_with(PEM_CERTIFICATE_BEGIN.as_bytes()));
+        assert!(certificates[1].ends_with(b"BBBB\n-----END CERTIFICATE-----\n"));
+    }
+
+    #[test]
+    fn test_build() {
+        let registry = RegistryBuilder::new("https://registry.example.com")
+            .plain_http(true)
+            .build()
+            .expect("Could not build registry");
+        assert_eq!(registry.url, "http://registry.example.com");
+
+        let err = RegistryBuilder::new("https://registry.example.com")
+            .user_agent("invalid\n")
+            .build()
+            .expect_err("Building with invalid User-Agent succeeded");
+        match err {
+            RegistryError::InvalidHeader(_) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        RegistryBuilder::new("https://registry.example.com")
+            .ca_certificates(b"not a certificate")
+            .build()
+            .expect_err("Building with invalid CA certificate succeeded");
+    }
+
+    #[test]
+    fn test_http_fallback() {
+        let memory = MemoryTransport::new().route(
+            Method::GET,
+            "http://localhost:5000/v2/foo/tags/list",
+            |_| json_response(StatusCode::OK, r#"{"name": "foo", "tags": ["latest"]}"#),
+        );
+        let transport = Arc::new(PlainHttpOnly(memory, io::ErrorKind::Other));
+
+        let registry = RegistryBuilder::new("https://localhost:5000")
+            .transport(transport.clone())
+            .build()
+            .expect("Could not build registry");
+        assert!(transport.0.requests().is_empty());
+
+        for _ in 0..2 {
+            let tags: Vec<String> = registry
+                .tags("foo")
+                .expect("Could not list tags")
+                .collect::<Result<_, _>>()
+                .expect("Could not list tags");
+            assert_eq!(tags, vec!["latest".to_owned()]);
+        }
+
+        let requests = transport.0.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.url.scheme() == "http"));
+    }
+
+    #[test]
+    fn test_http_fallback_tls_error() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let body = r#"{"name": "foo", "tags": ["latest"]}"#;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        let registry = RegistryBuilder::new(&format!("https://127.0.0.1:{}", port))
+            .build()
+            .expect("Could not build registry");
+        let tags: Vec<String> = registry
+            .tags("foo")
+            .expect("Could not list tags")
+            .collect::<Result<_, _>>()
+            .expect("Could not list tags");
+        assert_eq!(tags, vec!["latest".to_owned()]);
+    }
+
+    #[test]
+    fn test_no_fallback_on_timeout() {
+        let memory = MemoryTransport::new();
+        let transport = Arc::new(PlainHttpOnly(memory, io::ErrorKind::TimedOut));
+
+        let registry = RegistryBuilder::new("https://localhost:5000")
+            .transport(transport.clone())
+            .build()
+            .expect("Could not build registry");
+
+        registry
+            .tags("foo")
+            .and_then(|tags| tags.collect::<Result<Vec<_>, _>>())
+            .expect_err("Fell back to plain HTTP");
+        assert!(transport.0.requests().is_empty());
+    }
+
+    #[test]
+    fn test_no_fallback_for_remote_registry() {
+        let memory = MemoryTransport::new();
+        let transport = Arc::new(PlainHttpOnly(memory, io::ErrorKind::Other));
+
+        let registry = RegistryBuilder::new("https://registry.example.com")
+            .transport(transport.clone())
+            .build()
+            .expect("Could not build registry");
+
+        registry
+            .tags("foo")
+            .and_then(|tags| tags.collect::<Result<Vec<_>, _>>())
+            .expect_err("Fell back to plain HTTP");
+        assert!(transport.0.requests().is_empty());
+    }
+}
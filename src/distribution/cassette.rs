@@ -0,0 +1,556 @@
+//! Recording exchanges with registries to cassette files, and replaying
+//! them to test code using a [Registry](crate::Registry) without a network.
+//!
+//! Secrets are redacted before exchanges are recorded: credentials in
+//! request headers and OAuth2 forms, cookies, and the tokens returned by
+//! authorization services.
+//!
+//! # Example
+//! ```no_run
+//!# extern crate opencontainers;
+//! use opencontainers::distribution::cassette::CassetteTransport;
+//! use opencontainers::image::TestImageSelector as ImagePlatformSelector;
+//! use opencontainers::Registry;
+//! use std::sync::Arc;
+//!
+//! // Replays the cassette, or records it if OPENCONTAINERS_RECORD is set.
+//! let transport = CassetteTransport::from_env("tests/hello-world.cassette.json")
+//!     .expect("Could not load cassette");
+//!
+//! let registry = Registry::builder("https://registry-1.docker.io")
+//!     .transport(Arc::new(transport))
+//!     .build()
+//!     .expect("Could not build registry");
+//!
+//! let image = registry
+//!     .image::<ImagePlatformSelector>(("library/hello-world", "latest"))
+//!     .expect("Could not get image");
+//! ```
+
+use crate::distribution::transport::{HttpRequest, ReqwestTransport, Transport};
+use crate::distribution::RegistryError;
+
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
+    PROXY_AUTHORIZATION, SET_COOKIE,
+};
+use reqwest::StatusCode;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Environment variable making [CassetteTransport::from_env] record
+/// cassettes instead of replaying them.
+pub const RECORD_ENV: &str = "OPENCONTAINERS_RECORD";
+
+/// Replaces secrets in recorded exchanges.
+const REDACTED: &str = "REDACTED";
+
+/// Request headers holding credentials.
+const SECRET_REQUEST_HEADERS: &[HeaderName] = &[AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE];
+
+/// Fields of OAuth2 forms and token responses holding credentials.
+const SECRET_FIELDS: &[&str] = &["password", "refresh_token", "token", "access_token"];
+
+#[derive(Debug, Fail)]
+pub enum CassetteError {
+    #[fail(display = "IO Error: {:?}", _0)]
+    IoError(#[cause] std::io::Error),
+
+    #[fail(display = "JSON Error: {:?}", _0)]
+    JsonError(#[cause] serde_json::Error),
+
+    #[fail(display = "Request Error: {:?}", _0)]
+    ReqwestError(#[cause] reqwest::Error),
+}
+
+/// A request or response body.
+///
+/// Bodies are stored as text if they are valid UTF-8, and base64-encoded
+/// otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordedBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+impl RecordedBody {
+    pub fn new(data: &[u8]) -> Self {
+        if data.is_empty() {
+            return Self::default();
+        }
+
+        match std::str::from_utf8(data) {
+            Ok(text) => RecordedBody {
+                body: Some(text.to_owned()),
+                body_base64: None,
+            },
+            Err(_) => RecordedBody {
+                body: None,
+                body_base64: Some(base64::encode(data)),
+            },
+        }
+    }
+
+    /// Return the content of the body.
+    pub fn data(&self) -> Vec<u8> {
+        match (&self.body, &self.body_base64) {
+            (Some(text), _) => text.as_bytes().to_vec(),
+            (None, Some(encoded)) => base64::decode(encoded).unwrap_or_default(),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+/// A request and the response it received.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// Exchanges with registries, in the order they happened.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CassetteError> {
+        let file = std::fs::File::open(path).map_err(CassetteError::IoError)?;
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(CassetteError::JsonError)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CassetteError> {
+        let path = path.as_ref();
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(CassetteError::IoError)?;
+        }
+
+        let mut data = serde_json::to_vec_pretty(self).map_err(CassetteError::JsonError)?;
+        data.push(b'\n');
+        std::fs::write(path, data).map_err(CassetteError::IoError)
+    }
+}
+
+enum Mode {
+    Record {
+        inner: Arc<dyn Transport>,
+        path: PathBuf,
+    },
+    Replay {
+        used: Vec<bool>,
+    },
+}
+
+/// Transport recording exchanges to a cassette, or replaying them from one.
+///
+/// When recording, requests are sent through another transport, and the
+/// cassette is saved when the transport is dropped, or by calling
+/// [CassetteTransport::save]. Response bodies are read completely before
+/// they are returned.
+///
+/// When replaying, each request is answered with the first recorded
+/// response to a request with the same method and URL that was not replayed
+/// yet. Once all of them have been replayed, the last one is repeated.
+/// Headers and bodies of requests are not compared, so recorded exchanges
+/// can be replayed although their credentials were redacted. Requests that
+/// were not recorded fail with [RegistryError::NotRecorded], so clients
+/// should be configured as when recording, such as whether they log in.
+pub struct CassetteTransport {
+    mode: Mutex<Mode>,
+    cassette: Mutex<Cassette>,
+}
+
+impl std::fmt::Debug for CassetteTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mode = match *self.mode.lock().expect("Cassette mode poisoned") {
+            Mode::Record { ref path, .. } => format!("Record({})", path.display()),
+            Mode::Replay { .. } => "Replay".to_owned(),
+        };
+        let interactions = self
+            .cassette
+            .lock()
+            .expect("Cassette poisoned")
+            .interactions
+            .len();
+
+        write!(
+            f,
+            "CassetteTransport {{ mode: {}, interactions: {} }}",
+            mode, interactions
+        )
+    }
+}
+
+impl CassetteTransport {
+    /// Record the exchanges sent through `inner` to a cassette at `path`.
+    pub fn record<P: Into<PathBuf>>(path: P, inner: Arc<dyn Transport>) -> Self {
+        CassetteTransport {
+            mode: Mutex::new(Mode::Record {
+                inner,
+                path: path.into(),
+            }),
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    /// Replay the exchanges recorded in a cassette.
+    pub fn replay(cassette: Cassette) -> Self {
+        CassetteTransport {
+            mode: Mutex::new(Mode::Replay {
+                used: vec![false; cassette.interactions.len()],
+            }),
+            cassette: Mutex::new(cassette),
+        }
+    }
+
+    /// Replay the exchanges recorded in a cassette file.
+    pub fn replay_file<P: AsRef<Path>>(path: P) -> Result<Self, CassetteError> {
+        Ok(Self::replay(Cassette::load(path)?))
+    }
+
+    /// Replay a cassette file, or record it using [reqwest] if the
+    /// `OPENCONTAINERS_RECORD` environment variable is set.
+    pub fn from_env<P: Into<PathBuf>>(path: P) -> Result<Self, CassetteError> {
+        let path = path.into();
+
+        if std::env::var_os(RECORD_ENV).is_some() {
+            info!("Recording cassette {}", path.display());
+            let client = reqwest::Client::builder()
+                .build()
+                .map_err(CassetteError::ReqwestError)?;
+            let inner = ReqwestTransport::new(client);
+            return Ok(Self::record(path, Arc::new(inner)));
+        }
+
+        Self::replay_file(path)
+    }
+
+    /// Return a copy of the cassette.
+    pub fn cassette(&self) -> Cassette {
+        self.cassette.lock().expect("Cassette poisoned").clone()
+    }
+
+    /// Save the cassette, if recording.
+    pub fn save(&self) -> Result<(), CassetteError> {
+        match *self.mode.lock().expect("Cassette mode poisoned") {
+            Mode::Record { ref path, .. } => self.cassette().save(path),
+            Mode::Replay { .. } => Ok(()),
+        }
+    }
+
+    fn replay_request(&self, request: &HttpRequest) -> Result<reqwest::Response, RegistryError> {
+        let cassette = self.cassette.lock().expect("Cassette poisoned");
+        let mut mode = self.mode.lock().expect("Cassette mode poisoned");
+        let used = match *mode {
+            Mode::Replay { ref mut used } => used,
+            Mode::Record { .. } => unreachable!("Not replaying"),
+        };
+
+        let method = request.method.as_str();
+        let url = request.url.as_str();
+
+        let matching: Vec<usize> = cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.request.method == method && i.request.url == url)
+            .map(|(index, _)| index)
+            .collect();
+
+        let index = matching
+            .iter()
+            .find(|&&index| !used[index])
+            .or_else(|| matching.last())
+            .copied()
+            .ok_or_else(|| RegistryError::NotRecorded(format!("{} {}", method, url)))?;
+
+        used[index] = true;
+        debug!("Replaying interaction {} for {} {}", index, method, url);
+
+        Ok(into_response(&cassette.interactions[index].response).into())
+    }
+
+    fn record_request(
+        &self,
+        inner: &dyn Transport,
+        request: HttpRequest,
+    ) -> Result<reqwest::Response, RegistryError> {
+        let recorded_request = RecordedRequest {
+            method: request.method.to_string(),
+            url: request.url.to_string(),
+            headers: redact_headers(&request.headers, SECRET_REQUEST_HEADERS),
+            body: RecordedBody::new(&redact_form(
+                &request.headers,
+                request.body.as_deref().unwrap_or_default(),
+            )),
+        };
+
+        let mut response = inner.send(request)?;
+
+        let mut body = Vec::new();
+        response
+            .copy_to(&mut body)
+            .map_err(RegistryError::ReqwestError)?;
+
+        let redacted = redact_json(&body);
+        let mut headers = redact_headers(response.headers(), &[SET_COOKIE]);
+        if redacted != body {
+            for (name, value) in headers.iter_mut() {
+                if name == CONTENT_LENGTH.as_str() {
+                    *value = redacted.len().to_string();
+                }
+            }
+        }
+
+        let recorded_response = RecordedResponse {
+            status: response.status().as_u16(),
+            headers,
+            body: RecordedBody::new(&redacted),
+        };
+
+        let mut replayed = http::Response::new(body);
+        *replayed.status_mut() = response.status();
+        *replayed.headers_mut() = response.headers().clone();
+
+        self.cassette
+            .lock()
+            .expect("Cassette poisoned")
+            .interactions
+            .push(Interaction {
+                request: recorded_request,
+                response: recorded_response,
+            });
+
+        Ok(replayed.into())
+    }
+}
+
+impl Transport for CassetteTransport {
+    fn send(&self, request: HttpRequest) -> Result<reqwest::Response, RegistryError> {
+        let inner = match *self.mode.lock().expect("Cassette mode poisoned") {
+            Mode::Record { ref inner, .. } => Some(inner.clone()),
+            Mode::Replay { .. } => None,
+        };
+
+        match inner {
+            Some(inner) => self.record_request(inner.as_ref(), request),
+            None => self.replay_request(&request),
+        }
+    }
+}
+
+impl Drop for CassetteTransport {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            error!("Could not save cassette: {}", e);
+        }
+    }
+}
+
+/// Create a response from a recorded one.
+fn into_response(recorded: &RecordedResponse) -> http::Response<Vec<u8>> {
+    let mut response = http::Response::new(recorded.body.data());
+    *response.status_mut() =
+        StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    for (name, value) in &recorded.headers {
+        let name = HeaderName::from_bytes(name.as_bytes());
+        let value = HeaderValue::from_str(value);
+
+        if let (Ok(name), Ok(value)) = (name, value) {
+            response.headers_mut().append(name, value);
+        }
+    }
+
+    response
+}
+
+fn redact_headers(headers: &HeaderMap, secret: &[HeaderName]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if secret.contains(name) {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or_default()
+            };
+
+            (name.as_str().to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+/// Redact the credentials in an OAuth2 form.
+fn redact_form(headers: &HeaderMap, body: &[u8]) -> Vec<u8> {
+    let is_form = matches!(
+        headers.get(CONTENT_TYPE),
+        Some(value) if value == "application/x-www-form-urlencoded"
+    );
+
+    let form: Vec<(String, String)> = match serde_urlencoded::from_bytes(body) {
+        Ok(form) if is_form => form,
+        _ => return body.to_vec(),
+    };
+
+    let form: Vec<(String, String)> = form
+        .into_iter()
+        .map(|(key, value)| {
+            if SECRET_FIELDS.contains(&key.as_str()) {
+                (key, REDACTED.to_owned())
+            } else {
+                (key, value)
+            }
+        })
+        .collect();
+
+    serde_urlencoded::to_string(form)
+        .map(String::into_bytes)
+        .unwrap_or_else(|_| body.to_vec())
+}
+
+/// Redact the tokens in a response of an authorization service.
+fn redact_json(body: &[u8]) -> Vec<u8> {
+    let mut object = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(object)) => object,
+        _ => return body.to_vec(),
+    };
+
+    let mut redacted = false;
+    for field in SECRET_FIELDS {
+        if let Some(value) = object.get_mut(*field) {
+            *value = REDACTED.into();
+            redacted = true;
+        }
+    }
+
+    if !redacted {
+        return body.to_vec();
+    }
+
+    serde_json::to_vec(&object).unwrap_or_else(|_| body.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::transport::{json_response, response, MemoryTransport};
+    use crate::distribution::Registry;
+    use crate::image::manifest::Digest;
+
+    use reqwest::Method;
+    use std::io::Read;
+
+    const REGISTRY: &str = "https://registry.example.com";
+    const BLOB: &str = "https://registry.example.com/v2/foo/blobs/sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn registry(transport: Arc<dyn Transport>) -> Registry {
+        Registry::builder(REGISTRY)
+            .transport(transport)
+            .build()
+            .unwrap()
+    }
+
+    fn get_blob(registry: &Registry) -> Result<Vec<u8>, RegistryError> {
+        let mut data = Vec::new();
+        registry
+            .get_blob("foo", &Digest::sha256(b"hello"))?
+            .read_to_end(&mut data)
+            .map_err(RegistryError::IoError)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.json");
+
+        let inner = MemoryTransport::new()
+            .route(Method::GET, BLOB, |request| {
+                match request.headers.get(AUTHORIZATION) {
+                    Some(value) if value == "Bearer secret" => response(StatusCode::OK, "hello"),
+                    _ => {
+                        let mut response = response(StatusCode::UNAUTHORIZED, Vec::new());
+                        response.headers_mut().insert(
+                            reqwest::header::WWW_AUTHENTICATE,
+                            r#"Bearer realm="https://auth.example.com/token",service="registry.example.com""#
+                                .parse()
+                                .unwrap(),
+                        );
+                        response
+                    }
+                }
+            })
+            .route(Method::GET, "https://auth.example.com/token", |_| {
+                json_response(StatusCode::OK, r#"{"token": "secret"}"#)
+            });
+
+        let transport = CassetteTransport::record(&path, Arc::new(inner));
+        {
+            let registry = registry(Arc::new(transport));
+            let registry = registry.with_basic_auth("user", "hunter2");
+            assert_eq!(get_blob(&registry).unwrap(), b"hello");
+        }
+
+        let cassette = std::fs::read_to_string(&path).unwrap();
+        assert!(!cassette.contains("secret"), "{}", cassette);
+        assert!(!cassette.contains("hunter2"), "{}", cassette);
+
+        // Credentials are redacted, but they select the recorded token flow
+        let transport = Arc::new(CassetteTransport::replay_file(&path).unwrap());
+        let registry = registry(transport).with_basic_auth("user", REDACTED);
+        assert_eq!(get_blob(&registry).unwrap(), b"hello");
+
+        // The last response is repeated
+        assert_eq!(get_blob(&registry).unwrap(), b"hello");
+
+        match registry.get_manifest_raw("foo", "latest") {
+            Err(RegistryError::NotRecorded(request)) => {
+                assert_eq!(
+                    request,
+                    "GET https://registry.example.com/v2/foo/manifests/latest"
+                );
+            }
+            result => panic!("Request should not be recorded: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_recorded_body() {
+        let text = RecordedBody::new(b"hello");
+        assert_eq!(text.body.as_deref(), Some("hello"));
+        assert_eq!(text.data(), b"hello");
+
+        let binary = RecordedBody::new(&[0x1f, 0x8b, 0xff]);
+        assert!(binary.body.is_none());
+        assert_eq!(binary.data(), vec![0x1f, 0x8b, 0xff]);
+    }
+}
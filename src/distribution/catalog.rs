@@ -0,0 +1,69 @@
+//! Listing repositories using the `/v2/_catalog` endpoint as described in the
+//! [Docker Registry HTTP API V2](https://docs.docker.com/registry/spec/api/#catalog)
+
+use crate::distribution::pagination::{Page, Paginated};
+use crate::distribution::{Registry, RegistryError};
+
+/// Response of the `/v2/_catalog` endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RepositoryList {
+    /// The repositories on this page.
+    pub repositories: Option<Vec<String>>,
+}
+
+impl Page for RepositoryList {
+    fn into_entries(self) -> Vec<String> {
+        self.repositories.unwrap_or_default()
+    }
+}
+
+/// Iterator over the repositories of a registry.
+pub type Catalog<'a> = Paginated<'a, RepositoryList>;
+
+impl Registry {
+    /// List the repositories available in the registry.
+    ///
+    /// # Authentication
+    /// Registries using token authentication will challenge for the
+    /// `registry:catalog:*` scope, which is requested like any other scope.
+    /// Public registries such as Docker Hub usually do not grant it.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// for repository in registry.catalog().expect("Could not list repositories") {
+    ///     println!("{}", repository.expect("Could not get repository"));
+    /// }
+    /// ```
+    pub fn catalog(&self) -> Result<Catalog<'_>, RegistryError> {
+        self.catalog_paginated(None, None)
+    }
+
+    /// List the repositories available in the registry, requesting at most
+    /// `n` repositories per page and starting after the repository `last`.
+    pub fn catalog_paginated(
+        &self,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<Catalog<'_>, RegistryError> {
+        Paginated::new(self, "/v2/_catalog", n, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repository_list() {
+        let repositories: RepositoryList =
+            serde_json::from_str(r#"{"repositories": ["library/hello-world", "library/nginx"]}"#)
+                .expect("Could not deserialize repository list");
+        assert_eq!(
+            repositories.into_entries(),
+            vec!["library/hello-world", "library/nginx"]
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Support for the [Docker credential helper
+//! protocol](https://github.com/docker/docker-credential-helpers), used to
+//! keep registry credentials in the platform's secret store.
+
+use crate::distribution::auth::Credential;
+use crate::distribution::credentials::CredentialsError;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Username returned by helpers if the secret is an identity token.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// Message helpers print if they do not know a server.
+const CREDENTIALS_NOT_FOUND: &str = "credentials not found in native keychain";
+
+/// Credentials as exchanged with a credential helper.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HelperCredentials {
+    #[serde(rename = "ServerURL")]
+    pub server_url: String,
+    pub username: String,
+    pub secret: String,
+}
+
+impl HelperCredentials {
+    /// Return the credential, which is an identity token if the helper
+    /// returned the username `<token>`.
+    pub fn credential(&self) -> Credential {
+        if self.username == IDENTITY_TOKEN_USERNAME {
+            return Credential::IdentityToken(self.secret.clone());
+        }
+
+        Credential::Basic {
+            username: self.username.clone(),
+            password: self.secret.clone(),
+        }
+    }
+}
+
+/// A `docker-credential-*` helper program.
+///
+/// # Example
+/// ```no_run
+///# extern crate opencontainers;
+/// use opencontainers::distribution::credential_helper::CredentialHelper;
+///
+/// let helper = CredentialHelper::new("secretservice");
+/// let credentials = helper.get("registry.example.com")
+///     .expect("Could not run credential helper");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CredentialHelper {
+    program: PathBuf,
+}
+
+impl CredentialHelper {
+    /// Create a helper given its name as used in `credsStore` and
+    /// `credHelpers`, e.g. `osxkeychain` for `docker-credential-osxkeychain`.
+    pub fn new(name: &str) -> Self {
+        CredentialHelper {
+            program: format!("docker-credential-{}", name).into(),
+        }
+    }
+
+    /// Create a helper given the path to its executable.
+    pub fn from_path<P: Into<PathBuf>>(program: P) -> Self {
+        CredentialHelper {
+            program: program.into(),
+        }
+    }
+
+    /// Run the helper with the given action and input, returning its output.
+    fn run(&self, action: &str, input: &[u8]) -> Result<String, CredentialsError> {
+        let mut child = Command::new(&self.program)
+            .arg(action)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(CredentialsError::IoError)?;
+
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(input)
+            .map_err(CredentialsError::IoError)?;
+
+        let output = child
+            .wait_with_output()
+            .map_err(CredentialsError::IoError)?;
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+        if !output.status.success() {
+            let message = if stdout.is_empty() {
+                String::from_utf8_lossy(&output.stderr).trim().to_owned()
+            } else {
+                stdout
+            };
+
+            return Err(CredentialsError::HelperFailed(
+                self.program.display().to_string(),
+                message,
+            ));
+        }
+
+        Ok(stdout)
+    }
+
+    /// Get the credentials for a server, if the helper has any.
+    pub fn get(&self, server_url: &str) -> Result<Option<HelperCredentials>, CredentialsError> {
+        match self.run("get", server_url.as_bytes()) {
+            Ok(output) => serde_json::from_str(&output)
+                .map(Some)
+                .map_err(CredentialsError::JsonError),
+            Err(CredentialsError::HelperFailed(_, ref message))
+                if message == CREDENTIALS_NOT_FOUND =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store credentials for a server.
+    pub fn store(&self, credentials: &HelperCredentials) -> Result<(), CredentialsError> {
+        let input = serde_json::to_vec(credentials).map_err(CredentialsError::JsonError)?;
+        self.run("store", &input).map(|_| ())
+    }
+
+    /// Erase the credentials for a server.
+    pub fn erase(&self, server_url: &str) -> Result<(), CredentialsError> {
+        self.run("erase", server_url.as_bytes()).map(|_| ())
+    }
+
+    /// List the servers the helper has credentials for, along with the
+    /// usernames.
+    pub fn list(&self) -> Result<HashMap<String, String>, CredentialsError> {
+        let output = self.run("list", &[])?;
+        serde_json::from_str(&output).map_err(CredentialsError::JsonError)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    const FAKE_HELPER: &str = r#"#!/bin/sh
+dir="$(dirname "$0")"
+case "$1" in
+get)
+    read -r url
+    if [ "$url" = "registry.example.com" ]; then
+        echo '{"ServerURL":"registry.example.com","Username":"user","Secret":"secret"}'
+    elif [ "$url" = "token.example.com" ]; then
+        echo '{"ServerURL":"token.example.com","Username":"<token>","Secret":"refresh"}'
+    else
+        echo "credentials not found in native keychain"
+        exit 1
+    fi
+    ;;
+store)
+    cat > "$dir/stored"
+    ;;
+erase)
+    read -r url
+    echo "$url" > "$dir/erased"
+    ;;
+list)
+    echo '{"registry.example.com":"user"}'
+    ;;
+*)
+    echo "unknown action" >&2
+    exit 1
+    ;;
+esac
+"#;
+
+    fn fake_helper(dir: &tempfile::TempDir) -> CredentialHelper {
+        let path = dir.path().join("docker-credential-fake");
+        std::fs::write(&path, FAKE_HELPER).expect("Could not write fake helper");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("Could not make fake helper executable");
+        CredentialHelper::from_path(path)
+    }
+
+    #[test]
+    fn test_helper_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let helper = fake_helper(&dir);
+
+        let credentials = helper
+            .get("registry.example.com")
+            .expect("Could not get credentials")
+            .expect("No credentials found");
+        assert_eq!(
+            credentials.credential(),
+            Credential::Basic {
+                username: "user".into(),
+                password: "secret".into(),
+            }
+        );
+
+        assert_eq!(helper.get("unknown.example.com").unwrap(), None);
+
+        let token = helper.get("token.example.com").unwrap().unwrap();
+        assert_eq!(
+            token.credential(),
+            Credential::IdentityToken("refresh".into())
+        );
+    }
+
+    #[test]
+    fn test_helper_store_erase_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let helper = fake_helper(&dir);
+
+        let credentials = HelperCredentials {
+            server_url: "registry.example.com".into(),
+            username: "user".into(),
+            secret: "secret".into(),
+        };
+        helper.store(&credentials).expect("Could not store");
+        let stored = std::fs::read_to_string(dir.path().join("stored")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HelperCredentials>(&stored).unwrap(),
+            credentials
+        );
+
+        helper
+            .erase("registry.example.com")
+            .expect("Could not erase");
+        let erased = std::fs::read_to_string(dir.path().join("erased")).unwrap();
+        assert_eq!(erased.trim(), "registry.example.com");
+
+        let list = helper.list().expect("Could not list");
+        assert_eq!(list["registry.example.com"], "user");
+    }
+
+    #[test]
+    fn test_helper_failure() {
+        let helper = CredentialHelper::from_path("/nonexistent/docker-credential-none");
+        match helper.get("registry.example.com") {
+            Err(CredentialsError::IoError(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Registry credentials stored by `docker login`, `podman login` and
+//! `skopeo login`.
+//!
+//! Docker stores credentials in `~/.docker/config.json` (or
+//! `$DOCKER_CONFIG/config.json`), while the containers tools use `auth.json`
+//! files in the same format. Credentials may also be kept by [credential
+//! helpers](crate::distribution::credential_helper) configured in these files.
+
+use crate::distribution::auth::Credential;
+use crate::distribution::credential_helper::{CredentialHelper, HelperCredentials};
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Key under which Docker stores credentials for Docker Hub.
+pub const DOCKER_HUB_KEY: &str = "https://index.docker.io/v1/";
+
+/// Host names referring to Docker Hub.
+const DOCKER_HUB_ALIASES: &[&str] = &[
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+    "registry.hub.docker.com",
+];
+
+#[derive(Debug, Fail)]
+pub enum CredentialsError {
+    #[fail(display = "IO Error: {:?}", _0)]
+    IoError(#[cause] std::io::Error),
+
+    #[fail(display = "JSON Error: {:?}", _0)]
+    JsonError(#[cause] serde_json::Error),
+
+    #[fail(display = "Invalid auth entry for {}", _0)]
+    InvalidAuth(String),
+
+    #[fail(display = "Credential helper {} failed: {}", _0, _1)]
+    HelperFailed(String, String),
+}
+
+/// Credentials for a single registry.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuthEntry {
+    /// Base64 encoded `username:password`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
+
+    /// Username, if stored separately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Password, if stored separately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// Refresh token for the OAuth2 token flow, stored by registries
+    /// returning one on login.
+    #[serde(
+        rename = "identitytoken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub identity_token: Option<String>,
+
+    /// Fields this implementation does not know about.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl AuthEntry {
+    /// Create an entry for a username and password.
+    pub fn new(username: &str, password: &str) -> Self {
+        AuthEntry {
+            auth: Some(base64::encode(&format!("{}:{}", username, password))),
+            ..Default::default()
+        }
+    }
+
+    /// Return the username and password of this entry.
+    ///
+    /// Separately stored usernames and passwords take precedence over the
+    /// `auth` field.
+    pub fn username_password(&self) -> Result<Option<(String, String)>, CredentialsError> {
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Ok(Some((username.clone(), password.clone())));
+        }
+
+        let auth = match self.auth {
+            Some(ref auth) if !auth.is_empty() => auth,
+            _ => return Ok(None),
+        };
+
+        let invalid = || CredentialsError::InvalidAuth(auth.clone());
+
+        let decoded = base64::decode(auth).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+
+        let mut split = decoded.splitn(2, ':');
+        match (split.next(), split.next()) {
+            (Some(username), Some(password)) => Ok(Some((username.into(), password.into()))),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Return the credential stored in this entry, if any.
+    ///
+    /// An identity token takes precedence over the username and password.
+    pub fn credential(&self) -> Result<Option<Credential>, CredentialsError> {
+        if let Some(ref identity_token) = self.identity_token {
+            if !identity_token.is_empty() {
+                return Ok(Some(Credential::IdentityToken(identity_token.clone())));
+            }
+        }
+
+        Ok(self
+            .username_password()?
+            .map(|(username, password)| Credential::Basic { username, password }))
+    }
+}
+
+/// Contents of a Docker `config.json` or containers `auth.json` file.
+///
+/// Only the parts concerning credentials are interpreted, all other settings
+/// are kept as they are when saving the file.
+///
+/// # Example
+/// ```no_run
+///# extern crate opencontainers;
+///# use opencontainers::Registry;
+/// use opencontainers::distribution::credentials::DockerConfig;
+///
+/// let config = DockerConfig::load().expect("Could not load credentials");
+/// let registry = Registry::new("https://registry-1.docker.io").with_docker_config(&config);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DockerConfig {
+    /// Credentials by registry.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub auths: HashMap<String, AuthEntry>,
+
+    /// Credential helper used for all registries.
+    #[serde(
+        rename = "credsStore",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creds_store: Option<String>,
+
+    /// Credential helpers by registry.
+    #[serde(
+        rename = "credHelpers",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub cred_helpers: HashMap<String, String>,
+
+    /// Settings this implementation does not know about.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl std::str::FromStr for DockerConfig {
+    type Err = CredentialsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(CredentialsError::JsonError)
+    }
+}
+
+impl DockerConfig {
+    /// Return the paths credentials are loaded from, in order of precedence.
+    ///
+    /// These are, like for the containers tools:
+    /// * `$REGISTRY_AUTH_FILE`
+    /// * `$XDG_RUNTIME_DIR/containers/auth.json`
+    /// * `$XDG_CONFIG_HOME/containers/auth.json`
+    /// * `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`
+    pub fn default_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        if let Some(path) = std::env::var_os("REGISTRY_AUTH_FILE") {
+            paths.push(path.into());
+        }
+
+        if let Some(dir) = dirs::runtime_dir() {
+            paths.push(dir.join("containers").join("auth.json"));
+        }
+
+        if let Some(dir) = dirs::config_dir() {
+            paths.push(dir.join("containers").join("auth.json"));
+        }
+
+        paths.push(Self::docker_path());
+
+        paths
+    }
+
+    /// Return the path of Docker's `config.json`.
+    pub fn docker_path() -> PathBuf {
+        match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir).join("config.json"),
+            None => dirs::home_dir()
+                .unwrap_or_default()
+                .join(".docker")
+                .join("config.json"),
+        }
+    }
+
+    /// Load credentials from all [DockerConfig::default_paths] that exist.
+    ///
+    /// If a registry is configured in more than one file, the first one
+    /// wins.
+    pub fn load() -> Result<Self, CredentialsError> {
+        let mut config = DockerConfig::default();
+
+        for path in Self::default_paths() {
+            if !path.is_file() {
+                continue;
+            }
+
+            info!("Loading registry credentials from {:?}", path);
+            config.merge(Self::from_file(&path)?);
+        }
+
+        Ok(config)
+    }
+
+    /// Load a single configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, CredentialsError> {
+        std::fs::read_to_string(path)
+            .map_err(CredentialsError::IoError)?
+            .parse()
+    }
+
+    /// Save the configuration to a file.
+    ///
+    /// The configuration is written to a temporary file next to it, only
+    /// readable by the current user, which then replaces the file. Readers
+    /// never see a partially written file, and the credentials are never
+    /// readable by others.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CredentialsError> {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let file_name = path.file_name().ok_or_else(|| {
+            CredentialsError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Path does not name a file",
+            ))
+        })?;
+
+        std::fs::create_dir_all(parent).map_err(CredentialsError::IoError)?;
+
+        let data = serde_json::to_vec_pretty(self).map_err(CredentialsError::JsonError)?;
+
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = parent.join(temp_name);
+
+        let result =
+            write_private(&temp_path, &data).and_then(|_| std::fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result.map_err(CredentialsError::IoError)
+    }
+
+    /// Add the settings of another configuration, keeping the existing ones
+    /// where both are set.
+    fn merge(&mut self, other: DockerConfig) {
+        for (key, entry) in other.auths {
+            self.auths.entry(key).or_insert(entry);
+        }
+
+        for (key, helper) in other.cred_helpers {
+            self.cred_helpers.entry(key).or_insert(helper);
+        }
+
+        if self.creds_store.is_none() {
+            self.creds_store = other.creds_store;
+        }
+    }
+
+    /// Return the entry for a registry host, such as `docker.io` or
+    /// `localhost:5000`.
+    pub fn auth_entry(&self, host: &str) -> Option<&AuthEntry> {
+        let host = normalize_host(host);
+
+        self.auths
+            .iter()
+            .find(|(key, _)| normalize_host(key) == host)
+            .map(|(_, entry)| entry)
+    }
+
+    /// Return the credential helper for a registry host, if any.
+    ///
+    /// A helper configured in `credHelpers` for the host takes precedence
+    /// over the `credsStore` used for all registries.
+    pub fn helper_for(&self, host: &str) -> Option<CredentialHelper> {
+        let host = normalize_host(host);
+
+        self.cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_host(key) == host)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref())
+            .map(|helper| CredentialHelper::new(helper))
+    }
+
+    /// Return the credential for a registry host, such as `docker.io` or
+    /// `localhost:5000`.
+    ///
+    /// If a credential helper is configured for the host, the credential is
+    /// requested from the helper, otherwise it is taken from `auths`.
+    pub fn credential_for(&self, host: &str) -> Result<Option<Credential>, CredentialsError> {
+        if let Some(helper) = self.helper_for(host) {
+            return Ok(helper
+                .get(&server_url(host))?
+                .map(|credentials| credentials.credential()));
+        }
+
+        match self.auth_entry(host) {
+            Some(entry) => entry.credential(),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a username and password for a registry host, replacing any
+    /// existing entry.
+    ///
+    /// If a credential helper is configured for the host, the credential is
+    /// stored by the helper.
+    pub fn set_credential(
+        &mut self,
+        host: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), CredentialsError> {
+        self.remove_credential(host)?;
+
+        let key = server_url(host);
+
+        if let Some(helper) = self.helper_for(host) {
+            helper.store(&HelperCredentials {
+                server_url: key.clone(),
+                username: username.into(),
+                secret: password.into(),
+            })?;
+
+            // Docker keeps an empty entry for registries stored by helpers.
+            self.auths.insert(key, AuthEntry::default());
+            return Ok(());
+        }
+
+        self.auths.insert(key, AuthEntry::new(username, password));
+
+        Ok(())
+    }
+
+    /// Remove the credentials for a registry host.
+    ///
+    /// If a credential helper is configured for the host, the credential is
+    /// erased from the helper as well.
+    pub fn remove_credential(&mut self, host: &str) -> Result<(), CredentialsError> {
+        if let Some(helper) = self.helper_for(host) {
+            if let Some(credentials) = helper.get(&server_url(host))? {
+                helper.erase(&credentials.server_url)?;
+            }
+        }
+
+        let host = normalize_host(host);
+        self.auths.retain(|key, _| normalize_host(key) != host);
+
+        Ok(())
+    }
+}
+
+/// Return the server URL under which Docker stores credentials for a registry
+/// host.
+fn server_url(host: &str) -> String {
+    if normalize_host(host) == DOCKER_HUB_ALIASES[0] {
+        DOCKER_HUB_KEY.into()
+    } else {
+        host.into()
+    }
+}
+
+/// Normalize a registry host or `auths` key for comparison.
+///
+/// Strips the scheme and path of keys such as `https://index.docker.io/v1/`,
+/// and maps all Docker Hub aliases to `docker.io`.
+pub fn normalize_host(key: &str) -> String {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    if DOCKER_HUB_ALIASES.contains(&host.as_str()) {
+        DOCKER_HUB_ALIASES[0].into()
+    } else {
+        host
+    }
+}
+
+/// Create a new file only readable by the current user and write `data` to
+/// it, replacing a file left over by an earlier attempt.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host(DOCKER_HUB_KEY), "docker.io");
+        assert_eq!(normalize_host("registry-1.docker.io"), "docker.io");
+        assert_eq!(normalize_host("localhost:5000"), "localhost:5000");
+        assert_eq!(
+            normalize_host("https://Registry.Example.com/v2/"),
+            "registry.example.com"
+        );
+    }
+
+    #[test]
+    fn test_docker_config() {
+        let mut config: DockerConfig = include_str!("test/docker-config.test.json")
+            .parse()
+            .expect("Could not parse docker config");
+
+        assert_eq!(config.creds_store.as_deref(), Some("desktop"));
+        assert_eq!(config.cred_helpers["gcr.io"], "gcloud");
+        config.creds_store = None;
+
+        assert_eq!(
+            config.credential_for("registry-1.docker.io").unwrap(),
+            Some(Credential::Basic {
+                username: "user".into(),
+                password: "pass:word".into(),
+            })
+        );
+        assert_eq!(
+            config.credential_for("localhost:5000").unwrap(),
+            Some(Credential::Basic {
+                username: "local".into(),
+                password: "secret".into(),
+            })
+        );
+        assert_eq!(
+            config.credential_for("registry.example.com").unwrap(),
+            Some(Credential::IdentityToken("refresh-me".into()))
+        );
+        assert_eq!(config.credential_for("quay.io").unwrap(), None);
+    }
+
+    #[test]
+    fn test_docker_config_helper_for() {
+        let mut config: DockerConfig = include_str!("test/docker-config.test.json")
+            .parse()
+            .expect("Could not parse docker config");
+
+        assert_eq!(
+            config.helper_for("gcr.io"),
+            Some(CredentialHelper::new("gcloud"))
+        );
+        assert_eq!(
+            config.helper_for("docker.io"),
+            Some(CredentialHelper::new("desktop"))
+        );
+
+        config.creds_store = None;
+        assert_eq!(config.helper_for("docker.io"), None);
+    }
+
+    #[test]
+    fn test_docker_config_roundtrip() {
+        let mut config: DockerConfig = include_str!("test/docker-config.test.json")
+            .parse()
+            .expect("Could not parse docker config");
+        config.creds_store = None;
+
+        config
+            .set_credential("docker.io", "other", "password")
+            .expect("Could not set credential");
+        config
+            .set_credential("quay.io", "quay", "password")
+            .expect("Could not set credential");
+
+        let data = serde_json::to_string(&config).expect("Could not serialize docker config");
+        let parsed: DockerConfig = data.parse().expect("Could not parse docker config");
+
+        assert_eq!(parsed, config);
+        assert!(parsed.auths.contains_key(DOCKER_HUB_KEY));
+        assert!(!parsed.auths.contains_key("docker.io"));
+        assert_eq!(parsed.other["psFormat"], "table {{.ID}}");
+        assert_eq!(
+            parsed.credential_for("docker.io").unwrap(),
+            Some(Credential::Basic {
+                username: "other".into(),
+                password: "password".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_docker_config_save() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, "{}").expect("Could not write docker config");
+
+        let mut config = DockerConfig::default();
+        config
+            .set_credential("quay.io", "quay", "password")
+            .expect("Could not set credential");
+        config.save(&path).expect("Could not save docker config");
+
+        let saved = DockerConfig::from_file(&path).expect("Could not load docker config");
+        assert_eq!(saved, config);
+
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .expect("Could not list directory")
+            .collect();
+        assert_eq!(entries.len(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(&path).expect("Could not stat docker config");
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Deleting manifests and blobs as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#deleting-manifests)
+
+use crate::distribution::{Registry, RegistryError};
+use crate::image::manifest::Digest;
+
+use reqwest::{Method, StatusCode};
+
+impl Registry {
+    /// Delete a manifest from a repository.
+    ///
+    /// Manifests can only be deleted by digest. Deleting a manifest removes
+    /// all tags pointing to it.
+    ///
+    /// # Errors
+    /// Returns [RegistryError::NotFound] if the manifest does not exist, and
+    /// [RegistryError::DeletionDisabled] if the registry does not allow
+    /// deletes.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let digest = "sha256:92c7f9c92844bbbb5d0a101b22f7c2a7949e40f8ea90c8b3bc396879d95e899a"
+    ///     .parse()
+    ///     .unwrap();
+    /// registry.delete_manifest("hello-world", &digest)
+    ///     .expect("Could not delete manifest");
+    /// ```
+    pub fn delete_manifest(&self, name: &str, digest: &Digest) -> Result<(), RegistryError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, digest);
+
+        self.delete(&url)
+    }
+
+    /// Delete a blob from a repository.
+    ///
+    /// # Errors
+    /// Returns [RegistryError::NotFound] if the blob does not exist, and
+    /// [RegistryError::DeletionDisabled] if the registry does not allow
+    /// deletes.
+    pub fn delete_blob(&self, name: &str, digest: &Digest) -> Result<(), RegistryError> {
+        let url = format!("{}/v2/{}/blobs/{}", self.url, name, digest);
+
+        self.delete(&url)
+    }
+
+    fn delete(&self, url: &str) -> Result<(), RegistryError> {
+        match self.send(Method::DELETE, url, None, None)? {
+            Ok(_) => Ok(()),
+            Err(response) => Err(delete_error(url, response.status())
+                .unwrap_or_else(|| RegistryError::from_response(response))),
+        }
+    }
+}
+
+/// Map the status codes with a special meaning for deletes to an error.
+fn delete_error(url: &str, status: StatusCode) -> Option<RegistryError> {
+    match status {
+        StatusCode::NOT_FOUND => Some(RegistryError::NotFound(url.into())),
+        StatusCode::METHOD_NOT_ALLOWED => Some(RegistryError::DeletionDisabled),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delete_error() {
+        match delete_error("/v2/foo/blobs/bar", StatusCode::NOT_FOUND) {
+            Some(RegistryError::NotFound(url)) => assert_eq!(url, "/v2/foo/blobs/bar"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        match delete_error("/v2/foo/blobs/bar", StatusCode::METHOD_NOT_ALLOWED) {
+            Some(RegistryError::DeletionDisabled) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        assert!(delete_error("/v2/foo/blobs/bar", StatusCode::FORBIDDEN).is_none());
+    }
+}
@@ -0,0 +1,210 @@
+//! Error responses as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#error-codes)
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Error codes a registry may return in the body of an error response.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub enum ErrorCode {
+    /// Blob unknown to registry
+    BlobUnknown,
+
+    /// Blob upload invalid
+    BlobUploadInvalid,
+
+    /// Blob upload unknown to registry
+    BlobUploadUnknown,
+
+    /// Provided digest did not match uploaded content
+    DigestInvalid,
+
+    /// Manifest references a manifest or blob unknown to registry
+    ManifestBlobUnknown,
+
+    /// Manifest invalid
+    ManifestInvalid,
+
+    /// Manifest unknown to registry
+    ManifestUnknown,
+
+    /// Invalid repository name
+    NameInvalid,
+
+    /// Repository name not known to registry
+    NameUnknown,
+
+    /// Provided length did not match content length
+    SizeInvalid,
+
+    /// Manifest tag did not match URI
+    TagInvalid,
+
+    /// Authentication required
+    Unauthorized,
+
+    /// Requested access to the resource is denied
+    Denied,
+
+    /// The operation is unsupported
+    Unsupported,
+
+    /// Too many requests
+    TooManyRequests,
+
+    /// Any other error code, which is not defined by the spec.
+    Other(String),
+}
+
+impl std::str::FromStr for ErrorCode {
+    type Err = void::Void;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "BLOB_UNKNOWN" => ErrorCode::BlobUnknown,
+            "BLOB_UPLOAD_INVALID" => ErrorCode::BlobUploadInvalid,
+            "BLOB_UPLOAD_UNKNOWN" => ErrorCode::BlobUploadUnknown,
+            "DIGEST_INVALID" => ErrorCode::DigestInvalid,
+            "MANIFEST_BLOB_UNKNOWN" => ErrorCode::ManifestBlobUnknown,
+            "MANIFEST_INVALID" => ErrorCode::ManifestInvalid,
+            "MANIFEST_UNKNOWN" => ErrorCode::ManifestUnknown,
+            "NAME_INVALID" => ErrorCode::NameInvalid,
+            "NAME_UNKNOWN" => ErrorCode::NameUnknown,
+            "SIZE_INVALID" => ErrorCode::SizeInvalid,
+            "TAG_INVALID" => ErrorCode::TagInvalid,
+            "UNAUTHORIZED" => ErrorCode::Unauthorized,
+            "DENIED" => ErrorCode::Denied,
+            "UNSUPPORTED" => ErrorCode::Unsupported,
+            "TOOMANYREQUESTS" => ErrorCode::TooManyRequests,
+            other => ErrorCode::Other(other.into()),
+        })
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ErrorCode::BlobUnknown => "BLOB_UNKNOWN",
+                ErrorCode::BlobUploadInvalid => "BLOB_UPLOAD_INVALID",
+                ErrorCode::BlobUploadUnknown => "BLOB_UPLOAD_UNKNOWN",
+                ErrorCode::DigestInvalid => "DIGEST_INVALID",
+                ErrorCode::ManifestBlobUnknown => "MANIFEST_BLOB_UNKNOWN",
+                ErrorCode::ManifestInvalid => "MANIFEST_INVALID",
+                ErrorCode::ManifestUnknown => "MANIFEST_UNKNOWN",
+                ErrorCode::NameInvalid => "NAME_INVALID",
+                ErrorCode::NameUnknown => "NAME_UNKNOWN",
+                ErrorCode::SizeInvalid => "SIZE_INVALID",
+                ErrorCode::TagInvalid => "TAG_INVALID",
+                ErrorCode::Unauthorized => "UNAUTHORIZED",
+                ErrorCode::Denied => "DENIED",
+                ErrorCode::Unsupported => "UNSUPPORTED",
+                ErrorCode::TooManyRequests => "TOOMANYREQUESTS",
+                ErrorCode::Other(code) => code,
+            }
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// A single error returned by the registry.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DistributionError {
+    /// The error code.
+    pub code: ErrorCode,
+
+    /// A human readable description of the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+
+    /// Unstructured information about the error, depending on the error
+    /// code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<serde_json::Value>,
+}
+
+impl std::fmt::Display for DistributionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.message {
+            Some(ref message) => write!(f, "{}: {}", self.code, message),
+            None => write!(f, "{}", self.code),
+        }
+    }
+}
+
+/// Body of an error response.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub errors: Vec<DistributionError>,
+}
+
+impl std::str::FromStr for ErrorResponse {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let response: ErrorResponse = r#"{
+            "errors": [
+                {
+                    "code": "MANIFEST_UNKNOWN",
+                    "message": "manifest unknown",
+                    "detail": {"Tag": "doesnotexist"}
+                },
+                {
+                    "code": "SOMETHING_ELSE"
+                }
+            ]
+        }"#
+        .parse()
+        .expect("Could not parse error response");
+
+        assert_eq!(response.errors.len(), 2);
+        assert_eq!(response.errors[0].code, ErrorCode::ManifestUnknown);
+        assert_eq!(
+            response.errors[0].to_string(),
+            "MANIFEST_UNKNOWN: manifest unknown"
+        );
+        assert_eq!(
+            response.errors[1].code,
+            ErrorCode::Other("SOMETHING_ELSE".into())
+        );
+        assert_eq!(response.errors[1].message, None);
+    }
+
+    #[test]
+    fn test_error_code_roundtrip() {
+        for code in &["BLOB_UNKNOWN", "DENIED", "TOOMANYREQUESTS", "FOO"] {
+            let parsed: ErrorCode = code.parse().unwrap();
+            assert_eq!(&parsed.to_string(), code);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Pushing manifests as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#pushing-manifests)
+
+use crate::distribution::{Registry, RegistryError};
+use crate::image::manifest::{self, Digest, ManifestError, ManifestV2, ManifestV2Schema};
+
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::Method;
+
+/// Name of the header a registry uses to report the digest of a manifest.
+pub(crate) const DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
+
+/// Description of a manifest, as returned by [Registry::resolve].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedManifest {
+    /// The digest of the manifest.
+    pub digest: Digest,
+
+    /// The media type of the manifest.
+    pub media_type: String,
+
+    /// The size of the manifest in bytes.
+    pub size: usize,
+}
+
+impl Registry {
+    /// Push a manifest to a repository under the given reference.
+    ///
+    /// The reference can either be a tag or the digest of the manifest.
+    ///
+    /// The manifest is serialized again, so this is meant for manifests
+    /// built by the caller. Fields unknown to this crate and the formatting
+    /// of a pulled manifest are lost, changing its digest, so push
+    /// [Image::raw_manifest](crate::Image::raw_manifest) using
+    /// [push_manifest_raw](Registry::push_manifest_raw) instead.
+    ///
+    /// Returns the digest reported by the registry, after checking it
+    /// against the digest of the serialized manifest.
+    pub fn push_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        manifest: &ManifestV2,
+    ) -> Result<Digest, RegistryError> {
+        #[allow(clippy::or_fun_call)]
+        let media_type = manifest
+            .media_type()
+            .ok_or(RegistryError::UnsupportedManifestSchema(
+                ManifestV2Schema::from(manifest),
+            ))?;
+
+        let data = serde_json::to_vec(manifest)
+            .map_err(ManifestError::JsonError)
+            .map_err(RegistryError::ManifestError)?;
+
+        self.push_manifest_raw(name, reference, media_type, &data)
+    }
+
+    /// Push a serialized manifest to a repository under the given reference.
+    ///
+    /// Since the digest of a manifest is calculated over its serialized
+    /// form, this should be used to push a manifest that has to keep its
+    /// digest.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let image = registry.image::<ImagePlatformSelector>(("hello-world", "latest"))
+    ///     .expect("Could not get image");
+    /// let media_type = image.manifest().media_type().expect("Manifest has no media type");
+    /// let digest = registry
+    ///     .push_manifest_raw("hello-world", "stable", media_type, image.raw_manifest())
+    ///     .expect("Could not push manifest");
+    /// ```
+    pub fn push_manifest_raw(
+        &self,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> Result<Digest, RegistryError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(media_type)
+                .map_err(|_| ManifestError::InvalidMediaType(media_type.into()))
+                .map_err(RegistryError::ManifestError)?,
+        );
+
+        let response = self.request(Method::PUT, &url, Some(&headers), Some(data))?;
+
+        let digest = Digest::sha256(data);
+
+        let reported = match response.headers().get(DOCKER_CONTENT_DIGEST) {
+            Some(header) => header
+                .to_str()
+                .map_err(|_| RegistryError::MissingHeader(DOCKER_CONTENT_DIGEST.into()))?
+                .parse()
+                .map_err(RegistryError::ManifestError)?,
+            None => {
+                warn!(
+                    "Registry did not report a digest for {}:{}",
+                    name, reference
+                );
+                return Ok(digest);
+            }
+        };
+
+        if reported != digest {
+            return Err(RegistryError::DigestMismatch(digest, reported));
+        }
+
+        Ok(digest)
+    }
+
+    /// Resolve a reference to the digest of the manifest it points to.
+    ///
+    /// This only issues a `HEAD` request, so the manifest is not downloaded,
+    /// and the request does not count as a pull on registries that limit
+    /// the rate of pulls.
+    ///
+    /// # Example
+    /// ```
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# use opencontainers::distribution::cassette::CassetteTransport;
+    ///# let transport = CassetteTransport::replay_file(concat!(
+    ///#     env!("CARGO_MANIFEST_DIR"),
+    ///#     "/src/image/test/synthetic-hello-world.cassette.json"
+    ///# )).unwrap();
+    ///# let registry = Registry::builder("https://registry.example.com")
+    ///#     .transport(std::sync::Arc::new(transport))
+    ///#     .build()
+    ///#     .unwrap();
+    /// let resolved = registry.resolve("library/hello-world", "latest")
+    ///     .expect("Could not resolve tag");
+    /// println!("latest is {}", resolved.digest);
+    /// ```
+    pub fn resolve(&self, name: &str, reference: &str) -> Result<ResolvedManifest, RegistryError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            manifest::MANIFEST_V2_MEDIA_TYPES.join(",").parse().unwrap(),
+        );
+
+        let response = self.request(Method::HEAD, &url, Some(&headers), None)?;
+        let headers = response.headers();
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| RegistryError::MissingHeader(name.into()))
+        };
+
+        let digest = match header(DOCKER_CONTENT_DIGEST) {
+            Ok(digest) => digest.parse().map_err(RegistryError::ManifestError)?,
+            // If we asked for a digest, we already know the answer.
+            Err(e) => reference.parse().map_err(|_| e)?,
+        };
+
+        let media_type = header(CONTENT_TYPE.as_str())?.to_owned();
+
+        let size = header(CONTENT_LENGTH.as_str())?
+            .parse()
+            .map_err(|_| RegistryError::MissingHeader(CONTENT_LENGTH.to_string()))?;
+
+        Ok(ResolvedManifest {
+            digest,
+            media_type,
+            size,
+        })
+    }
+
+    /// Fetch a manifest without parsing it.
+    ///
+    /// Returns the media type reported by the registry and the manifest as
+    /// it was served.
+    pub fn get_manifest_raw(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<(String, Vec<u8>), RegistryError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            manifest::MANIFEST_V2_MEDIA_TYPES.join(",").parse().unwrap(),
+        );
+
+        let mut response = self.get(&url, Some(&headers))?;
+
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| RegistryError::MissingHeader(CONTENT_TYPE.to_string()))?
+            .to_owned();
+
+        let mut data = Vec::new();
+        response
+            .copy_to(&mut data)
+            .map_err(RegistryError::ReqwestError)?;
+
+        verify_manifest(reference, &data)?;
+
+        Ok((media_type, data))
+    }
+
+    /// Tag an existing manifest with a new tag.
+    ///
+    /// The manifest is pushed again exactly as served by the registry, so
+    /// no layers have to be pulled.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let digest = registry.tag("hello-world", "latest", "stable")
+    ///     .expect("Could not tag image");
+    /// ```
+    pub fn tag(&self, name: &str, reference: &str, tag: &str) -> Result<Digest, RegistryError> {
+        let (media_type, data) = self.get_manifest_raw(name, reference)?;
+
+        self.push_manifest_raw(name, tag, &media_type, &data)
+    }
+}
+
+/// Check a manifest fetched by digest against that digest.
+///
+/// Manifests fetched by tag cannot be checked and are accepted as they are.
+pub(crate) fn verify_manifest(reference: &str, data: &[u8]) -> Result<(), RegistryError> {
+    match reference.parse::<Digest>() {
+        Ok(digest) if !digest.verify(data) => {
+            Err(RegistryError::DigestMismatch(digest, Digest::sha256(data)))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,511 @@
+//! Registry mirrors and prefix rewriting as configured in containers'
+//! [registries.conf](https://github.com/containers/image/blob/main/docs/containers-registries.conf.5.md)
+//! (version 2).
+//!
+//! Images are pulled from the mirrors configured for their registry in
+//! order, and from the registry itself if no mirror has the image.
+
+use crate::distribution::credentials::DockerConfig;
+use crate::distribution::{Registry, RegistryError};
+use crate::image::reference::{ReferenceError, DEFAULT_DOMAIN};
+use crate::image::{Image, ImageReference, ImageSelector};
+
+use std::path::{Path, PathBuf};
+
+/// Name of the default registry in `registries.conf`.
+const DOCKER_HUB: &str = "docker.io";
+
+#[derive(Debug, Fail)]
+#[allow(clippy::large_enum_variant)]
+pub enum MirrorError {
+    #[fail(display = "IO Error: {:?}", _0)]
+    IoError(#[cause] std::io::Error),
+
+    #[fail(display = "TOML Error: {:?}", _0)]
+    TomlError(#[cause] toml::de::Error),
+
+    #[fail(display = "Pulling from {} is blocked", _0)]
+    Blocked(String),
+
+    #[fail(display = "Invalid rewritten reference {}: {:?}", _0, _1)]
+    InvalidReference(String, #[cause] ReferenceError),
+
+    #[fail(display = "Registry Error: {:?}", _0)]
+    RegistryError(#[cause] RegistryError),
+}
+
+/// Which images may be pulled from a mirror.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullFromMirror {
+    /// Images referenced by tag or digest.
+    #[default]
+    #[serde(alias = "")]
+    All,
+
+    /// Only images referenced by digest.
+    DigestOnly,
+
+    /// Only images referenced by tag.
+    TagOnly,
+}
+
+/// A `[[registry.mirror]]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MirrorConf {
+    /// Location of the mirror, replacing the prefix of the registry.
+    pub location: String,
+
+    /// Whether the mirror may be contacted using plain HTTP or with invalid
+    /// certificates.
+    #[serde(default)]
+    pub insecure: bool,
+
+    /// Which images may be pulled from the mirror.
+    #[serde(default)]
+    pub pull_from_mirror: PullFromMirror,
+}
+
+/// A `[[registry]]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryConf {
+    /// Images this configuration applies to, such as `example.com/foo` or
+    /// `*.example.com`. Defaults to the location.
+    #[serde(default)]
+    pub prefix: Option<String>,
+
+    /// Location images matching the prefix are pulled from.
+    #[serde(default)]
+    pub location: String,
+
+    /// Whether the registry may be contacted using plain HTTP or with
+    /// invalid certificates.
+    #[serde(default)]
+    pub insecure: bool,
+
+    /// Whether pulling images matching the prefix is forbidden.
+    #[serde(default)]
+    pub blocked: bool,
+
+    /// Only pull images referenced by digest from the mirrors.
+    #[serde(default)]
+    pub mirror_by_digest_only: bool,
+
+    /// Mirrors to try before the location, in order.
+    #[serde(default, rename = "mirror")]
+    pub mirrors: Vec<MirrorConf>,
+}
+
+impl RegistryConf {
+    /// Return the prefix of this configuration.
+    fn prefix(&self) -> &str {
+        match self.prefix {
+            Some(ref prefix) if !prefix.is_empty() => prefix,
+            _ => &self.location,
+        }
+    }
+
+    /// Return the length of the part of a qualified name matching the prefix.
+    fn matches(&self, name: &str) -> Option<usize> {
+        let prefix = self.prefix();
+
+        if prefix.starts_with("*.") {
+            let host = name.split('/').next().unwrap_or_default();
+            return if host.ends_with(&prefix[1..]) {
+                Some(host.len())
+            } else {
+                None
+            };
+        }
+
+        let prefix = normalize_domain(prefix);
+        if name == prefix || name.starts_with(&format!("{}/", prefix)) {
+            Some(prefix.len())
+        } else {
+            None
+        }
+    }
+}
+
+/// Contents of a `registries.conf` file.
+///
+/// # Example
+/// ```no_run
+///# extern crate opencontainers;
+///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
+/// use opencontainers::distribution::mirrors::{Mirrors, RegistriesConf};
+///
+/// let conf = RegistriesConf::load().expect("Could not load registries.conf");
+/// let reference = "nginx:latest".parse().expect("Could not parse reference");
+/// let mirrors = Mirrors::new(&conf, &reference).expect("Could not configure mirrors");
+/// let image = mirrors.image::<ImagePlatformSelector>().expect("Could not get image");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistriesConf {
+    /// Registries to search for images without a domain.
+    #[serde(default)]
+    pub unqualified_search_registries: Vec<String>,
+
+    /// Configuration by prefix.
+    #[serde(default, rename = "registry")]
+    pub registries: Vec<RegistryConf>,
+}
+
+impl std::str::FromStr for RegistriesConf {
+    type Err = MirrorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(MirrorError::TomlError)
+    }
+}
+
+/// A location to pull an image from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    /// The reference to the image at this location.
+    pub reference: ImageReference,
+
+    /// Whether the location may be contacted using plain HTTP or with
+    /// invalid certificates.
+    pub insecure: bool,
+
+    /// Whether the location is a mirror.
+    pub mirror: bool,
+}
+
+impl Endpoint {
+    /// Create the registry interface for this endpoint.
+    pub fn registry(&self) -> Result<Registry, RegistryError> {
+        Registry::builder(&self.reference.registry_url())
+            .insecure(self.insecure)
+            .build()
+    }
+}
+
+impl RegistriesConf {
+    /// Return the path `registries.conf` is loaded from.
+    ///
+    /// These are, in order of precedence:
+    /// * `$CONTAINERS_REGISTRIES_CONF`
+    /// * `$XDG_CONFIG_HOME/containers/registries.conf`
+    /// * `/etc/containers/registries.conf`
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("CONTAINERS_REGISTRIES_CONF") {
+            return Some(path.into());
+        }
+
+        dirs::config_dir()
+            .map(|dir| dir.join("containers").join("registries.conf"))
+            .into_iter()
+            .chain(Some(PathBuf::from("/etc/containers/registries.conf")))
+            .find(|path| path.is_file())
+    }
+
+    /// Load the configuration from [RegistriesConf::default_path], or return
+    /// an empty configuration if there is none.
+    pub fn load() -> Result<Self, MirrorError> {
+        match Self::default_path() {
+            Some(path) => {
+                info!("Loading registry configuration from {:?}", path);
+                Self::from_file(path)
+            }
+            None => Ok(RegistriesConf::default()),
+        }
+    }
+
+    /// Load a single configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MirrorError> {
+        std::fs::read_to_string(path)
+            .map_err(MirrorError::IoError)?
+            .parse()
+    }
+
+    /// Return the configuration for an image, which is the one with the
+    /// longest matching prefix.
+    pub fn find(&self, reference: &ImageReference) -> Option<&RegistryConf> {
+        let name = qualified_name(reference);
+
+        self.registries
+            .iter()
+            .filter_map(|registry| registry.matches(&name).map(|len| (len, registry)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, registry)| registry)
+    }
+
+    /// Return the locations to pull an image from, in order: the mirrors
+    /// allowed for the reference, followed by the location of the registry.
+    pub fn endpoints(&self, reference: &ImageReference) -> Result<Vec<Endpoint>, MirrorError> {
+        let registry = match self.find(reference) {
+            Some(registry) => registry,
+            None => {
+                return Ok(vec![Endpoint {
+                    reference: reference.clone(),
+                    insecure: false,
+                    mirror: false,
+                }])
+            }
+        };
+
+        if registry.blocked {
+            return Err(MirrorError::Blocked(reference.to_string()));
+        }
+
+        let name = qualified_name(reference);
+        // The prefix matched when finding the registry.
+        let prefix_len = registry.matches(&name).unwrap();
+
+        let by_digest = reference.digest.is_some();
+        let mut endpoints = Vec::new();
+
+        for mirror in &registry.mirrors {
+            let allowed = match mirror.pull_from_mirror {
+                _ if registry.mirror_by_digest_only => by_digest,
+                PullFromMirror::All => true,
+                PullFromMirror::DigestOnly => by_digest,
+                PullFromMirror::TagOnly => !by_digest,
+            };
+
+            if !allowed {
+                continue;
+            }
+
+            endpoints.push(Endpoint {
+                reference: rewrite(reference, prefix_len, &mirror.location)?,
+                insecure: mirror.insecure,
+                mirror: true,
+            });
+        }
+
+        let reference = if registry.location.is_empty() {
+            reference.clone()
+        } else {
+            rewrite(reference, prefix_len, &registry.location)?
+        };
+
+        endpoints.push(Endpoint {
+            reference,
+            insecure: registry.insecure,
+            mirror: false,
+        });
+
+        Ok(endpoints)
+    }
+}
+
+/// Registries to pull an image from, in order of preference.
+#[derive(Debug)]
+pub struct Mirrors {
+    endpoints: Vec<(Registry, ImageReference)>,
+}
+
+impl Mirrors {
+    /// Create the registry interfaces for all locations of an image.
+    pub fn new(conf: &RegistriesConf, reference: &ImageReference) -> Result<Self, MirrorError> {
+        let endpoints = conf
+            .endpoints(reference)?
+            .into_iter()
+            .map(|endpoint| {
+                let registry = endpoint.registry().map_err(MirrorError::RegistryError)?;
+                Ok((registry, endpoint.reference))
+            })
+            .collect::<Result<_, MirrorError>>()?;
+
+        Ok(Mirrors { endpoints })
+    }
+
+    /// Use the credentials stored for each location.
+    pub fn with_docker_config(self, config: &DockerConfig) -> Self {
+        Mirrors {
+            endpoints: self
+                .endpoints
+                .into_iter()
+                .map(|(registry, reference)| (registry.with_docker_config(config), reference))
+                .collect(),
+        }
+    }
+
+    /// Return the registries and the reference to the image in each.
+    pub fn endpoints(&self) -> impl Iterator<Item = (&Registry, &ImageReference)> {
+        self.endpoints
+            .iter()
+            .map(|(registry, reference)| (registry, reference))
+    }
+
+    /// Create an image handle from the first location that has the image.
+    ///
+    /// If no location has the image, the error of the last location is
+    /// returned.
+    pub fn image<IS>(&self) -> Result<Image<'_>, RegistryError>
+    where
+        IS: ImageSelector,
+    {
+        let mut result = Err(RegistryError::NotFound("No locations configured".into()));
+
+        for (registry, reference) in &self.endpoints {
+            result = Image::new::<IS>(registry, reference);
+
+            match result {
+                Ok(_) => break,
+                Err(ref e) => info!("Could not get {} from {}: {}", reference, registry.url, e),
+            }
+        }
+
+        result
+    }
+}
+
+/// Map the default domain to the name used in `registries.conf`.
+fn normalize_domain(name: &str) -> String {
+    let mut split = name.splitn(2, '/');
+    let domain = split.next().unwrap_or_default();
+
+    let domain = match domain {
+        DEFAULT_DOMAIN | "index.docker.io" => DOCKER_HUB,
+        domain => domain,
+    };
+
+    match split.next() {
+        Some(rest) => format!("{}/{}", domain, rest),
+        None => domain.into(),
+    }
+}
+
+/// Return the fully qualified repository name of a reference, as matched
+/// against prefixes.
+fn qualified_name(reference: &ImageReference) -> String {
+    normalize_domain(&format!("{}/{}", reference.domain, reference.name))
+}
+
+/// Replace the first `prefix_len` bytes of the qualified name of a reference
+/// with a location.
+fn rewrite(
+    reference: &ImageReference,
+    prefix_len: usize,
+    location: &str,
+) -> Result<ImageReference, MirrorError> {
+    let name = qualified_name(reference);
+    let mut rewritten = format!("{}{}", location, &name[prefix_len..]);
+
+    if let Some(ref tag) = reference.tag {
+        rewritten = format!("{}:{}", rewritten, tag);
+    }
+
+    if let Some(ref digest) = reference.digest {
+        rewritten = format!("{}@{}", rewritten, digest);
+    }
+
+    rewritten
+        .parse()
+        .map_err(|e| MirrorError::InvalidReference(rewritten, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn conf() -> RegistriesConf {
+        include_str!("test/registries.test.conf")
+            .parse()
+            .expect("Could not parse registries.conf")
+    }
+
+    fn endpoints(reference: &str) -> Vec<(String, bool, bool)> {
+        conf()
+            .endpoints(&reference.parse().unwrap())
+            .expect("Could not get endpoints")
+            .into_iter()
+            .map(|e| (e.reference.to_string(), e.insecure, e.mirror))
+            .collect()
+    }
+
+    #[test]
+    fn test_registries_conf() {
+        let conf = conf();
+        assert_eq!(
+            conf.unqualified_search_registries,
+            vec!["docker.io".to_owned(), "quay.io".to_owned()]
+        );
+        assert_eq!(conf.registries.len(), 4);
+        assert_eq!(
+            conf.registries[0].mirrors[1].pull_from_mirror,
+            PullFromMirror::DigestOnly
+        );
+    }
+
+    #[test]
+    fn test_mirrors_by_tag() {
+        assert_eq!(
+            endpoints("nginx:1.25"),
+            vec![
+                (
+                    "mirror.example.com:5000/library/nginx:1.25".into(),
+                    true,
+                    true
+                ),
+                (
+                    "registry-1.docker.io/library/nginx:1.25".into(),
+                    false,
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mirrors_by_digest() {
+        let reference = format!("nginx@{}", DIGEST);
+        assert_eq!(
+            endpoints(&reference),
+            vec![
+                (
+                    format!("mirror.example.com:5000/library/nginx@{}", DIGEST),
+                    true,
+                    true
+                ),
+                (
+                    format!("digests.example.com/dockerhub/library/nginx@{}", DIGEST),
+                    false,
+                    true
+                ),
+                (
+                    format!("registry-1.docker.io/library/nginx@{}", DIGEST),
+                    false,
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefix_rewriting() {
+        assert_eq!(
+            endpoints("example.com/foo/baz:1.0"),
+            vec![("internal.example.com/bar/baz:1.0".into(), false, false)]
+        );
+        assert_eq!(
+            endpoints("example.com/other:1.0"),
+            vec![("example.com/other:1.0".into(), true, false)]
+        );
+        assert_eq!(
+            endpoints("example.com/foobar:1.0"),
+            vec![("example.com/foobar:1.0".into(), true, false)]
+        );
+        assert_eq!(
+            endpoints("quay.io/foo:1.0"),
+            vec![("quay.io/foo:1.0".into(), false, false)]
+        );
+    }
+
+    #[test]
+    fn test_blocked() {
+        match conf().endpoints(&"registry.blocked.example.com/foo".parse().unwrap()) {
+            Err(MirrorError::Blocked(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,60 @@
 mod auth;
-use auth::{Authenticate, Credential};
+use auth::Authenticate;
+pub use auth::{Credential, Token};
 
-use crate::image::Image;
+mod blob;
+pub use blob::BlobReader;
 
-use reqwest::{Client, StatusCode};
-use ttl_cache::TtlCache;
+mod builder;
+pub use builder::RegistryBuilder;
+
+pub mod cassette;
+
+mod catalog;
+pub use catalog::{Catalog, RepositoryList};
+
+pub mod credential_helper;
+pub mod credentials;
+
+mod delete;
+
+pub mod errors;
+use errors::{DistributionError, ErrorCode, ErrorResponse};
+
+mod manifest;
+pub(crate) use manifest::verify_manifest;
+pub use manifest::ResolvedManifest;
+
+pub mod mirrors;
+
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
+pub mod pagination;
+
+mod referrers;
+pub use referrers::referrers_tag;
+
+mod retry;
+pub use retry::RetryPolicy;
+
+mod tags;
+pub use tags::{TagList, Tags};
+
+mod token_cache;
+use token_cache::TokenCache;
+
+pub mod transport;
+use transport::{HttpRequest, Transport};
+
+mod upload;
+pub use upload::{BlobMount, BlobUpload, DEFAULT_CHUNK_SIZE};
+
+use crate::image::{Image, ImageReference, ToImageName};
+
+use reqwest::{Method, StatusCode};
+
+use std::sync::Arc;
 
 #[derive(Debug, Fail)]
 #[allow(clippy::large_enum_variant)]
@@ -29,22 +79,113 @@ pub enum RegistryError {
 
     #[fail(display = "Image Spec Error: {:?}", _0)]
     ImageSpecError(#[cause] crate::image::spec::ImageSpecError),
+
+    #[fail(display = "Invalid URL: {:?}", _0)]
+    UrlParseError(#[cause] reqwest::UrlError),
+
+    #[fail(display = "Missing header in response: {}", _0)]
+    MissingHeader(String),
+
+    #[fail(display = "Invalid header value: {}", _0)]
+    InvalidHeader(String),
+
+    #[fail(display = "IO Error: {:?}", _0)]
+    IoError(#[cause] std::io::Error),
+
+    #[fail(display = "Not found: {}", _0)]
+    NotFound(String),
+
+    #[fail(display = "Deletion is disabled on this registry")]
+    DeletionDisabled,
+
+    #[fail(display = "Registry responded with {}: {:?}", _0, _1)]
+    Distribution(StatusCode, Vec<DistributionError>),
+
+    #[fail(display = "No recorded response for {}", _0)]
+    NotRecorded(String),
+
+    #[fail(display = "Digest mismatch: expected {}, got {}", _0, _1)]
+    DigestMismatch(
+        crate::image::manifest::Digest,
+        crate::image::manifest::Digest,
+    ),
+
+    #[fail(display = "Size mismatch: expected {} bytes, got {}", _0, _1)]
+    SizeMismatch(u64, u64),
+}
+
+impl RegistryError {
+    /// Create an error from an unsuccessful response, parsing the error
+    /// codes in its body.
+    pub(crate) fn from_response(mut response: reqwest::Response) -> Self {
+        let status = response.status();
+
+        let errors = response
+            .text()
+            .ok()
+            .and_then(|body| body.parse::<ErrorResponse>().ok())
+            .map(|body| body.errors)
+            .unwrap_or_default();
+
+        RegistryError::Distribution(status, errors)
+    }
+
+    /// Create an error from a failed read, recovering errors raised while
+    /// verifying a [BlobReader].
+    pub(crate) fn from_io(error: std::io::Error) -> Self {
+        type Inner = failure::Compat<RegistryError>;
+
+        if !matches!(error.get_ref(), Some(e) if e.is::<Inner>()) {
+            return RegistryError::IoError(error);
+        }
+
+        match error.into_inner().map(|e| e.downcast::<Inner>()) {
+            Some(Ok(inner)) => inner.into_inner(),
+            _ => unreachable!("Inner error is a RegistryError"),
+        }
+    }
+
+    /// Check whether the registry responded with the given error code.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
+    /// use opencontainers::distribution::errors::ErrorCode;
+    ///
+    ///# let registry = Registry::new("https://registry-1.docker.io");
+    /// match registry.image::<ImagePlatformSelector>(("library/hello-world", "doesnotexist")) {
+    ///     Err(ref e) if e.has_code(&ErrorCode::ManifestUnknown) => println!("no such image"),
+    ///     Err(e) => panic!("{}", e),
+    ///     Ok(_) => {}
+    /// }
+    /// ```
+    pub fn has_code(&self, code: &ErrorCode) -> bool {
+        match self {
+            RegistryError::Distribution(_, errors) => errors.iter().any(|e| e.code == *code),
+            _ => false,
+        }
+    }
 }
 
 /// Represents a Registry implementing the [OpenContainer Distribution
 /// Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md)
 pub struct Registry {
     pub url: String,
-    client: Client,
-    credential_cache: TtlCache<String, Credential>,
+    transport: Arc<dyn Transport>,
+    tokens: TokenCache,
+    login: Option<Credential>,
+    force_oauth: bool,
+    retry: RetryPolicy,
 }
 
 impl std::fmt::Debug for Registry {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Registry {{ url: {}, client: {:?} }}",
-            self.url, self.client
+            "Registry {{ url: {}, transport: {:?} }}",
+            self.url, self.transport
         )
     }
 }
@@ -66,48 +207,155 @@ impl Registry {
     /// This function can panic if the backing
     /// [ClientBuilder](https://docs.rs/reqwest/0/reqwest/struct.ClientBuilder.html)
     /// cannot be initialized. This can happen if the native TLS backend
-    /// cannot be initialized.
+    /// cannot be initialized. Use [Registry::builder] to handle this error.
     pub fn new(url: &str) -> Self {
-        let client = Client::builder()
-            .gzip(true)
+        Self::builder(url)
             .build()
-            .expect("Could not build request client");
+            .expect("Could not build request client")
+    }
+
+    /// Create a builder for a registry interface, to configure TLS, proxies
+    /// and timeouts.
+    ///
+    /// See [RegistryBuilder] for an example.
+    pub fn builder(url: &str) -> RegistryBuilder {
+        RegistryBuilder::new(url)
+    }
+
+    /// Use static credentials when authenticating with the registry.
+    pub fn with_credential(mut self, credential: Credential) -> Self {
+        self.login = Some(credential);
+        self
+    }
 
-        let credential_cache: TtlCache<String, Credential> = TtlCache::new(32);
+    /// Use a username and password when authenticating with the registry.
+    ///
+    /// Registries using `Basic` authentication receive them directly, while
+    /// registries using token authentication receive a token for them.
+    ///
+    /// # Example
+    /// ```
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    /// let registry = Registry::new("https://registry.example.com")
+    ///     .with_basic_auth("user", "secret");
+    /// ```
+    pub fn with_basic_auth(self, username: &str, password: &str) -> Self {
+        self.with_credential(Credential::Basic {
+            username: username.into(),
+            password: password.into(),
+        })
+    }
+
+    /// Create a new registry interface for the registry an image reference
+    /// points to.
+    ///
+    /// # Example
+    /// ```
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    /// let reference = "nginx:latest".parse().expect("Could not parse reference");
+    /// let registry = Registry::from_reference(&reference);
+    ///# assert_eq!(registry.url, "https://registry-1.docker.io");
+    /// ```
+    ///
+    /// # Panics
+    /// This function can panic for the same reasons as [Registry::new].
+    pub fn from_reference(reference: &ImageReference) -> Self {
+        Self::new(&reference.registry_url())
+    }
+
+    /// Use the credentials stored for this registry by `docker login` or a
+    /// compatible tool.
+    ///
+    /// See [credentials::DockerConfig] for an example.
+    pub fn with_docker_config(self, config: &credentials::DockerConfig) -> Self {
+        let host = match reqwest::Url::parse(&self.url) {
+            Ok(url) => match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_owned(),
+                (None, _) => return self,
+            },
+            Err(_) => return self,
+        };
 
-        Registry {
-            url: url.into(),
-            client,
-            credential_cache,
+        match config.credential_for(&host) {
+            Ok(Some(credential)) => self.with_credential(credential),
+            Ok(None) => self,
+            Err(e) => {
+                warn!("Ignoring stored credentials for {}: {}", host, e);
+                self
+            }
         }
     }
 
+    /// Return the policy for retrying failed requests.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
     fn try_auth(
         &self,
         authenticate: &reqwest::header::HeaderValue,
-    ) -> Result<Vec<Credential>, RegistryError> {
-        auth::do_challenge(&self.client, authenticate)
+        extra_scopes: &[String],
+    ) -> Result<Vec<(Option<token_cache::TokenKey>, Credential)>, RegistryError> {
+        auth::do_challenge(
+            self.transport.as_ref(),
+            authenticate,
+            extra_scopes,
+            self.login.as_ref(),
+            self.force_oauth,
+            &self.tokens,
+        )
     }
 
     fn attempt_request(
         &self,
+        method: &Method,
         url: &str,
         headers: Option<&reqwest::header::HeaderMap>,
+        body: Option<&[u8]>,
         cred: Option<&Credential>,
     ) -> Result<Result<reqwest::Response, reqwest::Response>, RegistryError> {
-        let mut request = self.client.get(url);
+        let mut retry = 0;
 
-        if let Some(headers) = headers {
-            request = request.headers(headers.clone());
-        }
+        let url = reqwest::Url::parse(url).map_err(RegistryError::UrlParseError)?;
 
-        if let Some(credential) = cred {
-            request = request.authenticate(&credential);
-        } else {
-            info!("Attempting unauthenticated request");
-        }
+        let response = loop {
+            let mut request = HttpRequest::new(method.clone(), url.clone());
+
+            if let Some(headers) = headers {
+                request.headers = headers.clone();
+            }
+
+            request.body = body.map(<[u8]>::to_vec);
 
-        let response = request.send().map_err(RegistryError::ReqwestError)?;
+            if let Some(credential) = cred {
+                request = request.authenticate(credential);
+            } else {
+                info!("Attempting unauthenticated request");
+            }
+
+            let delay = match self.transport.send(request) {
+                Ok(ref response)
+                    if retry::is_retryable_status(response.status())
+                        && retry < self.retry.max_retries =>
+                {
+                    self.retry.delay(retry, response.headers())
+                }
+                Err(RegistryError::ReqwestError(ref e))
+                    if retry::is_retryable_error(method, e) && retry < self.retry.max_retries =>
+                {
+                    self.retry.backoff(retry)
+                }
+                Ok(response) => break response,
+                Err(e) => return Err(e),
+            };
+
+            warn!("Request to {} failed, retrying in {:?}", url, delay);
+            std::thread::sleep(delay);
+            retry += 1;
+        };
 
         let status = response.status();
 
@@ -120,36 +368,52 @@ impl Registry {
         Ok(Err(response))
     }
 
-    /// Perform a GET request on the Registry, handling authentication.
-    ///
-    /// # Authentication
-    /// Authentication is handled transiently according to the [Docker
-    /// Registry Token Authentication
-    /// Specification](https://docs.docker.com/registry/spec/auth/token/)
+    /// Send a request, handling authentication.
     ///
-    /// # Example
-    /// ```
-    ///# extern crate opencontainers;
-    ///# use opencontainers::Registry;
-    ///# let registry = Registry::new("https://registry-1.docker.io");
-    /// let endpoint = format!("{}/v2/", registry.url);
-    /// let response = registry.get(endpoint.as_str(), None)
-    ///     .expect("Could not perform API Version Check");
-    /// assert!(response.status().is_success());
-    /// ```
-    pub fn get(
+    /// Returns the final response of the registry, which is `Err` if the
+    /// registry did not respond with a success status code.
+    fn send(
         &self,
+        method: Method,
         url: &str,
         headers: Option<&reqwest::header::HeaderMap>,
-    ) -> Result<reqwest::Response, RegistryError> {
-        // Try to use the credential if it is cached
-        let credential = self.credential_cache.get(url);
+        body: Option<&[u8]>,
+    ) -> Result<Result<reqwest::Response, reqwest::Response>, RegistryError> {
+        self.send_scoped(method, url, headers, body, &[])
+    }
 
-        // Attempt request
-        let response = match self.attempt_request(url, headers, credential)? {
-            Ok(response) => return Ok(response),
-            Err(response) => response,
+    /// Send a request like [Registry::send], requesting additional scopes
+    /// if the registry asks for authentication.
+    fn send_scoped(
+        &self,
+        method: Method,
+        url: &str,
+        headers: Option<&reqwest::header::HeaderMap>,
+        body: Option<&[u8]>,
+        extra_scopes: &[String],
+    ) -> Result<Result<reqwest::Response, reqwest::Response>, RegistryError> {
+        // Try to use the token accepted for similar requests if it is cached
+        let cached = match extra_scopes {
+            [] => self.tokens.lookup(&method, url),
+            _ => None,
         };
+        let credential = cached
+            .as_ref()
+            .map(|(_, token)| Credential::Token(token.clone()));
+
+        // Attempt request
+        let response =
+            match self.attempt_request(&method, url, headers, body, credential.as_ref())? {
+                Ok(response) => return Ok(Ok(response)),
+                Err(response) => response,
+            };
+
+        // The registry no longer accepts the cached token
+        if let Some((key, _)) = cached {
+            if response.status() == StatusCode::UNAUTHORIZED {
+                self.tokens.remove(&key);
+            }
+        }
 
         // Unauthorized
         let unauthorized = response.status() == StatusCode::UNAUTHORIZED;
@@ -162,7 +426,7 @@ impl Registry {
                 "No authentication challenge presented".into(),
             ));
         } else if !unauthorized {
-            return Err(RegistryError::CouldNotGetToken(response.status()));
+            return Ok(Err(response));
         }
 
         info!("Authentication required");
@@ -174,23 +438,92 @@ impl Registry {
                 "Missing WWW-Authenticate Header".into(),
             ))?;
 
-        let credentials = self.try_auth(authenticate)?;
+        let credentials = self.try_auth(authenticate, extra_scopes)?;
 
         // Attempt with each credential we got
-        for credential in credentials {
-            if let Ok(response) = self.attempt_request(url, headers, Some(&credential))? {
-                info!("Got response: {:?}", response);
-
-                // TODO: Cache credential.
-                return Ok(response);
+        for (key, credential) in credentials {
+            match self.attempt_request(&method, url, headers, body, Some(&credential))? {
+                Ok(response) => {
+                    info!("Got response: {:?}", response);
+
+                    if let Some(key) = key {
+                        self.tokens.remember(&method, url, key);
+                    }
+
+                    return Ok(Ok(response));
+                }
+                Err(response) => {
+                    // We got past authentication, so let the caller handle
+                    // the error.
+                    if response.status() != StatusCode::UNAUTHORIZED {
+                        return Ok(Err(response));
+                    }
+                }
             }
         }
 
         Err(RegistryError::CouldNotAuthenticate)
     }
 
+    /// Perform a request with an arbitrary method on the Registry, handling
+    /// authentication.
+    ///
+    /// If the request requires authentication, the scopes requested by the
+    /// registry's authentication challenge are used to obtain a token, so a
+    /// request that modifies a repository will obtain a token with `push`
+    /// scope.
+    ///
+    /// The body is passed as a slice, since it may have to be sent more than
+    /// once.
+    pub fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: Option<&reqwest::header::HeaderMap>,
+        body: Option<&[u8]>,
+    ) -> Result<reqwest::Response, RegistryError> {
+        self.send(method, url, headers, body)?
+            .map_err(RegistryError::from_response)
+    }
+
+    /// Perform a GET request on the Registry, handling authentication.
+    ///
+    /// # Authentication
+    /// Authentication is handled transiently according to the [Docker
+    /// Registry Token Authentication
+    /// Specification](https://docs.docker.com/registry/spec/auth/token/)
+    ///
+    /// # Example
+    /// ```
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# use opencontainers::distribution::cassette::CassetteTransport;
+    ///# let transport = CassetteTransport::replay_file(concat!(
+    ///#     env!("CARGO_MANIFEST_DIR"),
+    ///#     "/src/image/test/synthetic-hello-world.cassette.json"
+    ///# )).unwrap();
+    ///# let registry = Registry::builder("https://registry.example.com")
+    ///#     .transport(std::sync::Arc::new(transport))
+    ///#     .build()
+    ///#     .unwrap();
+    /// let endpoint = format!("{}/v2/", registry.url);
+    /// let response = registry.get(endpoint.as_str(), None)
+    ///     .expect("Could not perform API Version Check");
+    /// assert!(response.status().is_success());
+    /// ```
+    pub fn get(
+        &self,
+        url: &str,
+        headers: Option<&reqwest::header::HeaderMap>,
+    ) -> Result<reqwest::Response, RegistryError> {
+        self.request(Method::GET, url, headers, None)
+    }
+
     /// Create an image handle for a given image
     ///
+    /// The image is given either as an [ImageReference] or as a pair of a
+    /// repository name and a tag or digest, see [ToImageName].
+    ///
     /// The type parameter has a trait bound on [image::ImageSelector], which can
     /// be implemented to select which image to use when pulling from a
     /// fat manifest.
@@ -201,14 +534,28 @@ impl Registry {
     ///# extern crate opencontainers;
     ///# use opencontainers::Registry;
     ///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
-    ///# let registry = Registry::new("https://registry-1.docker.io");
-    /// let manifest = registry.image::<ImagePlatformSelector>("library/hello-world", "latest")
+    ///# use opencontainers::distribution::cassette::CassetteTransport;
+    ///# let transport = CassetteTransport::replay_file(concat!(
+    ///#     env!("CARGO_MANIFEST_DIR"),
+    ///#     "/src/image/test/synthetic-hello-world.cassette.json"
+    ///# )).unwrap();
+    ///# let registry = Registry::builder("https://registry.example.com")
+    ///#     .transport(std::sync::Arc::new(transport))
+    ///#     .build()
+    ///#     .unwrap();
+    /// let image = registry.image::<ImagePlatformSelector>(("library/hello-world", "latest"))
+    ///     .expect("Could not get image");
+    ///
+    /// let reference = "registry.example.com/library/hello-world:latest"
+    ///     .parse::<opencontainers::image::ImageReference>()
+    ///     .expect("Could not parse reference");
+    /// let image = registry.image::<ImagePlatformSelector>(&reference)
     ///     .expect("Could not get image");
     /// ```
-    pub fn image<IS>(&self, name: &str, reference: &str) -> Result<Image, RegistryError>
+    pub fn image<IS>(&self, image: impl ToImageName) -> Result<Image, RegistryError>
     where
         IS: crate::image::ImageSelector,
     {
-        Image::new::<IS>(self, name, reference)
+        Image::new::<IS>(self, image)
     }
 }
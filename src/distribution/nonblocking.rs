@@ -0,0 +1,630 @@
+//! Asynchronous interface to registries, for use with
+//! [tokio](https://tokio.rs).
+//!
+//! Requests are sent by the blocking [Registry] on tokio's blocking thread
+//! pool, so they never block the runtime, and share its authentication,
+//! token cache and retry behaviour. Blobs are streamed to the caller while
+//! they are downloaded, and can be consumed either as an [AsyncRead] or as
+//! a [Stream] of chunks. Streaming a blob only takes up a thread while a
+//! chunk is read, see [AsyncBlob].
+//!
+//! This module requires the `async` feature.
+//!
+//! # Example
+//! ```no_run
+//!# extern crate opencontainers;
+//! use opencontainers::distribution::nonblocking::AsyncRegistry;
+//! use opencontainers::image::ImagePlatformSelector;
+//! use opencontainers::Registry;
+//!
+//!# async fn pull() {
+//! let registry = AsyncRegistry::new(Registry::new("https://registry-1.docker.io"));
+//! let image = registry
+//!     .image::<ImagePlatformSelector>(("library/hello-world", "latest"))
+//!     .await
+//!     .expect("Could not get image");
+//! let config = image.config().await.expect("Could not get config");
+//!# }
+//! ```
+
+use crate::distribution::{BlobReader, Registry, RegistryError, ResolvedManifest};
+use crate::image::manifest::{Descriptor, Digest, Layer, ManifestV2};
+use crate::image::{self, spec, Image, ImageSelector, ToImageName};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::task::JoinHandle;
+use tokio_util::io::SyncIoBridge;
+
+use std::future::Future;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+/// Size of the chunks blobs are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Asynchronous handle to a [Registry].
+///
+/// Cloning the handle is cheap, clones share the same registry, including
+/// its token cache.
+#[derive(Debug, Clone)]
+pub struct AsyncRegistry {
+    registry: Arc<Registry>,
+}
+
+impl From<Registry> for AsyncRegistry {
+    fn from(registry: Registry) -> Self {
+        Self::new(registry)
+    }
+}
+
+impl AsyncRegistry {
+    pub fn new(registry: Registry) -> Self {
+        AsyncRegistry {
+            registry: Arc::new(registry),
+        }
+    }
+
+    /// Return the blocking registry requests are sent with.
+    pub fn blocking(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Run a blocking operation on the registry.
+    async fn run<F, T>(&self, f: F) -> Result<T, RegistryError>
+    where
+        F: FnOnce(&Registry) -> Result<T, RegistryError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let registry = self.registry.clone();
+        spawn_blocking(move || f(&registry)).await
+    }
+
+    /// Fetch a manifest without parsing it, see
+    /// [Registry::get_manifest_raw].
+    pub async fn get_manifest_raw(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<(String, Vec<u8>), RegistryError> {
+        let (name, reference) = (name.to_owned(), reference.to_owned());
+        self.run(move |registry| registry.get_manifest_raw(&name, &reference))
+            .await
+    }
+
+    /// Resolve a reference to the manifest it refers to, see
+    /// [Registry::resolve].
+    pub async fn resolve(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ResolvedManifest, RegistryError> {
+        let (name, reference) = (name.to_owned(), reference.to_owned());
+        self.run(move |registry| registry.resolve(&name, &reference))
+            .await
+    }
+
+    /// Push a manifest, see [Registry::push_manifest].
+    pub async fn push_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        manifest: ManifestV2,
+    ) -> Result<Digest, RegistryError> {
+        let (name, reference) = (name.to_owned(), reference.to_owned());
+        self.run(move |registry| registry.push_manifest(&name, &reference, &manifest))
+            .await
+    }
+
+    /// Push a serialized manifest, see [Registry::push_manifest_raw].
+    pub async fn push_manifest_raw(
+        &self,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: Vec<u8>,
+    ) -> Result<Digest, RegistryError> {
+        let (name, reference) = (name.to_owned(), reference.to_owned());
+        let media_type = media_type.to_owned();
+        self.run(move |registry| registry.push_manifest_raw(&name, &reference, &media_type, &data))
+            .await
+    }
+
+    /// Tag an existing manifest, see [Registry::tag].
+    pub async fn tag(
+        &self,
+        name: &str,
+        reference: &str,
+        tag: &str,
+    ) -> Result<Digest, RegistryError> {
+        let (name, reference, tag) = (name.to_owned(), reference.to_owned(), tag.to_owned());
+        self.run(move |registry| registry.tag(&name, &reference, &tag))
+            .await
+    }
+
+    /// List all tags of a repository, see [Registry::tags].
+    pub async fn tags(&self, name: &str) -> Result<Vec<String>, RegistryError> {
+        let name = name.to_owned();
+        self.run(move |registry| registry.tags(&name)?.collect())
+            .await
+    }
+
+    /// List all repositories of the registry, see [Registry::catalog].
+    pub async fn catalog(&self) -> Result<Vec<String>, RegistryError> {
+        self.run(|registry| registry.catalog()?.collect()).await
+    }
+
+    /// List the manifests referring to a manifest, see
+    /// [Registry::referrers].
+    pub async fn referrers(
+        &self,
+        name: &str,
+        digest: &Digest,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Descriptor>, RegistryError> {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        let artifact_type = artifact_type.map(str::to_owned);
+        self.run(move |registry| registry.referrers(&name, &digest, artifact_type.as_deref()))
+            .await
+    }
+
+    /// Delete a manifest, see [Registry::delete_manifest].
+    pub async fn delete_manifest(&self, name: &str, digest: &Digest) -> Result<(), RegistryError> {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        self.run(move |registry| registry.delete_manifest(&name, &digest))
+            .await
+    }
+
+    /// Delete a blob, see [Registry::delete_blob].
+    pub async fn delete_blob(&self, name: &str, digest: &Digest) -> Result<(), RegistryError> {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        self.run(move |registry| registry.delete_blob(&name, &digest))
+            .await
+    }
+
+    /// Check whether a blob exists, see [Registry::blob_exists].
+    pub async fn blob_exists(&self, name: &str, digest: &Digest) -> Result<bool, RegistryError> {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        self.run(move |registry| registry.blob_exists(&name, &digest))
+            .await
+    }
+
+    /// Push a blob in a single request, see [Registry::push_blob].
+    pub async fn push_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+        data: Vec<u8>,
+    ) -> Result<(), RegistryError> {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        self.run(move |registry| registry.push_blob(&name, &digest, &data))
+            .await
+    }
+
+    /// Push a blob in chunks read from an [AsyncRead], see
+    /// [Registry::push_blob_chunked].
+    pub async fn push_blob_chunked<R>(
+        &self,
+        name: &str,
+        digest: &Digest,
+        reader: R,
+        chunk_size: usize,
+    ) -> Result<(), RegistryError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (name, digest) = (name.to_owned(), digest.clone());
+        let reader = SyncIoBridge::new(reader);
+        self.run(move |registry| registry.push_blob_chunked(&name, &digest, reader, chunk_size))
+            .await
+    }
+
+    /// Copy a blob from another repository, see [Registry::copy_blob].
+    pub async fn copy_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+        from: &str,
+        chunk_size: usize,
+    ) -> Result<(), RegistryError> {
+        let (name, digest, from) = (name.to_owned(), digest.clone(), from.to_owned());
+        self.run(move |registry| registry.copy_blob(&name, &digest, &from, chunk_size))
+            .await
+    }
+
+    /// Download a blob from a repository, see [Registry::get_blob].
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::distribution::nonblocking::AsyncRegistry;
+    ///# use opencontainers::Registry;
+    /// use tokio::io::AsyncReadExt;
+    ///
+    ///# async fn download(registry: AsyncRegistry) {
+    /// let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b"
+    ///     .parse()
+    ///     .expect("Could not parse digest");
+    /// let mut data = Vec::new();
+    /// registry.get_blob("library/hello-world", &digest)
+    ///     .await
+    ///     .expect("Could not get blob")
+    ///     .read_to_end(&mut data)
+    ///     .await
+    ///     .expect("Could not read blob");
+    ///# }
+    /// ```
+    pub async fn get_blob(&self, name: &str, digest: &Digest) -> Result<AsyncBlob, RegistryError> {
+        AsyncBlob::download(self.registry.clone(), name.to_owned(), digest.clone(), None).await
+    }
+
+    /// Create an image handle, see [Registry::image].
+    pub async fn image<IS>(&self, image: impl ToImageName) -> Result<AsyncImage, RegistryError>
+    where
+        IS: ImageSelector + 'static,
+    {
+        AsyncImage::new::<IS>(self, image).await
+    }
+}
+
+/// Asynchronous handle to an image, see [Image].
+#[derive(Debug)]
+pub struct AsyncImage {
+    registry: Arc<Registry>,
+    name: String,
+    manifest: ManifestV2,
+    raw_manifest: String,
+}
+
+impl AsyncImage {
+    /// Create a new image given an [ImageReference](image::ImageReference)
+    /// or a repository name and a tag or digest, see [Image::new].
+    pub async fn new<IS>(
+        registry: &AsyncRegistry,
+        image: impl ToImageName,
+    ) -> Result<Self, RegistryError>
+    where
+        IS: ImageSelector + 'static,
+    {
+        image::check_registry(&registry.registry, &image);
+
+        let (name, reference) = image.image_name();
+        let (name, manifest, raw_manifest) = registry
+            .run(move |registry| {
+                Image::new::<IS>(registry, (name.as_str(), reference.as_str()))
+                    .map(Image::into_parts)
+            })
+            .await?;
+
+        Ok(AsyncImage {
+            registry: registry.registry.clone(),
+            name,
+            manifest,
+            raw_manifest,
+        })
+    }
+
+    pub fn manifest(&self) -> &ManifestV2 {
+        &self.manifest
+    }
+
+    /// Return the manifest as sent by the registry, see
+    /// [Image::raw_manifest].
+    pub fn raw_manifest(&self) -> &[u8] {
+        self.raw_manifest.as_bytes()
+    }
+
+    /// Return the name of the repository the image is pulled from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Download a blob from the image's repository.
+    pub async fn get_blob(&self, digest: &Digest) -> Result<AsyncBlob, RegistryError> {
+        AsyncBlob::download(
+            self.registry.clone(),
+            self.name.clone(),
+            digest.clone(),
+            None,
+        )
+        .await
+    }
+
+    /// Return the image runtime configuration
+    pub async fn config(&self) -> Result<spec::ImageV1, RegistryError> {
+        let config = image::config_descriptor(&self.manifest)?;
+        let (digest, size) = (config.digest().clone(), config.size() as u64);
+        let name = self.name.clone();
+        let registry = self.registry.clone();
+
+        spawn_blocking(move || {
+            let mut config = String::new();
+            registry
+                .get_blob(&name, &digest)?
+                .expect_size(size)
+                .read_to_string(&mut config)
+                .map_err(RegistryError::from_io)?;
+
+            config.parse().map_err(RegistryError::ImageSpecError)
+        })
+        .await
+    }
+
+    /// Download a layer.
+    ///
+    /// Unlike [Image::get_layer], the layer is not decompressed, so it can
+    /// be passed to an asynchronous decompressor. Whether it is compressed
+    /// is indicated by the layer's [media
+    /// type](crate::image::manifest::LayerMediaType::is_gzipped).
+    pub async fn get_layer<L>(&self, layer: &L) -> Result<AsyncBlob, RegistryError>
+    where
+        L: Layer + ?Sized,
+    {
+        AsyncBlob::download(
+            self.registry.clone(),
+            self.name.clone(),
+            layer.digest().clone(),
+            layer.size().map(|size| size as u64),
+        )
+        .await
+    }
+}
+
+/// Contents of a blob, streamed while it is downloaded.
+///
+/// The blob is read in chunks on tokio's blocking thread pool, reading the
+/// next chunk while the caller consumes the previous one. A thread is only
+/// used while a chunk is read, not for as long as the blob is streamed.
+///
+/// Interrupted downloads are resumed transparently and the contents are
+/// verified, see [BlobReader].
+#[derive(Debug)]
+pub struct AsyncBlob {
+    size: Option<u64>,
+    state: State,
+    chunk: Bytes,
+}
+
+/// Progress of an [AsyncBlob].
+#[derive(Debug)]
+enum State {
+    /// A chunk is read on the blocking thread pool.
+    Reading(JoinHandle<(BlobReader<'static>, io::Result<Bytes>)>),
+
+    /// The blob was read to the end, or reading it failed.
+    Done,
+}
+
+impl AsyncBlob {
+    /// Start downloading a blob, returning once the registry responded.
+    ///
+    /// If the size of the blob is known, it is checked along with its
+    /// digest, see [BlobReader::expect_size].
+    async fn download(
+        registry: Arc<Registry>,
+        name: String,
+        digest: Digest,
+        size: Option<u64>,
+    ) -> Result<Self, RegistryError> {
+        let reader = spawn_blocking(move || {
+            let reader = registry.get_blob_shared(&name, &digest)?;
+            Ok(match size {
+                Some(size) => reader.expect_size(size),
+                None => reader,
+            })
+        })
+        .await?;
+
+        Ok(AsyncBlob {
+            size: reader.size(),
+            state: State::Reading(read_chunk(reader)),
+            chunk: Bytes::new(),
+        })
+    }
+
+    /// Return the size of the blob, if the registry sent it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Wait for the chunk being read, and start reading the next one.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        let handle = match self.state {
+            State::Reading(ref mut handle) => handle,
+            State::Done => return Poll::Ready(None),
+        };
+
+        let result = ready!(Pin::new(handle).poll(cx));
+        self.state = State::Done;
+
+        Poll::Ready(match result {
+            Ok((_, Ok(chunk))) if chunk.is_empty() => None,
+            Ok((reader, Ok(chunk))) => {
+                self.state = State::Reading(read_chunk(reader));
+                Some(Ok(chunk))
+            }
+            Ok((_, Err(e))) => Some(Err(e)),
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Some(Err(io::Error::from(e))),
+        })
+    }
+}
+
+/// Read the next chunk of a blob on the blocking thread pool.
+fn read_chunk(
+    mut reader: BlobReader<'static>,
+) -> JoinHandle<(BlobReader<'static>, io::Result<Bytes>)> {
+    tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let chunk = loop {
+            match reader.read(&mut buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+
+        let chunk = chunk.map(|n| {
+            buf.truncate(n);
+            Bytes::from(buf)
+        });
+
+        (reader, chunk)
+    })
+}
+
+impl Stream for AsyncBlob {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::take(&mut self.chunk);
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        self.poll_chunk(cx)
+    }
+}
+
+impl AsyncRead for AsyncBlob {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.chunk.is_empty() {
+            match ready!(self.poll_chunk(cx)) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = buf.remaining().min(self.chunk.len());
+        let chunk = self.chunk.split_to(n);
+        buf.put_slice(&chunk);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run a blocking operation on tokio's blocking thread pool.
+async fn spawn_blocking<F, T>(f: F) -> Result<T, RegistryError>
+where
+    F: FnOnce() -> Result<T, RegistryError> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(RegistryError::IoError(io::Error::from(e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::errors::ErrorCode;
+    use crate::distribution::transport::{json_response, response, MemoryTransport};
+
+    use reqwest::{Method, StatusCode};
+    use tokio::io::AsyncReadExt;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn registry(transport: MemoryTransport) -> AsyncRegistry {
+        Registry::builder("https://registry.example.com")
+            .transport(Arc::new(transport))
+            .build()
+            .expect("Could not build registry")
+            .into()
+    }
+
+    fn blob_url(digest: &Digest) -> String {
+        format!("https://registry.example.com/v2/foo/blobs/{}", digest)
+    }
+
+    #[tokio::test]
+    async fn test_tags() {
+        let registry = registry(MemoryTransport::new().route(
+            Method::GET,
+            "https://registry.example.com/v2/foo/tags/list",
+            |_| {
+                json_response(
+                    StatusCode::OK,
+                    r#"{"name": "foo", "tags": ["1.0", "latest"]}"#,
+                )
+            },
+        ));
+
+        let tags = registry.tags("foo").await.expect("Could not list tags");
+        assert_eq!(tags, vec!["1.0".to_owned(), "latest".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_blob() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let body = data.clone();
+        let digest = Digest::sha256(&data);
+        let registry = registry(MemoryTransport::new().route(
+            Method::GET,
+            &blob_url(&digest),
+            move |_| response(StatusCode::OK, body.clone()),
+        ));
+
+        let mut blob = registry
+            .get_blob("foo", &digest)
+            .await
+            .expect("Could not get blob");
+        assert_eq!(blob.size(), Some(data.len() as u64));
+
+        let mut downloaded = Vec::new();
+        blob.read_to_end(&mut downloaded)
+            .await
+            .expect("Could not read blob");
+        assert_eq!(downloaded, data);
+    }
+
+    #[tokio::test]
+    async fn test_blob_stream() {
+        let digest = Digest::sha256(&[1; CHUNK_SIZE + 1]);
+        let registry = registry(MemoryTransport::new().route(
+            Method::GET,
+            &blob_url(&digest),
+            |_| response(StatusCode::OK, vec![1; CHUNK_SIZE + 1]),
+        ));
+
+        let mut blob = registry
+            .get_blob("foo", &digest)
+            .await
+            .expect("Could not get blob");
+
+        let mut sizes = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut blob).poll_next(cx)).await {
+            sizes.push(chunk.expect("Could not read chunk").len());
+        }
+        assert_eq!(sizes.iter().sum::<usize>(), CHUNK_SIZE + 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_blob_error() {
+        let digest = DIGEST.parse().unwrap();
+        let registry =
+            registry(
+                MemoryTransport::new().route(Method::GET, &blob_url(&digest), |_| {
+                    json_response(
+                        StatusCode::NOT_FOUND,
+                        r#"{"errors": [{"code": "BLOB_UNKNOWN", "message": "blob unknown"}]}"#,
+                    )
+                }),
+            );
+
+        let error = registry
+            .get_blob("foo", &digest)
+            .await
+            .expect_err("Blob should not exist");
+        assert!(error.has_code(&ErrorCode::BlobUnknown));
+    }
+}
@@ -0,0 +1,176 @@
+//! Pagination of list endpoints using the `Link` header, as described in the
+//! [Distribution Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#pagination)
+
+use crate::distribution::{Registry, RegistryError};
+
+use hyperx::header::{Header, Link, RelationType};
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+
+use std::marker::PhantomData;
+
+/// A single page of results from a list endpoint.
+pub trait Page: DeserializeOwned {
+    /// Return the entries on this page.
+    fn into_entries(self) -> Vec<String>;
+}
+
+/// Iterator over the entries of a paginated list endpoint.
+///
+/// Pages are only requested once all entries of the previous page have been
+/// consumed, so only one page is held in memory at a time.
+pub struct Paginated<'a, P> {
+    registry: &'a Registry,
+    next: Option<String>,
+    entries: std::vec::IntoIter<String>,
+    page: PhantomData<P>,
+}
+
+impl<'a, P> std::fmt::Debug for Paginated<'a, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Paginated {{ registry: {:?}, next: {:?} }}",
+            self.registry, self.next
+        )
+    }
+}
+
+impl<'a, P> Paginated<'a, P>
+where
+    P: Page,
+{
+    /// Create an iterator starting at `path` relative to the registry.
+    ///
+    /// `n` limits the number of entries per page, `last` starts the listing
+    /// after the given entry.
+    pub(crate) fn new(
+        registry: &'a Registry,
+        path: &str,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<Self, RegistryError> {
+        let mut url = Url::parse(&format!("{}{}", registry.url, path))
+            .map_err(RegistryError::UrlParseError)?;
+
+        if n.is_some() || last.is_some() {
+            let mut query = url.query_pairs_mut();
+
+            if let Some(n) = n {
+                query.append_pair("n", &n.to_string());
+            }
+
+            if let Some(last) = last {
+                query.append_pair("last", last);
+            }
+        }
+
+        Ok(Paginated {
+            registry,
+            next: Some(url.into_string()),
+            entries: Vec::new().into_iter(),
+            page: PhantomData,
+        })
+    }
+
+    /// Fetch the next page, returning its entries.
+    fn fetch(&mut self, url: &str) -> Result<Vec<String>, RegistryError> {
+        let mut response = self.registry.get(url, None)?;
+
+        self.next = match next_link(response.headers()) {
+            Some(link) => Some(
+                Url::parse(url)
+                    .and_then(|base| base.join(&link))
+                    .map_err(RegistryError::UrlParseError)?
+                    .into_string(),
+            ),
+            None => None,
+        };
+
+        let page: P = response.json().map_err(RegistryError::ReqwestError)?;
+
+        Ok(page.into_entries())
+    }
+}
+
+impl<'a, P> Iterator for Paginated<'a, P>
+where
+    P: Page,
+{
+    type Item = Result<String, RegistryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+
+            let url = self.next.take()?;
+
+            match self.fetch(&url) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Return the target of the `rel="next"` link in a response, if any.
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    if !headers.contains_key(reqwest::header::LINK) {
+        return None;
+    }
+
+    let raw: hyperx::header::Raw = headers
+        .get_all(reqwest::header::LINK)
+        .iter()
+        .map(|value| value.as_bytes().to_vec())
+        .collect::<Vec<_>>()
+        .into();
+
+    let link = Link::parse_header(&raw).ok()?;
+
+    link.values()
+        .iter()
+        .find(|value| {
+            value
+                .rel()
+                .into_iter()
+                .flatten()
+                .any(|rel| *rel == RelationType::Next)
+        })
+        .map(|value| value.link().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_link() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(next_link(&headers), None);
+
+        headers.insert(
+            reqwest::header::LINK,
+            "</v2/library/nginx/tags/list?last=1.25&n=100>; rel=\"next\""
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            next_link(&headers),
+            Some("/v2/library/nginx/tags/list?last=1.25&n=100".into())
+        );
+    }
+
+    #[test]
+    fn test_next_link_other_rel() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::LINK,
+            "</v2/_catalog?last=foo>; rel=\"prev\"".parse().unwrap(),
+        );
+        assert_eq!(next_link(&headers), None);
+    }
+}
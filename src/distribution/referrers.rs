@@ -0,0 +1,143 @@
+//! Listing referrers as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-referrers)
+
+use crate::distribution::{Registry, RegistryError};
+use crate::image::manifest::{Descriptor, Digest, ImageIndex, IMAGE_INDEX_MEDIA_TYPE};
+
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT};
+use reqwest::{Method, StatusCode, Url};
+
+impl Registry {
+    /// List the manifests referring to the manifest with the given digest,
+    /// such as signatures, SBOMs and attestations.
+    ///
+    /// If `artifact_type` is given, only referrers of that artifact type are
+    /// returned.
+    ///
+    /// Registries that do not support the referrers API are queried using
+    /// the [referrers tag schema](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#referrers-tag-schema)
+    /// instead.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let digest = "sha256:92c7f9c92844bbbb5d0a101b22f7c2a7949e40f8ea90c8b3bc396879d95e899a"
+    ///     .parse()
+    ///     .unwrap();
+    /// let signatures = registry
+    ///     .referrers("hello-world", &digest, Some("application/vnd.dev.cosign.artifact.sig.v1+json"))
+    ///     .expect("Could not list referrers");
+    /// ```
+    pub fn referrers(
+        &self,
+        name: &str,
+        digest: &Digest,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Descriptor>, RegistryError> {
+        let mut url = Url::parse(&format!("{}/v2/{}/referrers/{}", self.url, name, digest))
+            .map_err(RegistryError::UrlParseError)?;
+
+        if let Some(artifact_type) = artifact_type {
+            url.query_pairs_mut()
+                .append_pair("artifactType", artifact_type);
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(IMAGE_INDEX_MEDIA_TYPE));
+
+        let index: ImageIndex = match self.send(Method::GET, url.as_str(), Some(&headers), None)? {
+            Ok(mut response) => response.json().map_err(RegistryError::ReqwestError)?,
+            Err(ref response) if response.status() == StatusCode::NOT_FOUND => {
+                info!("Referrers API not supported, falling back to tag schema");
+
+                match self.referrers_tag_schema(name, digest)? {
+                    Some(index) => index,
+                    None => return Ok(Vec::new()),
+                }
+            }
+            Err(response) => return Err(RegistryError::from_response(response)),
+        };
+
+        // Registries are not required to apply the filter, so filter again.
+        Ok(filter_artifact_type(index.manifests, artifact_type))
+    }
+
+    /// Fetch the referrers index stored under the tag schema fallback tag, if
+    /// it exists.
+    fn referrers_tag_schema(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<Option<ImageIndex>, RegistryError> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.url,
+            name,
+            referrers_tag(digest)
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(IMAGE_INDEX_MEDIA_TYPE));
+
+        match self.send(Method::GET, &url, Some(&headers), None)? {
+            Ok(mut response) => Ok(Some(response.json().map_err(RegistryError::ReqwestError)?)),
+            Err(ref response) if response.status() == StatusCode::NOT_FOUND => Ok(None),
+            Err(response) => Err(RegistryError::from_response(response)),
+        }
+    }
+}
+
+/// Return the tag under which registries without the referrers API store the
+/// referrers of a manifest.
+pub fn referrers_tag(digest: &Digest) -> String {
+    format!("{}-{}", digest.algorithm, digest.hex)
+}
+
+fn filter_artifact_type(
+    descriptors: Vec<Descriptor>,
+    artifact_type: Option<&str>,
+) -> Vec<Descriptor> {
+    match artifact_type {
+        Some(artifact_type) => descriptors
+            .into_iter()
+            .filter(|d| d.artifact_type.as_deref() == Some(artifact_type))
+            .collect(),
+        None => descriptors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referrers_tag() {
+        let digest: Digest =
+            "sha256:a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1"
+                .parse()
+                .expect("Could not parse digest");
+
+        assert_eq!(
+            referrers_tag(&digest),
+            "sha256-a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1"
+        );
+    }
+
+    #[test]
+    fn test_filter_artifact_type() {
+        let index: ImageIndex =
+            serde_json::from_str(include_str!("../image/test/referrers.test.json"))
+                .expect("Could not deserialize image index");
+
+        let filtered = filter_artifact_type(
+            index.manifests.clone(),
+            Some("application/vnd.example.signature.v1"),
+        );
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0], index.manifests[1]);
+
+        assert_eq!(filter_artifact_type(index.manifests, None).len(), 2);
+    }
+}
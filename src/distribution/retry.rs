@@ -0,0 +1,162 @@
+//! Retrying requests that failed due to transient errors.
+
+use hyperx::header::{Header, RetryAfter};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+
+use std::time::{Duration, SystemTime};
+
+/// Policy for retrying requests after connection errors, timeouts, or
+/// responses indicating that the registry is overloaded (`429 Too Many
+/// Requests` and `503 Service Unavailable`).
+///
+/// Between retries, the policy waits for an exponentially growing delay, or
+/// for as long as the registry asks for in a `Retry-After` header. Either
+/// delay is capped at `max_backoff`.
+///
+/// # Example
+/// ```
+///# extern crate opencontainers;
+///# use opencontainers::Registry;
+/// use opencontainers::distribution::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy {
+///     max_retries: 5,
+///     ..Default::default()
+/// };
+///
+/// let registry = Registry::builder("https://registry.example.com")
+///     .retry_policy(policy)
+///     .build()
+///     .expect("Could not build registry client");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt.
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubled for each further retry.
+    pub initial_backoff: Duration,
+
+    /// Upper bound for the delay between retries, including delays asked
+    /// for by the registry.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Return the delay before the given retry, starting at 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.checked_pow(retry).unwrap_or(u32::MAX);
+
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Return the delay before the given retry of a request the registry
+    /// responded to with the given headers, honoring `Retry-After` up to
+    /// the maximum backoff.
+    pub(crate) fn delay(&self, retry: u32, headers: &HeaderMap) -> Duration {
+        match retry_after(headers) {
+            Some(delay) => delay.min(self.max_backoff),
+            None => self.backoff(retry),
+        }
+    }
+}
+
+/// Check whether a response status indicates that the request should be
+/// retried later.
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// Check whether a request that failed without a response should be
+/// retried.
+///
+/// Only idempotent requests are retried, since the registry may have
+/// processed the request before the connection failed.
+pub(crate) fn is_retryable_error(method: &Method, error: &reqwest::Error) -> bool {
+    let idempotent = matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    );
+
+    idempotent && (error.is_timeout() || error.is_http())
+}
+
+/// Return the delay requested by a `Retry-After` header, if any.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?;
+    let raw: hyperx::header::Raw = value.as_bytes().into();
+
+    match RetryAfter::parse_header(&raw).ok()? {
+        RetryAfter::Delay(delay) => Some(delay),
+        RetryAfter::DateTime(date) => Some(
+            SystemTime::from(date)
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10), Duration::from_secs(30));
+        assert_eq!(policy.backoff(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = RetryPolicy::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(policy.delay(1, &headers), Duration::from_secs(1));
+
+        headers.insert(reqwest::header::RETRY_AFTER, "10".parse().unwrap());
+        assert_eq!(policy.delay(1, &headers), Duration::from_secs(10));
+
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(policy.delay(1, &headers), Duration::from_secs(30));
+
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(policy.delay(1, &headers), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
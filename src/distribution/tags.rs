@@ -0,0 +1,71 @@
+//! Listing tags as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#listing-tags)
+
+use crate::distribution::pagination::{Page, Paginated};
+use crate::distribution::{Registry, RegistryError};
+
+/// Response of the `/v2/<name>/tags/list` endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TagList {
+    /// The name of the repository.
+    pub name: String,
+
+    /// The tags on this page. Some registries return `null` for a
+    /// repository without tags.
+    pub tags: Option<Vec<String>>,
+}
+
+impl Page for TagList {
+    fn into_entries(self) -> Vec<String> {
+        self.tags.unwrap_or_default()
+    }
+}
+
+/// Iterator over the tags of a repository.
+pub type Tags<'a> = Paginated<'a, TagList>;
+
+impl Registry {
+    /// List the tags of a repository.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("https://registry-1.docker.io");
+    /// for tag in registry.tags("library/hello-world").expect("Could not list tags") {
+    ///     println!("{}", tag.expect("Could not get tag"));
+    /// }
+    /// ```
+    pub fn tags(&self, name: &str) -> Result<Tags<'_>, RegistryError> {
+        self.tags_paginated(name, None, None)
+    }
+
+    /// List the tags of a repository, requesting at most `n` tags per page
+    /// and starting after the tag `last`.
+    pub fn tags_paginated(
+        &self,
+        name: &str,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<Tags<'_>, RegistryError> {
+        Paginated::new(self, &format!("/v2/{}/tags/list", name), n, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_list() {
+        let tags: TagList =
+            serde_json::from_str(r#"{"name": "library/hello-world", "tags": ["latest", "linux"]}"#)
+                .expect("Could not deserialize tag list");
+        assert_eq!(tags.name, "library/hello-world");
+        assert_eq!(tags.into_entries(), vec!["latest", "linux"]);
+
+        let tags: TagList = serde_json::from_str(r#"{"name": "empty", "tags": null}"#)
+            .expect("Could not deserialize empty tag list");
+        assert!(tags.into_entries().is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//! Caching of tokens obtained from authorization services, so that a token
+//! is only requested once for all requests sharing its scopes.
+
+use crate::distribution::auth::Token;
+
+use reqwest::{Method, Url};
+use ttl_cache::TtlCache;
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of tokens kept at a time.
+const TOKEN_CACHE_CAPACITY: usize = 32;
+
+/// Tokens are refreshed this long before they expire, so they do not expire
+/// while a request is in flight.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Path components following the repository name in API endpoints.
+const REPOSITORY_ENDPOINTS: &[&str] = &["/manifests/", "/blobs/", "/tags/", "/referrers/"];
+
+/// Identifies the token issued by an authorization service for a set of
+/// scopes.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub(crate) struct TokenKey {
+    pub realm: String,
+    pub service: Option<String>,
+    pub scopes: BTreeSet<String>,
+}
+
+/// Identifies the kind of request a token was used for.
+///
+/// Since a registry may send requests to several hosts, e.g. when using
+/// mirrors, the host and port of the request are part of the key.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct RequestKey {
+    method: Method,
+    host: String,
+    port: Option<u16>,
+    repository: String,
+}
+
+impl RequestKey {
+    fn new(method: &Method, url: &str) -> Option<Self> {
+        let url = Url::parse(url).ok()?;
+        let path = url.path().trim_start_matches("/v2/");
+
+        let repository = match REPOSITORY_ENDPOINTS
+            .iter()
+            .filter_map(|endpoint| path.rfind(endpoint))
+            .max()
+        {
+            Some(end) => &path[..end],
+            None => path,
+        };
+
+        Some(RequestKey {
+            method: method.clone(),
+            host: url.host_str()?.to_owned(),
+            port: url.port_or_known_default(),
+            repository: repository.into(),
+        })
+    }
+}
+
+/// Cache of tokens by realm, service and scopes.
+///
+/// The cache also remembers which token was accepted for requests with a
+/// given method on a given repository of a host, so later requests of the
+/// same kind can be authenticated up front instead of being challenged
+/// first.
+///
+/// Refresh tokens obtained using OAuth2 are kept by realm and service, and
+/// used instead of the login for later token requests.
+pub(crate) struct TokenCache {
+    tokens: Mutex<TtlCache<TokenKey, Token>>,
+    requests: Mutex<HashMap<RequestKey, TokenKey>>,
+    refresh_tokens: Mutex<HashMap<(String, Option<String>), String>>,
+}
+
+impl TokenCache {
+    pub fn new() -> Self {
+        TokenCache {
+            tokens: Mutex::new(TtlCache::new(TOKEN_CACHE_CAPACITY)),
+            requests: Mutex::new(HashMap::new()),
+            refresh_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the cached token for a key, unless it is about to expire.
+    pub fn get(&self, key: &TokenKey) -> Option<Token> {
+        self.tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    /// Cache a token for the rest of its lifetime.
+    pub fn insert(&self, key: TokenKey, token: Token) {
+        let lifetime = match token.lifetime().checked_sub(TOKEN_EXPIRY_MARGIN) {
+            Some(lifetime) if lifetime > Duration::from_secs(0) => lifetime,
+            _ => return,
+        };
+
+        debug!("Caching token for {:?} for {:?}", key, lifetime);
+
+        self.tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .insert(key, token, lifetime);
+    }
+
+    /// Remove a token, e.g. because the registry did not accept it.
+    pub fn remove(&self, key: &TokenKey) {
+        self.tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .remove(key);
+    }
+
+    /// Return the token last accepted for a request like the given one, if
+    /// it is still cached.
+    pub fn lookup(&self, method: &Method, url: &str) -> Option<(TokenKey, Token)> {
+        let request = RequestKey::new(method, url)?;
+
+        let key = self
+            .requests
+            .lock()
+            .expect("Token cache poisoned")
+            .get(&request)
+            .cloned()?;
+
+        self.get(&key).map(|token| (key, token))
+    }
+
+    /// Remember that the token for a key was accepted for a request.
+    pub fn remember(&self, method: &Method, url: &str, key: TokenKey) {
+        if let Some(request) = RequestKey::new(method, url) {
+            self.requests
+                .lock()
+                .expect("Token cache poisoned")
+                .insert(request, key);
+        }
+    }
+
+    /// Return the refresh token obtained from an authorization service.
+    pub fn refresh_token(&self, realm: &str, service: Option<&String>) -> Option<String> {
+        self.refresh_tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .get(&(realm.to_owned(), service.cloned()))
+            .cloned()
+    }
+
+    /// Keep a refresh token obtained from an authorization service.
+    pub fn set_refresh_token(&self, realm: &str, service: Option<&String>, refresh_token: &str) {
+        self.refresh_tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .insert(
+                (realm.to_owned(), service.cloned()),
+                refresh_token.to_owned(),
+            );
+    }
+
+    /// Forget a refresh token, e.g. because it has been revoked.
+    pub fn remove_refresh_token(&self, realm: &str, service: Option<&String>) {
+        self.refresh_tokens
+            .lock()
+            .expect("Token cache poisoned")
+            .remove(&(realm.to_owned(), service.cloned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scope: &str) -> TokenKey {
+        TokenKey {
+            realm: "https://auth.docker.io/token".into(),
+            service: Some("registry.docker.io".into()),
+            scopes: vec![scope.to_owned()].into_iter().collect(),
+        }
+    }
+
+    fn token(expires_in: Option<u64>) -> Token {
+        Token {
+            token: "secret".into(),
+            expires_in,
+            issued_at: None,
+            refresh_token: None,
+        }
+    }
+
+    #[test]
+    fn test_request_key() {
+        let repository = |url: &str| RequestKey::new(&Method::GET, url).unwrap().repository;
+
+        assert_eq!(
+            repository("https://registry-1.docker.io/v2/library/nginx/manifests/latest"),
+            "library/nginx"
+        );
+        assert_eq!(
+            repository("https://localhost:5000/v2/foo/tags/bar/blobs/uploads/1234"),
+            "foo/tags/bar"
+        );
+        assert_eq!(
+            repository("https://localhost:5000/v2/_catalog?n=10"),
+            "_catalog"
+        );
+    }
+
+    #[test]
+    fn test_token_cache() {
+        let cache = TokenCache::new();
+        let manifest = "https://registry-1.docker.io/v2/library/nginx/manifests/latest";
+        let blob = "https://registry-1.docker.io/v2/library/nginx/blobs/sha256:1234";
+
+        assert_eq!(cache.lookup(&Method::GET, manifest), None);
+
+        let pull = key("repository:library/nginx:pull");
+        cache.insert(pull.clone(), token(Some(300)));
+        cache.remember(&Method::GET, manifest, pull.clone());
+
+        assert_eq!(
+            cache.lookup(&Method::GET, blob),
+            Some((pull.clone(), token(Some(300))))
+        );
+        assert_eq!(cache.lookup(&Method::PUT, blob), None);
+
+        let mirror = "https://mirror.example.com/v2/library/nginx/blobs/sha256:1234";
+        assert_eq!(cache.lookup(&Method::GET, mirror), None);
+
+        cache.remove(&pull);
+        assert_eq!(cache.lookup(&Method::GET, blob), None);
+    }
+
+    #[test]
+    fn test_token_cache_expiring() {
+        let cache = TokenCache::new();
+        let pull = key("repository:library/nginx:pull");
+
+        cache.insert(pull.clone(), token(Some(5)));
+        assert_eq!(cache.get(&pull), None);
+
+        // Tokens without expires_in are valid for 60 seconds.
+        cache.insert(pull.clone(), token(None));
+        assert_eq!(cache.get(&pull), Some(token(None)));
+    }
+}
@@ -0,0 +1,317 @@
+//! The HTTP transport requests to registries and authorization services are
+//! sent through.
+//!
+//! By default, requests are sent using [reqwest](https://docs.rs/reqwest).
+//! [MemoryTransport] answers requests from handlers instead, to test code
+//! using a [Registry](crate::Registry) without a network.
+
+use crate::distribution::RegistryError;
+
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, StatusCode, Url};
+
+use std::sync::Mutex;
+
+/// An HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    /// Create a request without headers and body.
+    pub fn new(method: Method, url: Url) -> Self {
+        HttpRequest {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+}
+
+/// Sends HTTP requests.
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    /// Send a request, returning the response regardless of its status.
+    ///
+    /// Errors are only returned if no response was received.
+    fn send(&self, request: HttpRequest) -> Result<reqwest::Response, RegistryError>;
+}
+
+/// Transport using a [reqwest::Client].
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> Result<reqwest::Response, RegistryError> {
+        let mut builder = self
+            .client
+            .request(request.method, request.url)
+            .headers(request.headers);
+
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+
+        builder.send().map_err(RegistryError::ReqwestError)
+    }
+}
+
+/// Handler answering requests sent through a [MemoryTransport].
+type Handler = Box<dyn Fn(&HttpRequest) -> http::Response<Vec<u8>> + Send + Sync>;
+
+/// Transport answering requests from in-memory handlers.
+///
+/// Requests are answered by the first handler registered for their method
+/// and URL, ignoring the query. Requests without a handler are answered with
+/// `404 Not Found`. All requests are recorded, so tests can check what was
+/// sent.
+///
+/// # Example
+/// ```
+///# extern crate opencontainers;
+/// use opencontainers::distribution::transport::{json_response, MemoryTransport};
+/// use opencontainers::Registry;
+/// use reqwest::{Method, StatusCode};
+/// use std::sync::Arc;
+///
+/// let transport = Arc::new(MemoryTransport::new().route(
+///     Method::GET,
+///     "https://registry.example.com/v2/foo/tags/list",
+///     |_| json_response(StatusCode::OK, r#"{"name": "foo", "tags": ["latest"]}"#),
+/// ));
+///
+/// let registry = Registry::builder("https://registry.example.com")
+///     .transport(transport.clone())
+///     .build()
+///     .expect("Could not build registry");
+///
+/// let tags: Vec<String> = registry
+///     .tags("foo")
+///     .expect("Could not list tags")
+///     .collect::<Result<_, _>>()
+///     .expect("Could not list tags");
+/// assert_eq!(tags, vec!["latest".to_owned()]);
+/// assert_eq!(transport.requests().len(), 1);
+/// ```
+#[derive(Default)]
+pub struct MemoryTransport {
+    routes: Vec<(Method, String, Handler)>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl std::fmt::Debug for MemoryTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|(method, url, _)| format!("{} {}", method, url))
+            .collect();
+
+        write!(f, "MemoryTransport {{ routes: {:?} }}", routes)
+    }
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer requests with the given method and URL using a handler.
+    pub fn route<F>(mut self, method: Method, url: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> http::Response<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.routes.push((method, url.into(), Box::new(handler)));
+        self
+    }
+
+    /// Return the requests sent so far.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().expect("Request log poisoned").clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, request: HttpRequest) -> Result<reqwest::Response, RegistryError> {
+        let mut url = request.url.clone();
+        url.set_query(None);
+
+        let response = self
+            .routes
+            .iter()
+            .find(|(method, route, _)| *method == request.method && route == url.as_str())
+            .map(|(_, _, handler)| handler(&request))
+            .unwrap_or_else(|| response(StatusCode::NOT_FOUND, Vec::new()));
+
+        self.requests
+            .lock()
+            .expect("Request log poisoned")
+            .push(request);
+
+        Ok(response.into())
+    }
+}
+
+/// Create a response with a body and a matching `Content-Length`.
+pub fn response<B: Into<Vec<u8>>>(status: StatusCode, body: B) -> http::Response<Vec<u8>> {
+    let body = body.into();
+    let length = body.len();
+
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(reqwest::header::CONTENT_LENGTH, length.into());
+    response
+}
+
+/// Create a response with a JSON body.
+pub fn json_response<B: Into<Vec<u8>>>(status: StatusCode, body: B) -> http::Response<Vec<u8>> {
+    let mut response = response(status, body);
+    response.headers_mut().insert(
+        reqwest::header::CONTENT_TYPE,
+        "application/json".parse().expect("Content-Type is valid"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::errors::ErrorCode;
+    use crate::distribution::Registry;
+    use crate::image::manifest::MANIFEST_V2_MEDIA_TYPES;
+
+    use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+
+    use std::sync::Arc;
+
+    const REGISTRY: &str = "https://registry.example.com";
+    const MANIFEST: &str = "https://registry.example.com/v2/foo/manifests/latest";
+
+    fn registry(transport: &Arc<MemoryTransport>) -> Registry {
+        Registry::builder(REGISTRY)
+            .transport(transport.clone())
+            .build()
+            .expect("Could not build registry")
+    }
+
+    fn manifest(request: &HttpRequest) -> http::Response<Vec<u8>> {
+        match request.headers.get(AUTHORIZATION) {
+            Some(value) if value == "Bearer secret" => {
+                let mut response = response(StatusCode::OK, "{}");
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    "application/vnd.oci.image.manifest.v1+json"
+                        .parse()
+                        .unwrap(),
+                );
+                response
+            }
+            _ => {
+                let mut response = response(StatusCode::UNAUTHORIZED, Vec::new());
+                response.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    r#"Bearer realm="https://auth.example.com/token",service="registry.example.com",scope="repository:foo:pull""#
+                        .parse()
+                        .unwrap(),
+                );
+                response
+            }
+        }
+    }
+
+    #[test]
+    fn test_bearer_token_flow() {
+        let transport = Arc::new(
+            MemoryTransport::new()
+                .route(Method::GET, MANIFEST, manifest)
+                .route(Method::GET, "https://auth.example.com/token", |_| {
+                    json_response(StatusCode::OK, r#"{"token": "secret"}"#)
+                }),
+        );
+        let registry = registry(&transport);
+
+        for _ in 0..2 {
+            let (media_type, data) = registry
+                .get_manifest_raw("foo", "latest")
+                .expect("Could not get manifest");
+            assert_eq!(media_type, "application/vnd.oci.image.manifest.v1+json");
+            assert_eq!(data, b"{}");
+        }
+
+        let requests = transport.requests();
+        let urls: Vec<_> = requests.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                MANIFEST,
+                "https://auth.example.com/token?scope=repository%3Afoo%3Apull&service=registry.example.com",
+                MANIFEST,
+                MANIFEST,
+            ]
+        );
+
+        // The token is reused for the second request
+        assert_eq!(requests[3].headers[AUTHORIZATION], "Bearer secret");
+    }
+
+    #[test]
+    fn test_manifest_accept() {
+        let transport = Arc::new(MemoryTransport::new());
+        let registry = registry(&transport);
+
+        registry
+            .get_manifest_raw("foo", "latest")
+            .expect_err("Manifest should not exist");
+
+        let requests = transport.requests();
+        let accept = requests[0].headers[ACCEPT].to_str().unwrap();
+        for media_type in MANIFEST_V2_MEDIA_TYPES.iter() {
+            assert!(accept.contains(media_type), "{} not accepted", media_type);
+        }
+    }
+
+    #[test]
+    fn test_manifest_digest() {
+        let digest = crate::image::manifest::Digest::sha256(b"{}");
+        let url = format!("{}/v2/foo/manifests/{}", REGISTRY, digest);
+        let transport = Arc::new(MemoryTransport::new().route(Method::GET, &url, |_| {
+            json_response(StatusCode::OK, r#"{"schemaVersion": 2}"#)
+        }));
+        let registry = registry(&transport);
+
+        match registry.get_manifest_raw("foo", &digest.to_string()) {
+            Err(RegistryError::DigestMismatch(expected, _)) => assert_eq!(expected, digest),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_response() {
+        let transport = Arc::new(MemoryTransport::new().route(Method::GET, MANIFEST, |_| {
+            json_response(
+                StatusCode::NOT_FOUND,
+                r#"{"errors": [{"code": "MANIFEST_UNKNOWN", "message": "manifest unknown"}]}"#,
+            )
+        }));
+        let registry = registry(&transport);
+
+        let error = registry
+            .get_manifest_raw("foo", "latest")
+            .expect_err("Manifest should not exist");
+        assert!(error.has_code(&ErrorCode::ManifestUnknown));
+    }
+}
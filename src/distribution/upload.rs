@@ -0,0 +1,374 @@
+//! Blob uploads as described in the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/master/spec.md#pushing-blobs)
+
+use crate::distribution::{Registry, RegistryError};
+use crate::image::manifest::Digest;
+
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION,
+};
+use reqwest::{Method, StatusCode, Url};
+
+use std::io::Read;
+
+/// Default size of the chunks sent by [Registry::push_blob_chunked].
+pub const DEFAULT_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+
+/// An upload session for a single blob.
+///
+/// An upload session is started with [Registry::start_upload]. Data can then
+/// either be sent in chunks with [BlobUpload::upload_chunk], or all at once
+/// when completing the upload with [BlobUpload::finish].
+#[derive(Debug)]
+pub struct BlobUpload<'a> {
+    registry: &'a Registry,
+    location: Url,
+    offset: u64,
+}
+
+/// Result of an attempt to mount a blob from another repository.
+#[derive(Debug)]
+pub enum BlobMount<'a> {
+    /// The blob was mounted and is available at the given location.
+    Mounted(Url),
+
+    /// The blob could not be mounted, and the registry started an upload
+    /// session instead.
+    Upload(BlobUpload<'a>),
+}
+
+impl<'a> BlobUpload<'a> {
+    /// Return the URL of the upload session.
+    ///
+    /// The registry may change this location after each chunk.
+    pub fn location(&self) -> &Url {
+        &self.location
+    }
+
+    /// Return the number of bytes uploaded so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Upload a chunk of the blob.
+    ///
+    /// Chunks have to be uploaded in order.
+    pub fn upload_chunk(&mut self, data: &[u8]) -> Result<(), RegistryError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        let range = content_range(self.offset, data.len());
+        headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&range).map_err(|_| RegistryError::InvalidHeader(range))?,
+        );
+
+        let response = self.registry.request(
+            Method::PATCH,
+            self.location.as_str(),
+            Some(&headers),
+            Some(data),
+        )?;
+
+        self.location = self.registry.location(&response)?;
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Upload the contents of a reader in chunks of `chunk_size` bytes and
+    /// complete the upload.
+    pub fn upload_from<R>(
+        mut self,
+        digest: &Digest,
+        mut reader: R,
+        chunk_size: usize,
+    ) -> Result<Url, RegistryError>
+    where
+        R: Read,
+    {
+        let mut buffer = Vec::with_capacity(chunk_size);
+
+        loop {
+            buffer.clear();
+            (&mut reader)
+                .take(chunk_size as u64)
+                .read_to_end(&mut buffer)
+                .map_err(RegistryError::IoError)?;
+
+            if buffer.is_empty() {
+                break;
+            }
+
+            self.upload_chunk(&buffer)?;
+        }
+
+        self.finish(digest, None)
+    }
+
+    /// Complete the upload.
+    ///
+    /// If `data` is given, it is sent along with the closing request, so an
+    /// entire blob can be uploaded at once by calling this on a fresh upload
+    /// session.
+    pub fn finish(self, digest: &Digest, data: Option<&[u8]>) -> Result<Url, RegistryError> {
+        let url = with_digest(&self.location, digest);
+        let data = data.unwrap_or(&[]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        headers.insert(CONTENT_LENGTH, data.len().into());
+
+        let response =
+            self.registry
+                .request(Method::PUT, url.as_str(), Some(&headers), Some(data))?;
+
+        self.registry.location(&response)
+    }
+}
+
+impl Registry {
+    /// Resolve the `Location` header of a response relative to the registry.
+    fn location(&self, response: &reqwest::Response) -> Result<Url, RegistryError> {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| RegistryError::MissingHeader(LOCATION.to_string()))?;
+
+        Url::parse(&self.url)
+            .and_then(|base| base.join(location))
+            .map_err(RegistryError::UrlParseError)
+    }
+
+    /// Check whether a blob exists in a repository.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("https://registry-1.docker.io");
+    /// let digest = "sha256:fce289e99eb9bca977dae136fbe2a82b6b7d4c372474c9235adc1741675f587e"
+    ///     .parse()
+    ///     .unwrap();
+    /// let exists = registry.blob_exists("library/hello-world", &digest)
+    ///     .expect("Could not check for blob");
+    /// ```
+    pub fn blob_exists(&self, name: &str, digest: &Digest) -> Result<bool, RegistryError> {
+        let url = format!("{}/v2/{}/blobs/{}", self.url, name, digest);
+
+        match self.send(Method::HEAD, &url, None, None)? {
+            Ok(_) => Ok(true),
+            Err(ref response) if response.status() == StatusCode::NOT_FOUND => Ok(false),
+            Err(response) => Err(RegistryError::from_response(response)),
+        }
+    }
+
+    /// Start a new blob upload session in a repository.
+    pub fn start_upload(&self, name: &str) -> Result<BlobUpload<'_>, RegistryError> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.url, name);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, 0.into());
+
+        let response = self.request(Method::POST, &url, Some(&headers), None)?;
+
+        Ok(BlobUpload {
+            registry: self,
+            location: self.location(&response)?,
+            offset: 0,
+        })
+    }
+
+    /// Mount a blob from another repository on the same registry.
+    ///
+    /// If the registry cannot mount the blob, e.g. because it does not exist
+    /// in the source repository or the client may not pull from it, the
+    /// registry starts a regular upload session instead, which is returned
+    /// as [BlobMount::Upload].
+    ///
+    /// # Authentication
+    /// In addition to the scopes the registry asks for, a token for this
+    /// request also requests `pull` access on the source repository.
+    pub fn mount_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+        from: &str,
+    ) -> Result<BlobMount<'_>, RegistryError> {
+        let mut url = Url::parse(&format!("{}/v2/{}/blobs/uploads/", self.url, name))
+            .map_err(RegistryError::UrlParseError)?;
+        url.query_pairs_mut()
+            .append_pair("mount", &digest.to_string())
+            .append_pair("from", from);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, 0.into());
+
+        let scopes = [format!("repository:{}:pull", from)];
+        let response = self
+            .send_scoped(Method::POST, url.as_str(), Some(&headers), None, &scopes)?
+            .map_err(RegistryError::from_response)?;
+
+        let location = self.location(&response)?;
+
+        if response.status() == StatusCode::CREATED {
+            return Ok(BlobMount::Mounted(location));
+        }
+
+        info!(
+            "Could not mount {} from {}, falling back to upload",
+            digest, from
+        );
+        Ok(BlobMount::Upload(BlobUpload {
+            registry: self,
+            location,
+            offset: 0,
+        }))
+    }
+
+    /// Copy a blob from another repository on the same registry, unless it
+    /// already exists.
+    ///
+    /// The blob is mounted if possible. Otherwise, it is pulled from the
+    /// source repository and uploaded in chunks of `chunk_size` bytes.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# use opencontainers::distribution::DEFAULT_CHUNK_SIZE;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let digest = "sha256:1b930d010525941c1d56ec53b97bd057a67ae1865eebf042686d2a2d18271ced"
+    ///     .parse()
+    ///     .unwrap();
+    /// registry.copy_blob("hello-world", &digest, "library/hello-world", DEFAULT_CHUNK_SIZE)
+    ///     .expect("Could not copy blob");
+    /// ```
+    pub fn copy_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+        from: &str,
+        chunk_size: usize,
+    ) -> Result<(), RegistryError> {
+        if self.blob_exists(name, digest)? {
+            info!("Blob {} already exists in {}", digest, name);
+            return Ok(());
+        }
+
+        let upload = match self.mount_blob(name, digest, from)? {
+            BlobMount::Mounted(_) => return Ok(()),
+            BlobMount::Upload(upload) => upload,
+        };
+
+        let url = format!("{}/v2/{}/blobs/{}", self.url, from, digest);
+        let response = self.get(&url, None)?;
+
+        upload.upload_from(digest, response, chunk_size)?;
+
+        Ok(())
+    }
+
+    /// Upload a blob in a single request, unless it already exists.
+    ///
+    /// # Example
+    /// ```no_run
+    ///# extern crate opencontainers;
+    ///# use opencontainers::Registry;
+    ///# let registry = Registry::new("http://localhost:5000");
+    /// let data = b"hello world";
+    /// let digest = "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+    ///     .parse()
+    ///     .unwrap();
+    /// registry.push_blob("hello-world", &digest, data)
+    ///     .expect("Could not push blob");
+    /// ```
+    pub fn push_blob(&self, name: &str, digest: &Digest, data: &[u8]) -> Result<(), RegistryError> {
+        if self.blob_exists(name, digest)? {
+            info!("Blob {} already exists in {}", digest, name);
+            return Ok(());
+        }
+
+        self.start_upload(name)?.finish(digest, Some(data))?;
+
+        Ok(())
+    }
+
+    /// Upload a blob in chunks of `chunk_size` bytes, unless it already
+    /// exists.
+    ///
+    /// Consider using [DEFAULT_CHUNK_SIZE] as a chunk size.
+    pub fn push_blob_chunked<R>(
+        &self,
+        name: &str,
+        digest: &Digest,
+        reader: R,
+        chunk_size: usize,
+    ) -> Result<(), RegistryError>
+    where
+        R: Read,
+    {
+        if self.blob_exists(name, digest)? {
+            info!("Blob {} already exists in {}", digest, name);
+            return Ok(());
+        }
+
+        self.start_upload(name)?
+            .upload_from(digest, reader, chunk_size)?;
+
+        Ok(())
+    }
+}
+
+/// Format a `Content-Range` header value for a chunk starting at `offset`.
+fn content_range(offset: u64, len: usize) -> String {
+    format!("{}-{}", offset, offset + len as u64 - 1)
+}
+
+/// Append the `digest` query parameter to an upload location, keeping any
+/// parameters the registry put there.
+fn with_digest(location: &Url, digest: &Digest) -> Url {
+    let mut url = location.clone();
+    url.query_pairs_mut()
+        .append_pair("digest", &digest.to_string());
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range(0, 1), "0-0");
+        assert_eq!(content_range(0, 1024), "0-1023");
+        assert_eq!(content_range(1024, 1024), "1024-2047");
+    }
+
+    #[test]
+    fn test_with_digest() {
+        let digest: Digest =
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+                .parse()
+                .expect("Could not parse digest");
+
+        let location: Url = "https://registry.example.com/v2/foo/blobs/uploads/1234?_state=abc"
+            .parse()
+            .expect("Could not parse URL");
+
+        assert_eq!(
+            with_digest(&location, &digest).as_str(),
+            "https://registry.example.com/v2/foo/blobs/uploads/1234?_state=abc&digest=sha256%3Ab94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use pest::Parser;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::str::FromStr;
 
@@ -64,6 +65,11 @@ pub trait Layer {
 
     /// Return the media type of the layer, if available
     fn media_type(&self) -> Option<&LayerMediaType>;
+
+    /// Return the size of the layer in bytes, if available
+    fn size(&self) -> Option<usize> {
+        None
+    }
 }
 
 impl Layer for Box<dyn Layer> {
@@ -74,6 +80,10 @@ impl Layer for Box<dyn Layer> {
     fn media_type(&self) -> Option<&LayerMediaType> {
         self.deref().media_type()
     }
+
+    fn size(&self) -> Option<usize> {
+        self.deref().size()
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
@@ -184,8 +194,22 @@ impl Serialize for LayerMediaType {
         serializer.collect_str(self)
     }
 }
+
+/// Media types of schema 2 manifests and manifest lists, in order of
+/// preference.
+///
+/// If a client does not send these in the `Accept` header, registries will
+/// fall back to schema 1.
+pub const MANIFEST_V2_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.distribution.manifest.list.v2+json",
+    "application/vnd.oci.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
 /// Enum of Manifest structs for each schema version.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
 pub enum ManifestV2 {
     Schema1(ManifestV2_1),
     Schema2(ManifestV2_2),
@@ -200,6 +224,17 @@ impl ManifestV2 {
             ManifestV2::Schema2List(_) => unimplemented!(),
         })
     }
+
+    /// Return the media type of the manifest, if it carries one.
+    ///
+    /// Schema 1 manifests do not have a media type.
+    pub fn media_type(&self) -> Option<&str> {
+        match self {
+            ManifestV2::Schema1(_) => None,
+            ManifestV2::Schema2(s2) => Some(&s2.media_type),
+            ManifestV2::Schema2List(list) => Some(&list.media_type),
+        }
+    }
 }
 
 impl FromStr for ManifestV2 {
@@ -301,6 +336,36 @@ pub struct Digest {
     pub hex: String,
 }
 
+impl Digest {
+    /// Compute the SHA-256 digest of some content.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///# use opencontainers::image::manifest::Digest;
+    /// let digest = Digest::sha256(b"hello world");
+    /// assert_eq!(
+    ///     &digest.to_string(),
+    ///     "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+    /// );
+    /// ```
+    pub fn sha256(data: &[u8]) -> Self {
+        use sha2::Digest as _;
+
+        Self {
+            algorithm: DigestAlgorithm::Sha256,
+            hex: format!("{:x}", sha2::Sha256::digest(data)),
+        }
+    }
+
+    /// Check whether some content matches this digest.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        match self.algorithm {
+            DigestAlgorithm::Sha256 => Self::sha256(data) == *self,
+        }
+    }
+}
+
 impl std::fmt::Display for Digest {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}:{}", self.algorithm, self.hex)
@@ -447,6 +512,10 @@ impl ConfigV2_2 {
     pub fn digest(&self) -> &Digest {
         &self.digest
     }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -475,6 +544,7 @@ pub struct LayerV2_2 {
     ///
     /// Content should be verified against the digest and size. This field is
     /// optional and uncommon.
+    #[serde(skip_serializing_if = "Option::is_none")]
     urls: Option<Vec<String>>,
 }
 
@@ -486,6 +556,10 @@ impl Layer for LayerV2_2 {
     fn media_type(&self) -> Option<&LayerMediaType> {
         Some(&self.media_type)
     }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.size)
+    }
 }
 
 /// Image Manifest Version 2, Schema 2
@@ -651,14 +725,26 @@ impl ManifestListV2_2 {
     where
         T: ImageSelector,
     {
-        let digest = self
-            .get_current_platform_manifest_digest::<T>()
+        self.fetch_current_platform_manifest::<T>(image)
+            .map(|(manifest, _)| manifest)
+    }
+
+    /// Get a platform manifest for the current platform from a manifest
+    /// list, along with the manifest as sent by the registry.
+    pub(crate) fn fetch_current_platform_manifest<T>(
+        &self,
+        image: &Image,
+    ) -> Result<(ManifestV2_2, String), RegistryError>
+    where
+        T: ImageSelector,
+    {
+        let entry = T::select_manifest(self)
             .ok_or(ManifestError::NoMatchingPlatformFound)
             .map_err(RegistryError::ManifestError)?;
 
         let url = format!(
             "{}/v2/{}/manifests/{}",
-            image.registry.url, image.name, digest
+            image.registry.url, image.name, entry.digest
         );
 
         let blob = image
@@ -667,12 +753,81 @@ impl ManifestListV2_2 {
             .text()
             .map_err(RegistryError::ReqwestError)?;
 
-        serde_json::from_str(&blob)
+        if blob.len() != entry.size {
+            return Err(RegistryError::SizeMismatch(
+                entry.size as u64,
+                blob.len() as u64,
+            ));
+        }
+
+        if !entry.digest.verify(blob.as_bytes()) {
+            return Err(RegistryError::DigestMismatch(
+                entry.digest.clone(),
+                Digest::sha256(blob.as_bytes()),
+            ));
+        }
+
+        let manifest = serde_json::from_str(&blob)
             .map_err(ManifestError::JsonError)
-            .map_err(RegistryError::ManifestError)
+            .map_err(RegistryError::ManifestError)?;
+
+        Ok((manifest, blob))
     }
 }
 
+/// Content descriptor, as defined in the [OCI Image Format
+/// Specification](https://github.com/opencontainers/image-spec/blob/master/descriptor.md)
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    /// The media type of the referenced content.
+    pub media_type: String,
+
+    /// The digest of the targeted content.
+    pub digest: Digest,
+
+    /// The size, in bytes, of the raw content.
+    pub size: usize,
+
+    /// The type of an artifact when the descriptor points to an artifact,
+    /// e.g. a signature or an SBOM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+
+    /// Arbitrary metadata for this descriptor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// Media type of an OCI image index.
+pub const IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Image Index, as defined in the [OCI Image Format
+/// Specification](https://github.com/opencontainers/image-spec/blob/master/image-index.md)
+///
+/// This is the OCI counterpart to the [ManifestListV2_2], and is also used
+/// by registries to list the referrers of a manifest.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    /// This field specifies the image manifest schema version as an integer.
+    ///
+    /// This schema uses version 2.
+    pub schema_version: u64,
+
+    /// The media type of the index. This should be set to
+    /// `application/vnd.oci.image.index.v1+json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    /// The manifests referenced by this index.
+    pub manifests: Vec<Descriptor>,
+
+    /// Arbitrary metadata for the image index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -755,6 +910,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_manifest_v2_serialize() {
+        let test_data = include_str!("test/manifest-v2-2.test.json");
+        let manifest: ManifestV2 = test_data.parse().expect("Could not parse manifest");
+
+        let data = serde_json::to_string(&manifest).expect("Could not serialize manifest");
+        let serialized: serde_json::Value = serde_json::from_str(&data).unwrap();
+        for layer in serialized["layers"].as_array().unwrap() {
+            assert!(layer.get("urls").is_none());
+        }
+        let reparsed: ManifestV2 = data.parse().expect("Could not parse manifest");
+        assert_eq!(serde_json::to_string(&reparsed).unwrap(), data);
+    }
+
     #[test]
     fn test_manifest_list_v2() {
         let test_data = include_str!("test/manifest-list-v2-2.test.json");
@@ -770,6 +939,27 @@ mod tests {
         assert_eq!(manifest_list.manifests.len(), 2);
     }
 
+    #[test]
+    fn test_image_index_referrers() {
+        let test_data = include_str!("test/referrers.test.json");
+
+        let index: ImageIndex =
+            serde_json::from_str(test_data).expect("Could not deserialize image index");
+
+        assert_eq!(index.schema_version, 2);
+        assert_eq!(index.media_type.as_deref(), Some(IMAGE_INDEX_MEDIA_TYPE));
+        assert_eq!(index.manifests.len(), 2);
+        assert_eq!(
+            index.manifests[0].artifact_type.as_deref(),
+            Some("application/vnd.example.sbom.v1")
+        );
+        assert_eq!(index.manifests[1].size, 1234);
+        assert_eq!(
+            index.manifests[1].annotations.as_ref().unwrap()["org.opencontainers.image.created"],
+            "2023-01-01T00:00:00Z"
+        );
+    }
+
     #[test]
     fn test_manifest_schemaonly_schema1() {
         let test_data = include_str!("test/manifest-v2-1.test.json");
@@ -887,6 +1077,19 @@ mod tests {
         assert_eq!(&digest.to_string(), test_data)
     }
 
+    #[test]
+    fn test_digest_sha256() {
+        let digest = Digest::sha256(b"");
+
+        assert_eq!(digest.algorithm, DigestAlgorithm::Sha256);
+        assert_eq!(
+            digest.hex,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(digest.verify(b""));
+        assert!(!digest.verify(b"foo"));
+    }
+
     #[test]
     fn test_parse_digest_fail() {
         "foobar"
@@ -1,16 +1,33 @@
-use crate::distribution::{Registry, RegistryError};
+use crate::distribution::{BlobReader, Registry, RegistryError};
 mod go;
 
 pub mod manifest;
+pub mod reference;
 pub mod spec;
 use manifest::Digest;
 pub use manifest::ManifestV2;
+pub use reference::{ImageReference, ToImageName};
+
+use std::io::Read;
 
 #[derive(Debug)]
 pub struct Image<'a> {
     registry: &'a Registry,
     name: String,
     manifest: ManifestV2,
+    raw_manifest: String,
+}
+
+/// Warn if an image is pulled from a registry other than the one it refers
+/// to.
+pub(crate) fn check_registry(registry: &Registry, image: &impl ToImageName) {
+    match image.image_registry_url() {
+        Some(url) if url != registry.url => warn!(
+            "Pulling from registry at {}, which does not match {}",
+            registry.url, url
+        ),
+        _ => {}
+    }
 }
 
 /// Trait to determine which image to select from a Manifest.
@@ -51,6 +68,10 @@ impl<'a> Image<'a> {
     ///
     /// Consider using [Registry::image] instead.
     ///
+    /// The image is given either as an [ImageReference], whose domain is
+    /// expected to refer to the given registry, or as a pair of a repository
+    /// name and a tag or digest, see [ToImageName].
+    ///
     /// The type parameter has a trait bound on [ImageSelector], which can
     /// be implemented to select which image to use when pulling from a
     /// fat manifest.
@@ -61,53 +82,61 @@ impl<'a> Image<'a> {
     ///# extern crate opencontainers;
     ///# use opencontainers::Registry;
     ///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
-    ///# let registry = Registry::new("https://registry-1.docker.io");
-    /// let image = opencontainers::Image::new::<ImagePlatformSelector>(&registry, "library/hello-world", "latest")
+    ///# use opencontainers::distribution::cassette::CassetteTransport;
+    ///# let transport = CassetteTransport::replay_file(concat!(
+    ///#     env!("CARGO_MANIFEST_DIR"),
+    ///#     "/src/image/test/synthetic-hello-world.cassette.json"
+    ///# )).unwrap();
+    ///# let registry = Registry::builder("https://registry.example.com")
+    ///#     .transport(std::sync::Arc::new(transport))
+    ///#     .build()
+    ///#     .unwrap();
+    /// let image = opencontainers::Image::new::<ImagePlatformSelector>(&registry, ("library/hello-world", "latest"))
+    ///     .expect("Could not get image");
+    ///
+    /// let reference = "registry.example.com/library/hello-world"
+    ///     .parse::<opencontainers::image::ImageReference>()
+    ///     .expect("Could not parse reference");
+    /// let image = opencontainers::Image::new::<ImagePlatformSelector>(&registry, &reference)
     ///     .expect("Could not get image");
     /// ```
-    pub fn new<IS>(
-        registry: &'a Registry,
-        name: &str,
-        reference: &str,
-    ) -> Result<Self, RegistryError>
+    pub fn new<IS>(registry: &'a Registry, image: impl ToImageName) -> Result<Self, RegistryError>
     where
         IS: ImageSelector,
     {
-        let name = name.to_owned();
+        check_registry(registry, &image);
+
+        let (name, reference) = image.image_name();
+        let reference = reference.as_str();
 
         let url = format!("{}/v2/{}/manifests/{}", registry.url, name, reference);
 
         // Make sure we only accept schema 2, if we don't set this, we will get
         // schema1 by default.
-        // For now, do not support Manifest Lists.
-        let accept_types = vec![
-            "application/vnd.oci.distribution.manifest.list.v2+json",
-            "application/vnd.oci.distribution.manifest.v2+json",
-            "application/vnd.docker.distribution.manifest.list.v2+json",
-            "application/vnd.docker.distribution.manifest.v2+json",
-        ];
-
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(
             reqwest::header::ACCEPT,
-            accept_types.join(",").parse().unwrap(),
+            manifest::MANIFEST_V2_MEDIA_TYPES.join(",").parse().unwrap(),
         );
 
         let manifest = registry
             .get(&url, Some(&headers))?
             .text()
-            .map_err(RegistryError::ReqwestError)?
-            .parse()
-            .map_err(RegistryError::ManifestError)?;
+            .map_err(RegistryError::ReqwestError)?;
+
+        crate::distribution::verify_manifest(reference, manifest.as_bytes())?;
 
         let mut image = Self {
             registry,
             name,
-            manifest,
+            manifest: manifest.parse().map_err(RegistryError::ManifestError)?,
+            raw_manifest: manifest,
         };
 
         if let ManifestV2::Schema2List(ref l) = image.manifest {
-            image.manifest = ManifestV2::Schema2(l.get_current_platform_manifest::<IS>(&image)?);
+            let (manifest, raw_manifest) = l.fetch_current_platform_manifest::<IS>(&image)?;
+            image.manifest = ManifestV2::Schema2(manifest);
+            image.raw_manifest = raw_manifest;
         };
 
         Ok(image)
@@ -120,8 +149,16 @@ impl<'a> Image<'a> {
     ///# extern crate opencontainers;
     ///# use opencontainers::Registry;
     ///# use opencontainers::image::TestImageSelector as ImagePlatformSelector;
-    ///# let registry = Registry::new("https://registry-1.docker.io");
-    /// let manifest = registry.image::<ImagePlatformSelector>("library/hello-world", "latest")
+    ///# use opencontainers::distribution::cassette::CassetteTransport;
+    ///# let transport = CassetteTransport::replay_file(concat!(
+    ///#     env!("CARGO_MANIFEST_DIR"),
+    ///#     "/src/image/test/synthetic-hello-world.cassette.json"
+    ///# )).unwrap();
+    ///# let registry = Registry::builder("https://registry.example.com")
+    ///#     .transport(std::sync::Arc::new(transport))
+    ///#     .build()
+    ///#     .unwrap();
+    /// let manifest = registry.image::<ImagePlatformSelector>(("library/hello-world", "latest"))
     ///     .expect("Could not get image")
     ///     .manifest();
     /// ```
@@ -129,40 +166,64 @@ impl<'a> Image<'a> {
         &self.manifest
     }
 
-    pub fn get_blob(&self, digest: &Digest) -> Result<reqwest::Response, RegistryError> {
-        let url = format!("{}/v2/{}/blobs/{}", self.registry.url, self.name, digest);
+    /// Return the manifest as sent by the registry.
+    ///
+    /// The digest of a manifest is calculated over these bytes, so use them
+    /// to push the manifest somewhere else, see
+    /// [Registry::push_manifest_raw].
+    pub fn raw_manifest(&self) -> &[u8] {
+        self.raw_manifest.as_bytes()
+    }
+
+    /// Return the name of the repository the image is pulled from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        self.registry.get(&url, None)
+    /// Return the name, manifest and raw manifest, releasing the registry.
+    pub(crate) fn into_parts(self) -> (String, ManifestV2, String) {
+        (self.name, self.manifest, self.raw_manifest)
+    }
+
+    /// Download a blob from the image's repository.
+    ///
+    /// The contents are verified against the digest while they are read,
+    /// see [BlobReader](crate::distribution::BlobReader).
+    pub fn get_blob(&self, digest: &Digest) -> Result<BlobReader<'a>, RegistryError> {
+        self.registry.get_blob(&self.name, digest)
     }
 
     /// Return the image runtime configuration
     pub fn config(&self) -> Result<spec::ImageV1, RegistryError> {
-        match manifest::ManifestV2Schema::from(self.manifest()) {
-            manifest::ManifestV2Schema::Schema2 => {}
-            other => return Err(RegistryError::UnsupportedManifestSchema(other)),
-        };
+        let config = config_descriptor(self.manifest())?;
 
-        let config_digest = match self.manifest() {
-            ManifestV2::Schema2(m) => m.config.digest(),
-            _ => unreachable!(),
-        };
+        let mut data = String::new();
+        self.get_blob(config.digest())?
+            .expect_size(config.size() as u64)
+            .read_to_string(&mut data)
+            .map_err(RegistryError::from_io)?;
 
-        self.get_blob(config_digest)?
-            .text()
-            .map_err(RegistryError::ReqwestError)?
-            .parse()
-            .map_err(RegistryError::ImageSpecError)
+        data.parse().map_err(RegistryError::ImageSpecError)
     }
 
     /// Get a layer, decompressing if necessary
+    ///
+    /// The layer is streamed from the registry, so the archive borrows the
+    /// registry to resume interrupted downloads transparently. It is
+    /// verified against its digest and size while it is read, and reading
+    /// fails once the whole layer was read if it does not match, see
+    /// [BlobReader](crate::distribution::BlobReader).
     pub fn get_layer<L>(
         &self,
         layer: &L,
-    ) -> Result<tar::Archive<Box<dyn std::io::Read>>, RegistryError>
+    ) -> Result<tar::Archive<Box<dyn std::io::Read + 'a>>, RegistryError>
     where
         L: crate::image::manifest::Layer + ?Sized,
     {
-        let response = self.get_blob(layer.digest())?;
+        let mut response = self.registry.get_blob(&self.name, layer.digest())?;
+        if let Some(size) = layer.size() {
+            response = response.expect_size(size as u64);
+        }
 
         if let Some(media_type) = layer.media_type() {
             if !media_type.is_gzipped() {
@@ -176,3 +237,194 @@ impl<'a> Image<'a> {
         Ok(tar::Archive::new(Box::new(decoder)))
     }
 }
+
+/// Return the descriptor of the configuration of an image manifest.
+pub(crate) fn config_descriptor(
+    manifest: &ManifestV2,
+) -> Result<&manifest::ConfigV2_2, RegistryError> {
+    match manifest {
+        ManifestV2::Schema2(m) => Ok(&m.config),
+        other => Err(RegistryError::UnsupportedManifestSchema(other.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::cassette::CassetteTransport;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    /// Synthetic pull of `library/hello-world`, recorded against an emulated
+    /// registry at `registry.example.com` that serves a single small layer.
+    const HELLO_WORLD: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/image/test/synthetic-hello-world.cassette.json"
+    );
+
+    fn registry() -> Registry {
+        let transport =
+            CassetteTransport::replay_file(HELLO_WORLD).expect("Could not load cassette");
+        Registry::builder("https://registry.example.com")
+            .transport(Arc::new(transport))
+            .build()
+            .expect("Could not build registry")
+    }
+
+    #[test]
+    fn test_image_new() {
+        let registry = registry();
+        let image = Image::new::<TestImageSelector>(&registry, ("library/hello-world", "latest"))
+            .expect("Could not get image");
+
+        assert_eq!(image.name(), "library/hello-world");
+        assert_eq!(image.manifest().layers().unwrap().count(), 1);
+
+        let reference: ImageReference = "registry.example.com/library/hello-world"
+            .parse()
+            .expect("Could not parse reference");
+        let image = registry
+            .image::<TestImageSelector>(&reference)
+            .expect("Could not get image");
+        assert_eq!(image.name(), "library/hello-world");
+    }
+
+    #[test]
+    fn test_image_config() {
+        let registry = registry();
+        let image = registry
+            .image::<TestImageSelector>(("library/hello-world", "latest"))
+            .expect("Could not get image");
+        let config = image.config().expect("Could not get config");
+
+        assert_eq!(config.architecture, go::GoArch::AMD64);
+        assert_eq!(config.os, go::GoOs::Linux);
+    }
+
+    #[test]
+    fn test_image_get_layer() {
+        let registry = registry();
+        let image = registry
+            .image::<TestImageSelector>(("library/hello-world", "latest"))
+            .expect("Could not get image");
+        let layer = image.manifest().layers().unwrap().next().unwrap();
+        let mut archive = image.get_layer(layer).expect("Could not get layer");
+
+        let mut entries = archive.entries().expect("Could not read layer");
+        let mut entry = entries.next().unwrap().expect("Could not read entry");
+        assert_eq!(entry.path().unwrap().to_str(), Some("hello"));
+
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "Hello from a synthetic registry!\n");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_image_get_layer_tampered() {
+        use crate::distribution::transport::{response, MemoryTransport};
+        use reqwest::{Method, StatusCode};
+
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, "hello.txt", &b"hello\n"[..])
+            .unwrap();
+        let layer = archive.into_inner().unwrap().finish().unwrap();
+
+        // The registry serves a layer that does not match the manifest.
+        let digest = Digest::sha256(b"another layer");
+        let manifest = format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "config": {{
+                    "mediaType": "application/vnd.docker.container.image.v1+json",
+                    "size": 2,
+                    "digest": "{}"
+                }},
+                "layers": [{{
+                    "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                    "size": {},
+                    "digest": "{}"
+                }}]
+            }}"#,
+            Digest::sha256(b"{}"),
+            layer.len(),
+            digest
+        );
+
+        let url = format!("https://registry.example.com/v2/foo/blobs/{}", digest);
+        let transport = MemoryTransport::new().route(Method::GET, &url, move |_| {
+            response(StatusCode::OK, layer.clone())
+        });
+        let registry = Registry::builder("https://registry.example.com")
+            .transport(Arc::new(transport))
+            .build()
+            .expect("Could not build registry");
+
+        let image = Image {
+            registry: &registry,
+            name: "foo".into(),
+            manifest: manifest.parse().expect("Could not parse manifest"),
+            raw_manifest: manifest,
+        };
+        let layer = image.manifest().layers().unwrap().next().unwrap();
+
+        let mut archive = image.get_layer(layer).expect("Could not get layer");
+        let error = archive
+            .entries()
+            .and_then(|entries| {
+                for entry in entries {
+                    std::io::copy(&mut entry?, &mut std::io::sink())?;
+                }
+                Ok(())
+            })
+            .expect_err("Tampered layer was unpacked");
+
+        match RegistryError::from_io(error) {
+            RegistryError::DigestMismatch(expected, _) => assert_eq!(expected, digest),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_image_push_raw_manifest() {
+        use crate::distribution::transport::{response, MemoryTransport};
+        use reqwest::{Method, StatusCode};
+
+        let registry = registry();
+        let image = registry
+            .image::<TestImageSelector>(("library/hello-world", "latest"))
+            .expect("Could not get image");
+        let digest: Digest =
+            "sha256:f13d78cf54c6ca85cfe760cdf3fec9e5472fbf5ca7a2a733d6f3534b0ce5f090"
+                .parse()
+                .unwrap();
+        assert!(digest.verify(image.raw_manifest()));
+
+        let transport = Arc::new(MemoryTransport::new().route(
+            Method::PUT,
+            "https://mirror.example.com/v2/hello-world/manifests/latest",
+            |_| response(StatusCode::CREATED, ""),
+        ));
+        let mirror = Registry::builder("https://mirror.example.com")
+            .transport(transport.clone())
+            .build()
+            .expect("Could not build registry");
+
+        let media_type = image.manifest().media_type().unwrap();
+        let pushed = mirror
+            .push_manifest_raw("hello-world", "latest", media_type, image.raw_manifest())
+            .expect("Could not push manifest");
+        assert_eq!(pushed, digest);
+
+        let request = &transport.requests()[0];
+        assert_eq!(request.body.as_deref(), Some(image.raw_manifest()));
+    }
+}
@@ -0,0 +1,344 @@
+//! Image references as used by `docker pull`, following the [reference
+//! grammar](https://github.com/docker/distribution/blob/master/reference/reference.go)
+//! of docker/distribution.
+
+use pest::Parser;
+
+use crate::image::manifest::{Digest, ManifestError};
+
+/// The registry images without a domain are pulled from.
+pub const DEFAULT_DOMAIN: &str = "registry-1.docker.io";
+
+/// Domains that are aliases for [DEFAULT_DOMAIN].
+const DEFAULT_DOMAIN_ALIASES: &[&str] = &["docker.io", "index.docker.io"];
+
+/// The namespace of official images on the default registry.
+const OFFICIAL_REPOSITORY_NAMESPACE: &str = "library";
+
+/// The tag used if a reference contains neither a tag nor a digest.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Maximum length of the name of a repository, including the domain.
+const NAME_TOTAL_LENGTH_MAX: usize = 255;
+
+#[derive(Debug, Fail)]
+#[allow(clippy::large_enum_variant)]
+pub enum ReferenceError {
+    #[fail(display = "Parsing reference failed: '{}' ({:?})", _0, _1)]
+    ParseFailed(String, #[cause] pest::error::Error<Rule>),
+
+    #[fail(display = "Repository name too long: {}", _0)]
+    NameTooLong(String),
+
+    #[fail(display = "Invalid digest: {:?}", _0)]
+    InvalidDigest(#[cause] ManifestError),
+}
+
+#[derive(Parser)]
+#[grammar = "image/reference.pest"]
+struct ReferenceParser;
+
+/// A reference to an image, such as
+/// `docker.io/library/nginx:1.25@sha256:...`.
+///
+/// References are normalized when parsed: references without a domain refer
+/// to [DEFAULT_DOMAIN], single-component names on the default registry are
+/// official images in the `library` namespace, and references without a
+/// digest default to the `latest` tag.
+///
+/// # Example
+///
+/// ```
+///# use opencontainers::image::ImageReference;
+/// let reference: ImageReference = "nginx".parse().expect("parsing reference failed!");
+/// assert_eq!(reference.domain, "registry-1.docker.io");
+/// assert_eq!(reference.name, "library/nginx");
+/// assert_eq!(reference.tag.as_deref(), Some("latest"));
+/// assert_eq!(
+///     &reference.to_string(),
+///     "registry-1.docker.io/library/nginx:latest"
+/// );
+/// ```
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct ImageReference {
+    /// The domain of the registry, optionally including a port.
+    pub domain: String,
+
+    /// The name of the repository within the registry.
+    pub name: String,
+
+    /// The tag, if any.
+    pub tag: Option<String>,
+
+    /// The digest, if any.
+    pub digest: Option<Digest>,
+}
+
+impl ImageReference {
+    /// Return the URL of the registry the image is stored in.
+    pub fn registry_url(&self) -> String {
+        format!("https://{}", self.domain)
+    }
+
+    /// Return the reference to use when fetching the manifest, which is the
+    /// digest if available and the tag otherwise.
+    pub fn reference(&self) -> String {
+        match (&self.digest, &self.tag) {
+            (Some(digest), _) => digest.to_string(),
+            (None, Some(tag)) => tag.clone(),
+            (None, None) => DEFAULT_TAG.into(),
+        }
+    }
+}
+
+/// An image in a repository, given either as an [ImageReference] or as a
+/// pair of a repository name and a tag or digest.
+///
+/// [Registry::image](crate::Registry::image) and [Image::new](crate::Image::new)
+/// accept either, like [std::net::ToSocketAddrs] accepts an address or a
+/// pair of a host and a port.
+///
+/// # Example
+/// ```
+///# use opencontainers::image::{ImageReference, ToImageName};
+/// let reference: ImageReference = "nginx:1.25".parse().expect("Could not parse reference");
+/// assert_eq!(reference.image_name(), ("library/nginx".into(), "1.25".into()));
+/// assert_eq!(("library/nginx", "1.25").image_name(), reference.image_name());
+/// ```
+pub trait ToImageName {
+    /// Return the name of the repository and the tag or digest of the image.
+    fn image_name(&self) -> (String, String);
+
+    /// Return the URL of the registry the image is expected in, if known.
+    fn image_registry_url(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToImageName for ImageReference {
+    fn image_name(&self) -> (String, String) {
+        (self.name.clone(), self.reference())
+    }
+
+    fn image_registry_url(&self) -> Option<String> {
+        Some(self.registry_url())
+    }
+}
+
+impl ToImageName for (&str, &str) {
+    fn image_name(&self) -> (String, String) {
+        (self.0.into(), self.1.into())
+    }
+}
+
+impl<T: ToImageName + ?Sized> ToImageName for &T {
+    fn image_name(&self) -> (String, String) {
+        (**self).image_name()
+    }
+
+    fn image_registry_url(&self) -> Option<String> {
+        (**self).image_registry_url()
+    }
+}
+
+impl std::str::FromStr for ImageReference {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pairs = ReferenceParser::parse(Rule::reference, s)
+            .map_err(|e| ReferenceError::ParseFailed(s.into(), e))?
+            .next()
+            .unwrap() // Can never fail because we have at least one result
+            .into_inner();
+
+        let mut domain = None;
+        let mut name = String::new();
+        let mut tag = None;
+        let mut digest = None;
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::name => {
+                    if pair.as_str().len() > NAME_TOTAL_LENGTH_MAX {
+                        return Err(ReferenceError::NameTooLong(pair.as_str().into()));
+                    }
+
+                    for inner in pair.into_inner() {
+                        match inner.as_rule() {
+                            Rule::domain => domain = Some(inner.as_str().to_owned()),
+                            Rule::path => name = inner.as_str().to_owned(),
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+                Rule::tag => tag = Some(pair.as_str().to_owned()),
+                Rule::digest => {
+                    digest = Some(
+                        pair.as_str()
+                            .parse()
+                            .map_err(ReferenceError::InvalidDigest)?,
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        let domain = match domain {
+            Some(ref d) if DEFAULT_DOMAIN_ALIASES.contains(&d.as_str()) => DEFAULT_DOMAIN.into(),
+            Some(d) => d,
+            None => DEFAULT_DOMAIN.into(),
+        };
+
+        if domain == DEFAULT_DOMAIN && !name.contains('/') {
+            name = format!("{}/{}", OFFICIAL_REPOSITORY_NAMESPACE, name);
+        }
+
+        if tag.is_none() && digest.is_none() {
+            tag = Some(DEFAULT_TAG.into());
+        }
+
+        Ok(ImageReference {
+            domain,
+            name,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl std::fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.domain, self.name)?;
+
+        if let Some(ref tag) = self.tag {
+            write!(f, ":{}", tag)?;
+        }
+
+        if let Some(ref digest) = self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn parse(s: &str) -> ImageReference {
+        s.parse()
+            .unwrap_or_else(|e| panic!("Could not parse {}: {:?}", s, e))
+    }
+
+    #[test]
+    fn test_parse_official_image() {
+        let reference = parse("nginx");
+        assert_eq!(reference.domain, DEFAULT_DOMAIN);
+        assert_eq!(reference.name, "library/nginx");
+        assert_eq!(reference.tag.as_deref(), Some("latest"));
+        assert_eq!(reference.digest, None);
+        assert_eq!(reference.reference(), "latest");
+    }
+
+    #[test]
+    fn test_parse_docker_hub_aliases() {
+        let expected = "registry-1.docker.io/library/nginx:1.25";
+        assert_eq!(parse("nginx:1.25").to_string(), expected);
+        assert_eq!(parse("docker.io/nginx:1.25").to_string(), expected);
+        assert_eq!(parse("docker.io/library/nginx:1.25").to_string(), expected);
+        assert_eq!(
+            parse("index.docker.io/library/nginx:1.25").to_string(),
+            expected
+        );
+        assert_eq!(parse(expected).to_string(), expected);
+    }
+
+    #[test]
+    fn test_parse_user_image() {
+        let reference = parse("fubarnetes/opencontainers");
+        assert_eq!(reference.domain, DEFAULT_DOMAIN);
+        assert_eq!(reference.name, "fubarnetes/opencontainers");
+    }
+
+    #[test]
+    fn test_parse_full_reference() {
+        let reference = parse(&format!("docker.io/library/nginx:1.25@{}", DIGEST));
+        assert_eq!(reference.domain, DEFAULT_DOMAIN);
+        assert_eq!(reference.name, "library/nginx");
+        assert_eq!(reference.tag.as_deref(), Some("1.25"));
+        assert_eq!(reference.digest, Some(DIGEST.parse().unwrap()));
+        assert_eq!(reference.reference(), DIGEST);
+    }
+
+    #[test]
+    fn test_parse_digest_only() {
+        let reference = parse(&format!("nginx@{}", DIGEST));
+        assert_eq!(reference.tag, None);
+        assert_eq!(
+            reference.to_string(),
+            format!("registry-1.docker.io/library/nginx@{}", DIGEST)
+        );
+    }
+
+    #[test]
+    fn test_parse_domain_with_port() {
+        let reference = parse("localhost:5000/foo/bar:baz");
+        assert_eq!(reference.domain, "localhost:5000");
+        assert_eq!(reference.name, "foo/bar");
+        assert_eq!(reference.tag.as_deref(), Some("baz"));
+        assert_eq!(reference.registry_url(), "https://localhost:5000");
+
+        let reference = parse("registry:5000/foo");
+        assert_eq!(reference.domain, "registry:5000");
+        assert_eq!(reference.name, "foo");
+
+        let reference = parse("localhost/foo");
+        assert_eq!(reference.domain, "localhost");
+        assert_eq!(reference.name, "foo");
+    }
+
+    #[test]
+    fn test_parse_name_with_port_like_tag() {
+        let reference = parse("foo:5000");
+        assert_eq!(reference.domain, DEFAULT_DOMAIN);
+        assert_eq!(reference.name, "library/foo");
+        assert_eq!(reference.tag.as_deref(), Some("5000"));
+    }
+
+    #[test]
+    fn test_parse_separators() {
+        let reference = parse("ghcr.io/foo-bar/baz__qux.quux");
+        assert_eq!(reference.domain, "ghcr.io");
+        assert_eq!(reference.name, "foo-bar/baz__qux.quux");
+    }
+
+    #[test]
+    fn test_parse_reference_fail() {
+        "".parse::<ImageReference>()
+            .expect_err("parsing empty reference succeeded");
+        "Uppercase"
+            .parse::<ImageReference>()
+            .expect_err("parsing uppercase name succeeded");
+        "foo/-bar"
+            .parse::<ImageReference>()
+            .expect_err("parsing component with leading separator succeeded");
+        "foo:-bar"
+            .parse::<ImageReference>()
+            .expect_err("parsing tag with leading dash succeeded");
+        "foo@sha256:xyz"
+            .parse::<ImageReference>()
+            .expect_err("parsing invalid digest succeeded");
+        "foo@md5:d41d8cd98f00b204e9800998ecf8427e"
+            .parse::<ImageReference>()
+            .expect_err("parsing unsupported digest succeeded");
+        format!("foo:{}", "a".repeat(129))
+            .parse::<ImageReference>()
+            .expect_err("parsing long tag succeeded");
+        format!("foo/{}", "a".repeat(255))
+            .parse::<ImageReference>()
+            .expect_err("parsing long name succeeded");
+    }
+}
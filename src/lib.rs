@@ -18,3 +18,6 @@ pub use image::Image;
 
 pub mod runtime;
 pub use runtime::{Bundle, Runtime};
+
+#[cfg(feature = "server")]
+pub mod server;
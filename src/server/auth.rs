@@ -0,0 +1,986 @@
+//! Token authentication as described in the [Docker token authentication
+//! spec](https://docs.docker.com/registry/spec/auth/token/).
+//!
+//! A [TokenServer] issues tokens to the users in an [Htpasswd] file, for the
+//! access granted to them by an [Acl]. [TokenAuth] protects a registry,
+//! answering requests without a sufficient token with a challenge pointing
+//! clients to the token server.
+//!
+//! Tokens are JSON Web Tokens signed with HMAC-SHA256, using a key shared by
+//! the token server and the registry.
+
+use crate::distribution::errors::ErrorCode;
+use crate::server::registry::{query, ApiError, Route};
+use crate::server::{Handler, Request, Response};
+
+use chrono::{TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderName, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Method, StatusCode, Url};
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Lifetime of tokens, unless configured otherwise.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Largest request body accepted by the token server.
+const MAX_FORM_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Fail)]
+pub enum AuthError {
+    #[fail(display = "IO Error: {:?}", _0)]
+    IoError(#[cause] std::io::Error),
+
+    #[fail(display = "TOML Error: {:?}", _0)]
+    TomlError(#[cause] toml::de::Error),
+
+    #[fail(display = "Invalid or unsupported htpasswd entry on line {}", _0)]
+    InvalidHtpasswd(usize),
+
+    #[fail(display = "Invalid scope: {}", _0)]
+    InvalidScope(String),
+
+    #[fail(display = "Invalid token: {}", _0)]
+    InvalidToken(&'static str),
+
+    #[fail(display = "Token is expired or not yet valid")]
+    ExpiredToken,
+}
+
+/// Access to a resource, such as `repository:library/hello-world:pull,push`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl Scope {
+    pub fn new(resource_type: &str, name: &str, actions: &[&str]) -> Self {
+        Scope {
+            resource_type: resource_type.into(),
+            name: name.into(),
+            actions: actions.iter().map(|&action| action.into()).collect(),
+        }
+    }
+
+    /// Check whether this scope includes all access requested by another.
+    pub fn allows(&self, requested: &Scope) -> bool {
+        self.resource_type == requested.resource_type
+            && self.name == requested.name
+            && requested
+                .actions
+                .iter()
+                .all(|action| self.actions.contains(action))
+    }
+}
+
+impl FromStr for Scope {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AuthError::InvalidScope(s.into());
+
+        // Names may contain a port, so the actions are split off the end.
+        let (rest, actions) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let (resource_type, name) = rest.split_once(':').ok_or_else(invalid)?;
+
+        if resource_type.is_empty() || name.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Scope {
+            resource_type: resource_type.into(),
+            name: name.into(),
+            actions: actions
+                .split(',')
+                .filter(|action| !action.is_empty())
+                .map(String::from)
+                .collect(),
+        })
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.resource_type,
+            self.name,
+            self.actions.join(",")
+        )
+    }
+}
+
+/// Claims of a token issued by a [TokenServer].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// The issuer of the token.
+    pub iss: String,
+
+    /// The account the token was issued to, empty for anonymous users.
+    pub sub: String,
+
+    /// The service the token is valid for.
+    pub aud: String,
+
+    pub exp: i64,
+    pub nbf: i64,
+    pub iat: i64,
+
+    /// A unique identifier of the token.
+    pub jti: String,
+
+    /// The access granted by the token.
+    #[serde(default)]
+    pub access: Vec<Scope>,
+}
+
+impl Claims {
+    /// Encode the claims as a JSON Web Token, signed with a key.
+    pub fn sign(&self, key: &[u8]) -> String {
+        let header = encode_segment(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let claims = encode_segment(&serde_json::to_vec(self).expect("Claims are serializable"));
+        let message = format!("{}.{}", header, claims);
+
+        let mut mac = hmac(key);
+        mac.input(message.as_bytes());
+        let signature = encode_segment(&mac.result().code());
+
+        format!("{}.{}", message, signature)
+    }
+
+    /// Decode a JSON Web Token, checking its signature and that it is
+    /// currently valid.
+    pub fn verify(token: &str, key: &[u8]) -> Result<Self, AuthError> {
+        let malformed = || AuthError::InvalidToken("malformed token");
+        let (message, signature) = token.rsplit_once('.').ok_or_else(malformed)?;
+        let (header, claims) = message.split_once('.').ok_or_else(malformed)?;
+
+        let header: HashMap<String, serde_json::Value> = decode_segment(header)
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or(AuthError::InvalidToken("malformed header"))?;
+
+        if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+            return Err(AuthError::InvalidToken("unsupported algorithm"));
+        }
+
+        let signature =
+            decode_segment(signature).ok_or(AuthError::InvalidToken("malformed signature"))?;
+
+        let mut mac = hmac(key);
+        mac.input(message.as_bytes());
+        mac.verify(&signature)
+            .map_err(|_| AuthError::InvalidToken("invalid signature"))?;
+
+        let claims: Claims = decode_segment(claims)
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(AuthError::InvalidToken("malformed claims"))?;
+
+        let now = Utc::now().timestamp();
+        if now < claims.nbf || now >= claims.exp {
+            return Err(AuthError::ExpiredToken);
+        }
+
+        Ok(claims)
+    }
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_varkey(key).expect("HMAC accepts keys of any size")
+}
+
+fn encode_segment(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_segment(segment: &str) -> Option<Vec<u8>> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD).ok()
+}
+
+/// Users and their passwords, as written by `htpasswd -B`.
+///
+/// Only bcrypt hashes are supported.
+#[derive(Clone, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for Htpasswd {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut users: Vec<_> = self.users.keys().collect();
+        users.sort();
+        write!(f, "Htpasswd {{ users: {:?} }}", users)
+    }
+}
+
+impl Htpasswd {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, AuthError> {
+        std::fs::read_to_string(path)
+            .map_err(AuthError::IoError)?
+            .parse()
+    }
+
+    /// Check the password of a user.
+    ///
+    /// Unknown users take as long to reject as wrong passwords, so that
+    /// the time taken does not reveal which users exist.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => {
+                // Verify against any known hash, which has the same cost as
+                // the hash of an existing user would have.
+                if let Some(hash) = self.users.values().next() {
+                    let _ = bcrypt::verify(password, hash);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for Htpasswd {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((username, hash)) if !username.is_empty() && hash.starts_with("$2") => {
+                    users.insert(username.to_owned(), hash.to_owned());
+                }
+                _ => return Err(AuthError::InvalidHtpasswd(number + 1)),
+            }
+        }
+
+        Ok(Htpasswd { users })
+    }
+}
+
+/// Rules granting access to resources, read from a TOML file.
+///
+/// ```toml
+/// [[acl]]
+/// account = "alice"
+/// name = "library/*"
+/// actions = ["pull", "push"]
+/// ```
+///
+/// Patterns may use `*` to match any characters, and `${account}` in a name
+/// is replaced by the name of the user. Entries without an `account` apply
+/// to everyone, including anonymous users. The `type` of resource defaults
+/// to `repository`.
+///
+/// A user is granted the actions allowed by any matching entry, with `*`
+/// allowing all actions.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Acl {
+    #[serde(default, rename = "acl")]
+    pub entries: Vec<AclEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclEntry {
+    pub account: Option<String>,
+
+    #[serde(rename = "type", default = "default_resource_type")]
+    pub resource_type: String,
+
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+fn default_resource_type() -> String {
+    "repository".into()
+}
+
+impl AclEntry {
+    fn matches(&self, account: Option<&str>, scope: &Scope) -> bool {
+        let account_matches = match (&self.account, account) {
+            (None, _) => true,
+            (Some(pattern), Some(account)) => glob(pattern, account),
+            (Some(_), None) => false,
+        };
+
+        let name_matches = match account {
+            Some(account) => glob(&self.name.replace("${account}", account), &scope.name),
+            None if self.name.contains("${account}") => false,
+            None => glob(&self.name, &scope.name),
+        };
+
+        self.resource_type == scope.resource_type && account_matches && name_matches
+    }
+}
+
+impl Acl {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, AuthError> {
+        std::fs::read_to_string(path)
+            .map_err(AuthError::IoError)?
+            .parse()
+    }
+
+    /// Return the part of the requested access granted to an account, or to
+    /// anonymous users if it is `None`.
+    pub fn grant(&self, account: Option<&str>, requested: &Scope) -> Scope {
+        let mut actions: Vec<String> = Vec::new();
+
+        for entry in self
+            .entries
+            .iter()
+            .filter(|e| e.matches(account, requested))
+        {
+            let allowed = |action: &String| {
+                entry.actions.contains(action) || entry.actions.iter().any(|a| a == "*")
+            };
+
+            for action in requested.actions.iter().filter(|a| allowed(a)) {
+                if !actions.contains(action) {
+                    actions.push(action.clone());
+                }
+            }
+        }
+
+        Scope {
+            actions,
+            ..requested.clone()
+        }
+    }
+}
+
+impl FromStr for Acl {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(AuthError::TomlError)
+    }
+}
+
+/// Match a value against a pattern, where `*` matches any characters.
+fn glob(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == value,
+        Some((prefix, rest)) => {
+            value.starts_with(prefix)
+                && (prefix.len()..=value.len())
+                    .filter(|&i| value.is_char_boundary(i))
+                    .any(|i| glob(rest, &value[i..]))
+        }
+    }
+}
+
+/// Body of a successful token response.
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    access_token: String,
+    expires_in: u64,
+    issued_at: String,
+}
+
+/// An authorization service issuing tokens for a registry.
+///
+/// Tokens are requested with `GET`, passing credentials using `Basic`
+/// authentication, or with a `POST` using the OAuth2 password grant.
+/// Requests without credentials receive a token for the access granted to
+/// anonymous users. Tokens are issued for any path, so the realm can be any
+/// URL of the server.
+///
+/// # Example
+/// ```no_run
+///# extern crate opencontainers;
+/// use opencontainers::server::auth::{Acl, Htpasswd, TokenAuth, TokenServer};
+/// use opencontainers::server::{LocalRegistry, Server, Storage};
+///
+/// let key = b"a secret shared by both servers";
+///
+/// let users = Htpasswd::from_file("/etc/registry/htpasswd").expect("Could not read users");
+/// let acl = Acl::from_file("/etc/registry/acl.toml").expect("Could not read ACL");
+/// let tokens = Server::start("0.0.0.0:5001", TokenServer::new("registry", key, users, acl))
+///     .expect("Could not start token server");
+///
+/// let storage = Storage::new("/var/lib/registry").expect("Could not open storage");
+/// let registry = TokenAuth::new(
+///     LocalRegistry::new(storage),
+///     "https://auth.example.com/token",
+///     "registry",
+///     key,
+/// );
+/// Server::start("0.0.0.0:5000", registry)
+///     .expect("Could not start registry")
+///     .wait();
+/// ```
+pub struct TokenServer {
+    service: String,
+    issuer: String,
+    key: Vec<u8>,
+    users: Htpasswd,
+    acl: Acl,
+    lifetime: Duration,
+    ids: AtomicUsize,
+}
+
+impl std::fmt::Debug for TokenServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "TokenServer {{ service: {}, issuer: {}, users: {:?}, acl: {:?}, lifetime: {:?} }}",
+            self.service, self.issuer, self.users, self.acl, self.lifetime
+        )
+    }
+}
+
+impl Handler for TokenServer {
+    fn handle(&self, request: Request) -> Response {
+        let result = match request.method {
+            Method::GET => self.get(request),
+            Method::POST => self.post(request),
+            _ => Err(oauth_error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "invalid_request",
+            )),
+        };
+
+        match result {
+            Ok(claims) => self.token_response(&claims),
+            Err(response) => response,
+        }
+    }
+}
+
+impl TokenServer {
+    /// Create a token server for a service, issuing tokens signed with
+    /// `key`.
+    pub fn new(service: &str, key: &[u8], users: Htpasswd, acl: Acl) -> Self {
+        TokenServer {
+            service: service.into(),
+            issuer: service.into(),
+            key: key.to_vec(),
+            users,
+            acl,
+            lifetime: DEFAULT_TOKEN_LIFETIME,
+            ids: AtomicUsize::new(0),
+        }
+    }
+
+    /// Set the issuer of tokens. Defaults to the name of the service.
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Set how long tokens are valid. Defaults to five minutes.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Issue a token for an account, or an anonymous user if it is `None`,
+    /// granting the part of the requested access allowed by the ACL.
+    pub fn issue(&self, account: Option<&str>, requested: &[Scope]) -> Claims {
+        let access = requested
+            .iter()
+            .map(|scope| self.acl.grant(account, scope))
+            .filter(|scope| !scope.actions.is_empty())
+            .collect();
+
+        let now = Utc::now().timestamp();
+        let id = self.ids.fetch_add(1, Ordering::SeqCst);
+
+        Claims {
+            iss: self.issuer.clone(),
+            sub: account.unwrap_or_default().to_owned(),
+            aud: self.service.clone(),
+            exp: now + self.lifetime.as_secs() as i64,
+            nbf: now,
+            iat: now,
+            jti: format!(
+                "{:x}-{:x}",
+                Utc::now().timestamp_nanos_opt().unwrap_or(now),
+                id
+            ),
+            access,
+        }
+    }
+
+    fn get(&self, request: Request) -> Result<Claims, Response> {
+        let account = match request.headers.get(AUTHORIZATION) {
+            Some(value) => {
+                let (username, password) = value
+                    .to_str()
+                    .ok()
+                    .and_then(parse_basic)
+                    .ok_or_else(|| self.unauthorized())?;
+
+                if !self.users.verify(&username, &password) {
+                    return Err(self.unauthorized());
+                }
+
+                Some(username)
+            }
+            None => None,
+        };
+
+        let scopes = request
+            .url
+            .query_pairs()
+            .filter(|(key, _)| key == "scope")
+            .map(|(_, scope)| scope.into_owned())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        self.check_service(query(&request.url, "service"))?;
+        let scopes = parse_scopes(&scopes)?;
+
+        Ok(self.issue(account.as_deref(), &scopes))
+    }
+
+    fn post(&self, request: Request) -> Result<Claims, Response> {
+        let mut body = Vec::new();
+        request
+            .body
+            .take(MAX_FORM_SIZE)
+            .read_to_end(&mut body)
+            .map_err(|_| oauth_error(StatusCode::BAD_REQUEST, "invalid_request"))?;
+
+        let form: HashMap<String, String> = serde_urlencoded::from_bytes(&body)
+            .map_err(|_| oauth_error(StatusCode::BAD_REQUEST, "invalid_request"))?;
+        let field = |name: &str| form.get(name).map(String::as_str);
+
+        if field("grant_type") != Some("password") {
+            return Err(oauth_error(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+            ));
+        }
+
+        let (username, password) = match (field("username"), field("password")) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return Err(oauth_error(StatusCode::BAD_REQUEST, "invalid_request")),
+        };
+
+        if !self.users.verify(username, password) {
+            return Err(oauth_error(StatusCode::UNAUTHORIZED, "invalid_grant"));
+        }
+
+        self.check_service(field("service").map(String::from))?;
+        let scopes = parse_scopes(field("scope").unwrap_or_default())?;
+
+        Ok(self.issue(Some(username), &scopes))
+    }
+
+    fn check_service(&self, service: Option<String>) -> Result<(), Response> {
+        match service {
+            Some(ref service) if *service != self.service => {
+                Err(oauth_error(StatusCode::BAD_REQUEST, "invalid_request"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn token_response(&self, claims: &Claims) -> Response {
+        let token = claims.sign(&self.key);
+        let issued_at = Utc
+            .timestamp_opt(claims.iat, 0)
+            .single()
+            .unwrap_or_else(Utc::now);
+
+        info!(
+            "Issued token {} for {:?} to {:?}",
+            claims.jti, claims.access, claims.sub
+        );
+
+        Response::json(
+            StatusCode::OK,
+            &TokenResponse {
+                access_token: token.clone(),
+                token,
+                expires_in: self.lifetime.as_secs(),
+                issued_at: issued_at.to_rfc3339(),
+            },
+        )
+    }
+
+    fn unauthorized(&self) -> Response {
+        oauth_error(StatusCode::UNAUTHORIZED, "invalid_grant").with_header(
+            WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\"", self.service),
+        )
+    }
+}
+
+/// Parse the credentials of a `Basic` `Authorization` header.
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let credentials = base64::decode(value.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Parse space-separated scopes.
+fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, Response> {
+    scopes
+        .split_whitespace()
+        .map(|scope| scope.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| oauth_error(StatusCode::BAD_REQUEST, "invalid_scope"))
+}
+
+fn oauth_error(status: StatusCode, error: &str) -> Response {
+    let mut body = HashMap::new();
+    body.insert("error", error);
+
+    Response::json(status, &body)
+}
+
+/// A registry requiring tokens issued by a [TokenServer].
+///
+/// Requests without a token granting the access they need are answered with
+/// a challenge, pointing clients to the token server at `realm`.
+pub struct TokenAuth<H> {
+    inner: H,
+    realm: String,
+    service: String,
+    issuer: String,
+    key: Vec<u8>,
+}
+
+impl<H: std::fmt::Debug> std::fmt::Debug for TokenAuth<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "TokenAuth {{ inner: {:?}, realm: {}, service: {}, issuer: {} }}",
+            self.inner, self.realm, self.service, self.issuer
+        )
+    }
+}
+
+impl<H: Handler> TokenAuth<H> {
+    /// Protect a registry, accepting tokens for `service` signed with `key`.
+    pub fn new(inner: H, realm: &str, service: &str, key: &[u8]) -> Self {
+        TokenAuth {
+            inner,
+            realm: realm.into(),
+            service: service.into(),
+            issuer: service.into(),
+            key: key.to_vec(),
+        }
+    }
+
+    /// Set the issuer tokens must be issued by, see [TokenServer::issuer].
+    /// Defaults to the name of the service.
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Return the registry requests are passed on to.
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    fn challenge(&self, scopes: &[Scope], insufficient: bool) -> Response {
+        let mut challenge = format!(
+            "Bearer realm=\"{}\",service=\"{}\"",
+            self.realm, self.service
+        );
+
+        if !scopes.is_empty() {
+            let scopes: Vec<_> = scopes.iter().map(Scope::to_string).collect();
+            challenge.push_str(&format!(",scope=\"{}\"", scopes.join(" ")));
+        }
+
+        let error = if insufficient {
+            challenge.push_str(",error=\"insufficient_scope\"");
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Denied,
+                "requested access to the resource is denied",
+            )
+        } else {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Unauthorized,
+                "authentication required",
+            )
+        };
+
+        Response::from(error)
+            .with_header(WWW_AUTHENTICATE, challenge)
+            .with_header(
+                HeaderName::from_static("docker-distribution-api-version"),
+                "registry/2.0",
+            )
+    }
+}
+
+impl<H: Handler> Handler for TokenAuth<H> {
+    fn handle(&self, request: Request) -> Response {
+        let path = request.url.path().to_owned();
+        let route = match Route::parse(&path) {
+            Some(route) => route,
+            None => return self.inner.handle(request),
+        };
+
+        let required = required_scopes(&request.method, &route, &request.url);
+
+        let claims = request
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| Claims::verify(token, &self.key))
+            .and_then(|claims| match claims {
+                Ok(claims) => Some(claims),
+                Err(e) => {
+                    info!("Rejected token: {}", e);
+                    None
+                }
+            })
+            .filter(|claims| claims.aud == self.service && claims.iss == self.issuer);
+
+        match claims {
+            Some(ref claims)
+                if required
+                    .iter()
+                    .all(|scope| claims.access.iter().any(|access| access.allows(scope))) =>
+            {
+                self.inner.handle(request)
+            }
+            Some(_) => self.challenge(&required, true),
+            None => self.challenge(&required, false),
+        }
+    }
+}
+
+/// Return the access needed for a request.
+fn required_scopes(method: &Method, route: &Route, url: &Url) -> Vec<Scope> {
+    let name = match route {
+        Route::Base => return Vec::new(),
+        Route::Catalog => return vec![Scope::new("registry", "catalog", &["*"])],
+        route => route.name().expect("Route has a repository"),
+    };
+
+    let actions: &[&str] = match (method, route) {
+        (&Method::GET, Route::Upload(..)) => &["pull", "push"],
+        (&Method::GET, _) | (&Method::HEAD, _) => &["pull"],
+        (&Method::DELETE, Route::Manifest(..)) | (&Method::DELETE, Route::Blob(..)) => &["delete"],
+        _ => &["pull", "push"],
+    };
+
+    let mut scopes = vec![Scope::new("repository", name, actions)];
+
+    // Mounting a blob needs access to the repository it is mounted from.
+    if let (Route::Upload(_, None), Some(_), Some(from)) =
+        (route, query(url, "mount"), query(url, "from"))
+    {
+        if from != name {
+            scopes.push(Scope::new("repository", &from, &["pull"]));
+        }
+    }
+
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::{Registry, RegistryError, RetryPolicy};
+    use crate::image::manifest::Digest;
+    use crate::server::{LocalRegistry, Server, Storage};
+
+    const KEY: &[u8] = b"not so secret";
+    const SERVICE: &str = "registry.test";
+
+    fn acl() -> Acl {
+        include_str!("test/acl.test.toml").parse().unwrap()
+    }
+
+    fn claims(access: Vec<Scope>) -> Claims {
+        let now = Utc::now().timestamp();
+
+        Claims {
+            iss: SERVICE.into(),
+            sub: "alice".into(),
+            aud: SERVICE.into(),
+            exp: now + 60,
+            nbf: now,
+            iat: now,
+            jti: "1".into(),
+            access,
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope: Scope = "repository:localhost:5000/foo:pull,push".parse().unwrap();
+        assert_eq!(
+            scope,
+            Scope::new("repository", "localhost:5000/foo", &["pull", "push"])
+        );
+        assert_eq!(scope.to_string(), "repository:localhost:5000/foo:pull,push");
+
+        assert!(scope.allows(&Scope::new("repository", "localhost:5000/foo", &["pull"])));
+        assert!(!scope.allows(&Scope::new("repository", "localhost:5000/foo", &["delete"])));
+        assert!("repository:foo".parse::<Scope>().is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = acl();
+        let grant = |account, scope: &str| acl.grant(account, &scope.parse().unwrap()).actions;
+
+        assert_eq!(grant(None, "repository:public/foo:pull,push"), vec!["pull"]);
+        assert_eq!(
+            grant(Some("alice"), "repository:public/foo:pull,push"),
+            vec!["pull", "push"]
+        );
+        assert_eq!(
+            grant(Some("bob"), "repository:bob/foo:pull,push,delete"),
+            vec!["pull", "push", "delete"]
+        );
+        assert!(grant(Some("bob"), "repository:alice/foo:pull").is_empty());
+        assert!(grant(None, "repository:foo:pull").is_empty());
+        assert_eq!(grant(Some("alice"), "registry:catalog:*"), vec!["*"]);
+        assert!(grant(Some("bob"), "registry:catalog:*").is_empty());
+    }
+
+    #[test]
+    fn test_htpasswd() {
+        let users: Htpasswd = include_str!("test/htpasswd.test").parse().unwrap();
+
+        assert!(users.verify("alice", "alice-secret"));
+        assert!(!users.verify("alice", "bob-secret"));
+        assert!(!users.verify("carol", "carol-secret"));
+
+        assert!("alice:{SHA}secret".parse::<Htpasswd>().is_err());
+    }
+
+    #[test]
+    fn test_claims() {
+        let claims = claims(vec![Scope::new("repository", "foo", &["pull"])]);
+        let token = claims.sign(KEY);
+
+        assert_eq!(Claims::verify(&token, KEY).unwrap(), claims);
+        assert!(Claims::verify(&token, b"wrong key").is_err());
+
+        let (message, _) = token.rsplit_once('.').unwrap();
+        let forged = Claims {
+            sub: "mallory".into(),
+            ..claims.clone()
+        }
+        .sign(b"wrong key");
+        let (_, signature) = forged.rsplit_once('.').unwrap();
+        assert!(Claims::verify(&format!("{}.{}", message, signature), KEY).is_err());
+
+        let expired = Claims {
+            exp: claims.iat - 1,
+            ..claims
+        };
+        match Claims::verify(&expired.sign(KEY), KEY) {
+            Err(AuthError::ExpiredToken) => {}
+            result => panic!("Token should be expired: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_client() {
+        let users = include_str!("test/htpasswd.test").parse().unwrap();
+        let tokens =
+            Server::start("127.0.0.1:0", TokenServer::new(SERVICE, KEY, users, acl())).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Server::start(
+            "127.0.0.1:0",
+            TokenAuth::new(
+                LocalRegistry::new(Storage::new(dir.path()).unwrap()),
+                &format!("{}/token", tokens.url()),
+                SERVICE,
+                KEY,
+            ),
+        )
+        .unwrap();
+
+        let client = || {
+            Registry::builder(&registry.url())
+                .retry_policy(RetryPolicy::none())
+                .build()
+                .unwrap()
+        };
+        let anonymous = client();
+        let alice = client().with_basic_auth("alice", "alice-secret");
+        let bob = client().with_basic_auth("bob", "bob-secret");
+
+        let digest = Digest::sha256(b"hello");
+        alice.push_blob("public/foo", &digest, b"hello").unwrap();
+        assert!(anonymous.blob_exists("public/foo", &digest).unwrap());
+
+        bob.push_blob("bob/foo", &digest, b"hello").unwrap();
+        assert!(bob.push_blob("public/bar", &digest, b"hello").is_err());
+        assert!(anonymous.blob_exists("bob/foo", &digest).is_err());
+
+        let repositories: Vec<_> = alice.catalog().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(repositories, vec!["bob/foo", "public/foo"]);
+        assert!(bob
+            .catalog()
+            .and_then(|catalog| catalog.collect::<Result<Vec<_>, _>>())
+            .is_err());
+
+        let wrong_password = client().with_basic_auth("alice", "bob-secret");
+        match wrong_password.blob_exists("public/foo", &digest) {
+            Err(RegistryError::CouldNotAuthenticate) => {}
+            result => panic!("Request should be unauthorized: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_token_issuer() {
+        let status = |registry: &TokenAuth<_>, claims: &Claims| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            let token = format!("Bearer {}", claims.sign(KEY));
+            headers.insert(AUTHORIZATION, token.parse().unwrap());
+
+            let request = Request {
+                method: Method::GET,
+                url: "http://localhost/v2/foo/tags/list".parse().unwrap(),
+                headers,
+                body: &mut std::io::empty(),
+            };
+            registry.handle(request).status
+        };
+
+        let registry = TokenAuth::new(
+            |_: Request| Response::new(StatusCode::OK),
+            "https://auth.example.com/token",
+            SERVICE,
+            KEY,
+        );
+
+        let claims = claims(vec![Scope::new("repository", "foo", &["pull"])]);
+        let foreign = Claims {
+            iss: "auth.example.com".into(),
+            ..claims.clone()
+        };
+        assert_eq!(status(&registry, &claims), StatusCode::OK);
+        assert_eq!(status(&registry, &foreign), StatusCode::UNAUTHORIZED);
+
+        let registry = registry.issuer("auth.example.com");
+        assert_eq!(status(&registry, &foreign), StatusCode::OK);
+        assert_eq!(status(&registry, &claims), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,310 @@
+//! An embedded registry server implementing the [Distribution
+//! Spec](https://github.com/opencontainers/distribution-spec/blob/main/spec.md).
+//!
+//! The server is meant for hermetic tests and small deployments, and stores
+//! all content in a local directory, see [Storage]. It can also serve as a
+//! pull-through cache for another registry, see [ProxyRegistry], and
+//! requests can be authorized using tokens, see [auth].
+//!
+//! This module requires the `server` feature.
+//!
+//! # Example
+//! ```
+//!# extern crate opencontainers;
+//! use opencontainers::server::{LocalRegistry, Server, Storage};
+//! use opencontainers::Registry;
+//!
+//!# let dir = tempfile::tempdir().unwrap();
+//! let storage = Storage::new(dir.path()).expect("Could not open storage");
+//! let server = Server::start("127.0.0.1:0", LocalRegistry::new(storage))
+//!     .expect("Could not start server");
+//!
+//! let registry = Registry::new(&server.url());
+//! assert_eq!(registry.catalog().unwrap().count(), 0);
+//! ```
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Method, StatusCode, Url};
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+pub mod auth;
+
+mod proxy;
+pub use proxy::ProxyRegistry;
+
+mod registry;
+pub use registry::LocalRegistry;
+
+mod storage;
+pub use storage::Storage;
+
+#[derive(Debug, Fail)]
+pub enum ServerError {
+    #[fail(display = "Could not start server: {}", _0)]
+    BindError(String),
+
+    #[fail(display = "Server is not listening on a TCP socket")]
+    NotTcp,
+}
+
+/// An HTTP request received by a [Server].
+pub struct Request<'a> {
+    pub method: Method,
+
+    /// The URL of the request, relative to the server.
+    pub url: Url,
+
+    pub headers: HeaderMap,
+
+    /// The body of the request.
+    pub body: &'a mut dyn Read,
+}
+
+impl<'a> std::fmt::Debug for Request<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Request {{ method: {}, url: {}, headers: {:?} }}",
+            self.method, self.url, self.headers
+        )
+    }
+}
+
+/// Body of a [Response].
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+
+    /// Serve `length` bytes from the current position of a file.
+    File(File, u64),
+
+    /// Stream a body of the given length, or of unknown length if `None`.
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File(file, length) => write!(f, "File({:?}, {})", file, length),
+            Body::Reader(_, length) => write!(f, "Reader({:?})", length),
+        }
+    }
+}
+
+impl Body {
+    /// Return the size of the body, if known.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, length) => Some(*length),
+            Body::Reader(_, length) => *length,
+        }
+    }
+}
+
+/// An HTTP response sent by a [Server].
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Body,
+}
+
+impl Response {
+    /// Create a response without headers and body.
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+        }
+    }
+
+    /// Create a response with a JSON body.
+    pub fn json<T: serde::Serialize>(status: StatusCode, value: &T) -> Self {
+        let body = serde_json::to_vec(value).expect("Could not serialize response");
+
+        Response::new(status)
+            .with_header(CONTENT_TYPE, "application/json")
+            .with_body(Body::Bytes(body))
+    }
+
+    /// Add a header.
+    ///
+    /// # Panics
+    /// If the value is not a valid header value.
+    pub fn with_header<V: AsRef<str>>(mut self, name: HeaderName, value: V) -> Self {
+        let value = HeaderValue::from_str(value.as_ref()).expect("Invalid header value");
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Body) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// Handles the requests received by a [Server].
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+/// An HTTP server, answering requests with a [Handler] on a background
+/// thread.
+///
+/// The server is shut down when it is dropped.
+pub struct Server {
+    server: Arc<tiny_http::Server>,
+    addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Server {{ addr: {} }}", self.addr)
+    }
+}
+
+impl Server {
+    /// Start serving requests on the given address.
+    ///
+    /// Bind to port 0 to use any free port, and use [Server::url] to find
+    /// out which port was chosen.
+    pub fn start<A, H>(addr: A, handler: H) -> Result<Self, ServerError>
+    where
+        A: ToSocketAddrs,
+        H: Handler,
+    {
+        let server = Arc::new(
+            tiny_http::Server::http(addr).map_err(|e| ServerError::BindError(e.to_string()))?,
+        );
+
+        let addr = server.server_addr().to_ip().ok_or(ServerError::NotTcp)?;
+
+        let handler = Arc::new(handler);
+        let thread = {
+            let server = server.clone();
+            std::thread::spawn(move || serve(&server, handler))
+        };
+
+        info!("Serving registry on {}", addr);
+
+        Ok(Server {
+            server,
+            addr,
+            thread: Some(thread),
+        })
+    }
+
+    /// Return the address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Return the base URL of the server, such as `http://127.0.0.1:5000`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Block until the server is shut down.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.server.unblock();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer requests until the server is unblocked, each on its own thread.
+fn serve<H: Handler>(server: &tiny_http::Server, handler: Arc<H>) {
+    for request in server.incoming_requests() {
+        let handler = handler.clone();
+        std::thread::spawn(move || respond(request, handler.as_ref()));
+    }
+}
+
+/// Answer a single request.
+fn respond<H: Handler>(mut request: tiny_http::Request, handler: &H) {
+    let method = request.method().as_str().parse().unwrap_or(Method::GET);
+
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(request.url())) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Invalid request URL {}: {}", request.url(), e);
+            let _ = request.respond(tiny_http::Response::empty(400));
+            return;
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    for header in request.headers() {
+        let name = HeaderName::from_bytes(header.field.as_str().as_str().as_bytes());
+        let value = HeaderValue::from_str(header.value.as_str());
+
+        if let (Ok(name), Ok(value)) = (name, value) {
+            headers.append(name, value);
+        }
+    }
+
+    debug!("{} {}", method, url);
+
+    let response = handler.handle(Request {
+        method,
+        url,
+        headers,
+        body: request.as_reader(),
+    });
+
+    debug!("{} {}", response.status, request.url());
+
+    let headers = response
+        .headers
+        .iter()
+        .filter(|(name, _)| *name != CONTENT_LENGTH)
+        .filter_map(|(name, value)| {
+            tiny_http::Header::from_bytes(name.as_str().as_bytes(), value.as_bytes()).ok()
+        })
+        .collect();
+
+    let length = response.body.size().map(|size| size as usize);
+    let body: Box<dyn Read + Send> = match response.body {
+        Body::Empty => Box::new(io::empty()),
+        Body::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+        Body::File(file, length) => Box::new(file.take(length)),
+        Body::Reader(reader, _) => reader,
+    };
+
+    let response =
+        tiny_http::Response::new(response.status.as_u16().into(), headers, body, length, None);
+
+    if let Err(e) = request.respond(response) {
+        warn!("Could not send response: {}", e);
+    }
+}
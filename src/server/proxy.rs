@@ -0,0 +1,620 @@
+//! A pull-through cache, serving content from a [Storage] and fetching it
+//! from an upstream [Registry] on a miss.
+
+use crate::distribution::errors::ErrorCode;
+use crate::distribution::{Registry, RegistryError, TagList};
+use crate::image::manifest::Digest;
+use crate::server::registry::{
+    paginate, parse_digest, validate_name, validate_reference, with_link, ApiError, Route,
+};
+use crate::server::storage::reference_digest;
+use crate::server::{Body, Handler, LocalRegistry, Request, Response, Storage};
+
+use reqwest::header::{HeaderName, CONTENT_TYPE, RANGE};
+use reqwest::{Method, StatusCode, Url};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a tag is served from the cache before it is revalidated, unless
+/// configured otherwise.
+const DEFAULT_TAG_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Size of the chunks blobs are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered for a slow client.
+const CHUNK_BUFFER: usize = 16;
+
+/// A read-only registry caching the content of an upstream registry.
+///
+/// Manifests and blobs are served from the storage if present, and fetched
+/// from upstream otherwise. Blobs are stored while they are streamed to the
+/// client. Content referenced by digest never changes, so it is served from
+/// the cache indefinitely, but tags are revalidated with a `HEAD` request
+/// once their TTL has expired. If upstream cannot be reached, stale tags are
+/// served from the cache.
+///
+/// Tag lists are fetched from upstream, everything else is answered from the
+/// cache.
+///
+/// # Example
+/// ```no_run
+///# extern crate opencontainers;
+/// use opencontainers::server::{ProxyRegistry, Server, Storage};
+/// use opencontainers::Registry;
+/// use std::time::Duration;
+///
+/// let storage = Storage::new("/var/lib/registry").expect("Could not open storage");
+/// let upstream = Registry::new("https://registry-1.docker.io");
+/// let proxy = ProxyRegistry::new(storage, upstream).tag_ttl(Duration::from_secs(3600));
+///
+/// Server::start("0.0.0.0:5000", proxy)
+///     .expect("Could not start server")
+///     .wait();
+/// ```
+#[derive(Debug)]
+pub struct ProxyRegistry {
+    local: LocalRegistry,
+    storage: Storage,
+    upstream: Arc<Registry>,
+    tag_ttl: Duration,
+    validated: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Handler for ProxyRegistry {
+    fn handle(&self, request: Request) -> Response {
+        let mut response = match self.route(request) {
+            Ok(response) => response,
+            Err(e) => e.into(),
+        };
+
+        response.headers.insert(
+            HeaderName::from_static("docker-distribution-api-version"),
+            "registry/2.0".parse().expect("Header value is valid"),
+        );
+
+        response
+    }
+}
+
+impl ProxyRegistry {
+    pub fn new(storage: Storage, upstream: Registry) -> Self {
+        ProxyRegistry {
+            local: LocalRegistry::new(storage.clone()),
+            storage,
+            upstream: Arc::new(upstream),
+            tag_ttl: DEFAULT_TAG_TTL,
+            validated: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set how long tags are served from the cache before they are
+    /// revalidated. Defaults to five minutes.
+    pub fn tag_ttl(mut self, ttl: Duration) -> Self {
+        self.tag_ttl = ttl;
+        self
+    }
+
+    /// Return the storage content is cached in.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Return the registry content is fetched from.
+    pub fn upstream(&self) -> &Registry {
+        &self.upstream
+    }
+
+    fn route(&self, request: Request) -> Result<Response, ApiError> {
+        let path = request.url.path().to_owned();
+        let route = match Route::parse(&path) {
+            Some(route) => route,
+            None => return Ok(self.local.handle(request)),
+        };
+
+        if let Some(name) = route.name() {
+            validate_name(name)?;
+        }
+
+        match (&request.method, route) {
+            (&Method::GET, Route::Base)
+            | (&Method::HEAD, Route::Base)
+            | (&Method::GET, Route::Catalog)
+            | (&Method::GET, Route::Referrers(..)) => Ok(self.local.handle(request)),
+            (&Method::GET, Route::Manifest(name, reference))
+            | (&Method::HEAD, Route::Manifest(name, reference)) => {
+                validate_reference(reference)?;
+                self.refresh_manifest(name, reference)?;
+                Ok(self.local.handle(request))
+            }
+            (&Method::GET, Route::Blob(name, digest))
+            | (&Method::HEAD, Route::Blob(name, digest)) => {
+                let digest = parse_digest(digest)?;
+
+                if self.storage.blob_exists(name, &digest) {
+                    return Ok(self.local.handle(request));
+                }
+
+                let blob = match self.fetch_blob(name, &digest)? {
+                    Some(blob) => blob,
+                    None => return Ok(self.local.handle(request)),
+                };
+
+                if request.method == Method::GET && !request.headers.contains_key(RANGE) {
+                    return Ok(Response::new(StatusCode::OK)
+                        .with_header(CONTENT_TYPE, "application/octet-stream")
+                        .with_header(
+                            HeaderName::from_static("docker-content-digest"),
+                            digest.to_string(),
+                        )
+                        .with_body(blob));
+                }
+
+                // Cache the whole blob before answering from the storage.
+                if let Body::Reader(mut reader, _) = blob {
+                    io::copy(&mut reader, &mut io::sink())?;
+                }
+
+                Ok(self.local.handle(request))
+            }
+            (&Method::GET, Route::Tags(name)) => match self.upstream.tags(name) {
+                Ok(tags) => {
+                    let tags = tags
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(upstream_error)?;
+
+                    Ok(self.tags(name, tags, &request.url))
+                }
+                Err(ref e) if is_not_found(e) => Ok(self.local.handle(request)),
+                Err(e) => {
+                    warn!("Serving cached tags of {}: {}", name, e);
+                    Ok(self.local.handle(request))
+                }
+            },
+            _ => Err(ApiError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                ErrorCode::Unsupported,
+                "the registry is a read-only cache",
+            )),
+        }
+    }
+
+    fn tags(&self, name: &str, mut tags: Vec<String>, url: &Url) -> Response {
+        tags.sort();
+        let (tags, link) = paginate(tags, url);
+
+        let response = Response::json(
+            StatusCode::OK,
+            &TagList {
+                name: name.to_owned(),
+                tags: Some(tags),
+            },
+        );
+
+        with_link(response, link)
+    }
+
+    /// Make sure the cached manifest for a reference is up to date.
+    ///
+    /// Manifests that are not found upstream are left for the local
+    /// registry to report.
+    fn refresh_manifest(&self, name: &str, reference: &str) -> Result<(), ApiError> {
+        let cached = self.storage.resolve(name, reference)?;
+
+        if let Some(digest) = reference_digest(reference) {
+            if cached.is_none() {
+                match self.fetch_manifest(name, &digest) {
+                    Ok((media_type, data)) => {
+                        self.storage
+                            .put_manifest(name, reference, &media_type, &data)?;
+                    }
+                    Err(ref e) if is_not_found(e) => {}
+                    Err(e) => return Err(upstream_error(e)),
+                }
+            }
+
+            return Ok(());
+        }
+
+        let key = (name.to_owned(), reference.to_owned());
+        if cached.is_some() && self.is_fresh(&key) {
+            return Ok(());
+        }
+
+        match self.upstream.resolve(name, reference) {
+            Ok(ref resolved) if Some(&resolved.digest) == cached.as_ref() => {}
+            Ok(resolved) => {
+                let (media_type, data) = match self.storage.get_manifest(name, &resolved.digest)? {
+                    Some(manifest) => manifest,
+                    None => self
+                        .fetch_manifest(name, &resolved.digest)
+                        .map_err(upstream_error)?,
+                };
+
+                debug!("Tag {}:{} is now {}", name, reference, resolved.digest);
+                self.storage
+                    .put_manifest(name, reference, &media_type, &data)?;
+            }
+            Err(ref e) if is_not_found(e) => {
+                self.storage.delete_tag(name, reference)?;
+            }
+            Err(e) if cached.is_some() => {
+                warn!("Serving stale tag {}:{}: {}", name, reference, e);
+                return Ok(());
+            }
+            Err(e) => return Err(upstream_error(e)),
+        }
+
+        self.validated
+            .lock()
+            .expect("Validation times poisoned")
+            .insert(key, Instant::now());
+
+        Ok(())
+    }
+
+    fn is_fresh(&self, key: &(String, String)) -> bool {
+        let validated = self.validated.lock().expect("Validation times poisoned");
+
+        matches!(validated.get(key), Some(time) if time.elapsed() < self.tag_ttl)
+    }
+
+    /// Fetch a manifest by digest, checking that it matches.
+    fn fetch_manifest(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<(String, Vec<u8>), RegistryError> {
+        let (media_type, data) = self.upstream.get_manifest_raw(name, &digest.to_string())?;
+
+        if !digest.verify(&data) {
+            return Err(RegistryError::DigestMismatch(
+                digest.clone(),
+                Digest::sha256(&data),
+            ));
+        }
+
+        Ok((media_type, data))
+    }
+
+    /// Start fetching a blob from upstream, storing it while it is read from
+    /// the returned body.
+    ///
+    /// The blob is fetched on a background thread, so it is cached even if
+    /// the client goes away. Returns `None` if the blob is not found
+    /// upstream.
+    fn fetch_blob(&self, name: &str, digest: &Digest) -> Result<Option<Body>, ApiError> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (chunks_tx, chunks_rx) = mpsc::sync_channel(CHUNK_BUFFER);
+
+        let upstream = self.upstream.clone();
+        let storage = self.storage.clone();
+        let name = name.to_owned();
+        let digest = digest.clone();
+
+        std::thread::spawn(move || {
+            let reader = match upstream.get_blob(&name, &digest) {
+                Ok(reader) => {
+                    let _ = started_tx.send(Ok(reader.size()));
+                    reader
+                }
+                Err(e) => {
+                    let _ = started_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            let mut tee = Tee {
+                reader,
+                chunks: Some(chunks_tx),
+            };
+
+            match cache_blob(&storage, &name, &digest, &mut tee) {
+                Ok(()) => info!("Cached blob {} of {}", digest, name),
+                Err(e) => {
+                    warn!("Could not cache blob {} of {}: {}", digest, name, e);
+                    tee.send(Err(e));
+                }
+            }
+        });
+
+        let size = match started_rx.recv() {
+            Ok(Ok(size)) => size,
+            Ok(Err(ref e)) if is_not_found(e) => return Ok(None),
+            Ok(Err(e)) => return Err(upstream_error(e)),
+            Err(_) => {
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Other("UNKNOWN".into()),
+                    "Blob download panicked",
+                ))
+            }
+        };
+
+        let reader = ChunkReader {
+            chunks: chunks_rx,
+            chunk: Cursor::new(Vec::new()),
+        };
+
+        Ok(Some(Body::Reader(Box::new(reader), size)))
+    }
+}
+
+/// Store a blob read from upstream in a new upload session.
+fn cache_blob(
+    storage: &Storage,
+    name: &str,
+    digest: &Digest,
+    data: &mut dyn Read,
+) -> io::Result<()> {
+    let uuid = storage.start_upload(name)?;
+
+    let result = storage
+        .append_upload(name, &uuid, data)
+        .and_then(|_| storage.finish_upload(name, &uuid, digest));
+
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Upstream blob does not match {}", digest),
+        )),
+        Err(e) => {
+            let _ = storage.cancel_upload(name, &uuid);
+            Err(e)
+        }
+    }
+}
+
+/// Reader passing everything it reads on to a client.
+///
+/// If the client goes away, reading continues so the blob is still cached.
+struct Tee<R> {
+    reader: R,
+    chunks: Option<mpsc::SyncSender<io::Result<Vec<u8>>>>,
+}
+
+impl<R> Tee<R> {
+    fn send(&mut self, chunk: io::Result<Vec<u8>>) {
+        let gone = match self.chunks {
+            Some(ref chunks) => chunks.send(chunk).is_err(),
+            None => false,
+        };
+
+        if gone {
+            debug!("Client went away, caching the rest of the blob");
+            self.chunks = None;
+        }
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        let n = self.reader.read(&mut buf[..len])?;
+
+        if n > 0 {
+            self.send(Ok(buf[..n].to_vec()));
+        }
+
+        Ok(n)
+    }
+}
+
+/// Reader for the chunks sent by a [Tee].
+struct ChunkReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+fn is_not_found(e: &RegistryError) -> bool {
+    match e {
+        RegistryError::Distribution(status, _) => *status == StatusCode::NOT_FOUND,
+        RegistryError::NotFound(_) => true,
+        _ => false,
+    }
+}
+
+fn upstream_error(e: RegistryError) -> ApiError {
+    warn!("Upstream error: {}", e);
+
+    ApiError::new(
+        StatusCode::BAD_GATEWAY,
+        ErrorCode::Other("UNKNOWN".into()),
+        format!("upstream registry: {}", e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::RetryPolicy;
+    use crate::server::Server;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    struct Fixture {
+        _dirs: (tempfile::TempDir, tempfile::TempDir),
+        _upstream: Server,
+        upstream_down: Arc<AtomicBool>,
+        upstream_registry: Registry,
+        _proxy: Server,
+        registry: Registry,
+        cache: Storage,
+    }
+
+    fn fixture(tag_ttl: Duration) -> Fixture {
+        let upstream_dir = tempfile::tempdir().unwrap();
+        let local = LocalRegistry::new(Storage::new(upstream_dir.path()).unwrap());
+        let upstream_down = Arc::new(AtomicBool::new(false));
+        let down = upstream_down.clone();
+        let upstream = Server::start("127.0.0.1:0", move |request: Request| {
+            if down.load(Ordering::SeqCst) {
+                Response::new(StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                local.handle(request)
+            }
+        })
+        .unwrap();
+        let upstream_registry = Registry::new(&upstream.url());
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = Storage::new(cache_dir.path()).unwrap();
+        let client = Registry::builder(&upstream.url())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+        let proxy = Server::start(
+            "127.0.0.1:0",
+            ProxyRegistry::new(cache.clone(), client).tag_ttl(tag_ttl),
+        )
+        .unwrap();
+        let registry = Registry::builder(&proxy.url())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        Fixture {
+            _dirs: (upstream_dir, cache_dir),
+            _upstream: upstream,
+            upstream_down,
+            upstream_registry,
+            _proxy: proxy,
+            registry,
+            cache,
+        }
+    }
+
+    /// Push an image with a single layer, returning the manifest digest.
+    fn push_image(registry: &Registry, tag: &str, layer: &[u8]) -> Digest {
+        let config = Digest::sha256(b"{}");
+        let layer_digest = Digest::sha256(layer);
+        registry.push_blob("foo", &config, b"{}").unwrap();
+        registry.push_blob("foo", &layer_digest, layer).unwrap();
+
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": layer_digest,
+                "size": layer.len(),
+            }],
+        }))
+        .unwrap();
+
+        registry
+            .push_manifest_raw("foo", tag, IMAGE_MANIFEST_MEDIA_TYPE, &manifest)
+            .unwrap()
+    }
+
+    fn get_blob(registry: &Registry, digest: &Digest) -> Vec<u8> {
+        let mut data = Vec::new();
+        registry
+            .get_blob("foo", digest)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_pull_through() {
+        let fixture = fixture(DEFAULT_TAG_TTL);
+        let digest = push_image(&fixture.upstream_registry, "latest", b"hello");
+        let layer = Digest::sha256(b"hello");
+
+        assert_eq!(
+            fixture.registry.resolve("foo", "latest").unwrap().digest,
+            digest
+        );
+        assert_eq!(get_blob(&fixture.registry, &layer), b"hello");
+        assert_eq!(
+            fixture.cache.resolve("foo", "latest").unwrap(),
+            Some(digest.clone())
+        );
+
+        let tags: Vec<_> = fixture
+            .registry
+            .tags("foo")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tags, vec!["latest"]);
+
+        // Cached content is served without upstream
+        fixture.upstream_down.store(true, Ordering::SeqCst);
+
+        let (_, data) = fixture.registry.get_manifest_raw("foo", "latest").unwrap();
+        assert!(digest.verify(&data));
+        assert_eq!(get_blob(&fixture.registry, &layer), b"hello");
+
+        let error = fixture
+            .registry
+            .get_blob("foo", &Digest::sha256(b"{}"))
+            .unwrap_err();
+        assert!(error.to_string().contains("502"), "{}", error);
+
+        // The cache is read-only
+        match fixture.registry.delete_manifest("foo", &digest) {
+            Err(RegistryError::DeletionDisabled) => {}
+            result => panic!("Deletion should be disabled: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_blob_unknown() {
+        let fixture = fixture(DEFAULT_TAG_TTL);
+
+        let error = fixture
+            .registry
+            .get_blob("foo", &Digest::sha256(b"hello"))
+            .unwrap_err();
+        assert!(error.has_code(&ErrorCode::BlobUnknown), "{}", error);
+    }
+
+    #[test]
+    fn test_tag_ttl() {
+        for &(ttl, revalidated) in &[(Duration::from_secs(0), true), (DEFAULT_TAG_TTL, false)] {
+            let fixture = fixture(ttl);
+            let old = push_image(&fixture.upstream_registry, "latest", b"hello");
+            assert_eq!(
+                fixture.registry.resolve("foo", "latest").unwrap().digest,
+                old
+            );
+
+            let new = push_image(&fixture.upstream_registry, "latest", b"world");
+            let expected = if revalidated { new } else { old };
+            assert_eq!(
+                fixture.registry.resolve("foo", "latest").unwrap().digest,
+                expected
+            );
+        }
+    }
+}
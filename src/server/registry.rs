@@ -0,0 +1,1030 @@
+//! The registry API, serving content from a [Storage].
+
+use crate::distribution::errors::{DistributionError, ErrorCode, ErrorResponse};
+use crate::distribution::{RepositoryList, TagList};
+use crate::image::manifest::{
+    Descriptor, Digest, ImageIndex, ManifestV2, IMAGE_INDEX_MEDIA_TYPE, MANIFEST_V2_MEDIA_TYPES,
+};
+use crate::server::storage::{is_valid_tag, reference_digest};
+use crate::server::{Body, Handler, Request, Response, Storage};
+
+use reqwest::header::{HeaderMap, HeaderName};
+use reqwest::header::{ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, LINK, LOCATION, RANGE};
+use reqwest::{Method, StatusCode, Url};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Largest manifest accepted by the registry.
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// An error response of the registry API.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+}
+
+impl ApiError {
+    pub(crate) fn new<M: Into<String>>(status: StatusCode, code: ErrorCode, message: M) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(code: ErrorCode, message: &str) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    fn bad_request(code: ErrorCode, message: &str) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, code, message)
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        warn!("Storage error: {}", e);
+
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Other("UNKNOWN".into()),
+            e.to_string(),
+        )
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(e: ApiError) -> Self {
+        let body = ErrorResponse {
+            errors: vec![DistributionError {
+                code: e.code,
+                message: Some(e.message),
+                detail: None,
+            }],
+        };
+
+        Response::json(e.status, &body)
+    }
+}
+
+/// The endpoints of the registry API.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Route<'a> {
+    Base,
+    Catalog,
+    Manifest(&'a str, &'a str),
+    Blob(&'a str, &'a str),
+    Upload(&'a str, Option<&'a str>),
+    Tags(&'a str),
+    Referrers(&'a str, &'a str),
+}
+
+impl<'a> Route<'a> {
+    /// Find the endpoint a path refers to.
+    pub(crate) fn parse(path: &'a str) -> Option<Self> {
+        let rest = match path {
+            "/v2" | "/v2/" => return Some(Route::Base),
+            "/v2/_catalog" => return Some(Route::Catalog),
+            _ => path.strip_prefix("/v2/")?,
+        };
+
+        if let Some(name) = rest
+            .strip_suffix("/blobs/uploads/")
+            .or_else(|| rest.strip_suffix("/blobs/uploads"))
+        {
+            return Some(Route::Upload(name, None));
+        }
+
+        if let Some(name) = rest.strip_suffix("/tags/list") {
+            return Some(Route::Tags(name));
+        }
+
+        let split = |endpoint: &str| {
+            rest.rfind(endpoint)
+                .map(|i| (&rest[..i], &rest[i + endpoint.len()..]))
+        };
+
+        if let Some((name, uuid)) = split("/blobs/uploads/") {
+            Some(Route::Upload(name, Some(uuid)))
+        } else if let Some((name, reference)) = split("/manifests/") {
+            Some(Route::Manifest(name, reference))
+        } else if let Some((name, digest)) = split("/blobs/") {
+            Some(Route::Blob(name, digest))
+        } else if let Some((name, digest)) = split("/referrers/") {
+            Some(Route::Referrers(name, digest))
+        } else {
+            None
+        }
+    }
+
+    /// Return the repository name the endpoint refers to.
+    pub(crate) fn name(&self) -> Option<&'a str> {
+        match *self {
+            Route::Base | Route::Catalog => None,
+            Route::Manifest(name, _)
+            | Route::Blob(name, _)
+            | Route::Upload(name, _)
+            | Route::Tags(name)
+            | Route::Referrers(name, _) => Some(name),
+        }
+    }
+}
+
+/// References of a manifest to other content, for any kind of manifest.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestReferences {
+    media_type: Option<String>,
+    artifact_type: Option<String>,
+    config: Option<Descriptor>,
+
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+
+    subject: Option<Descriptor>,
+    annotations: Option<HashMap<String, String>>,
+}
+
+/// A registry serving content from a local [Storage].
+#[derive(Debug)]
+pub struct LocalRegistry {
+    storage: Storage,
+}
+
+impl Handler for LocalRegistry {
+    fn handle(&self, request: Request) -> Response {
+        let mut response = match self.route(request) {
+            Ok(response) => response,
+            Err(e) => e.into(),
+        };
+
+        response.headers.insert(
+            HeaderName::from_static("docker-distribution-api-version"),
+            "registry/2.0".parse().expect("Header value is valid"),
+        );
+
+        response
+    }
+}
+
+impl LocalRegistry {
+    pub fn new(storage: Storage) -> Self {
+        LocalRegistry { storage }
+    }
+
+    /// Return the storage content is served from.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    fn route(&self, request: Request) -> Result<Response, ApiError> {
+        let path = request.url.path().to_owned();
+        let route = Route::parse(&path)
+            .ok_or_else(|| ApiError::not_found(ErrorCode::Unsupported, "unknown endpoint"))?;
+
+        if let Some(name) = route.name() {
+            validate_name(name)?;
+        }
+
+        let Request {
+            method,
+            url,
+            headers,
+            body,
+        } = request;
+
+        match (method, route) {
+            (Method::GET, Route::Base) | (Method::HEAD, Route::Base) => Ok(Response::json(
+                StatusCode::OK,
+                &HashMap::<String, String>::new(),
+            )),
+            (Method::GET, Route::Catalog) => self.catalog(&url),
+            (Method::GET, Route::Manifest(name, reference))
+            | (Method::HEAD, Route::Manifest(name, reference)) => {
+                self.get_manifest(name, reference)
+            }
+            (Method::PUT, Route::Manifest(name, reference)) => {
+                self.put_manifest(name, reference, &headers, body)
+            }
+            (Method::DELETE, Route::Manifest(name, reference)) => {
+                self.delete_manifest(name, reference)
+            }
+            (Method::GET, Route::Blob(name, digest))
+            | (Method::HEAD, Route::Blob(name, digest)) => {
+                self.get_blob(name, &parse_digest(digest)?, &headers)
+            }
+            (Method::DELETE, Route::Blob(name, digest)) => {
+                self.delete_blob(name, &parse_digest(digest)?)
+            }
+            (Method::POST, Route::Upload(name, None)) => self.start_upload(name, &url, body),
+            (method, Route::Upload(name, Some(uuid))) => {
+                validate_uuid(uuid)?;
+
+                match method {
+                    Method::GET => self.upload_status(name, uuid),
+                    Method::PATCH => self.patch_upload(name, uuid, &headers, body),
+                    Method::PUT => self.finish_upload(name, uuid, &url, body),
+                    Method::DELETE => self.cancel_upload(name, uuid),
+                    _ => Err(method_not_allowed()),
+                }
+            }
+            (Method::GET, Route::Tags(name)) => self.tags(name, &url),
+            (Method::GET, Route::Referrers(name, digest)) => {
+                self.referrers(name, &parse_digest(digest)?, &url)
+            }
+            _ => Err(method_not_allowed()),
+        }
+    }
+
+    fn catalog(&self, url: &Url) -> Result<Response, ApiError> {
+        let (repositories, link) = paginate(self.storage.repositories()?, url);
+
+        let response = Response::json(
+            StatusCode::OK,
+            &RepositoryList {
+                repositories: Some(repositories),
+            },
+        );
+
+        Ok(with_link(response, link))
+    }
+
+    fn get_manifest(&self, name: &str, reference: &str) -> Result<Response, ApiError> {
+        let unknown = || ApiError::not_found(ErrorCode::ManifestUnknown, "manifest unknown");
+
+        validate_reference(reference)?;
+        let digest = self.storage.resolve(name, reference)?.ok_or_else(unknown)?;
+        let (media_type, data) = self
+            .storage
+            .get_manifest(name, &digest)?
+            .ok_or_else(unknown)?;
+
+        Ok(Response::new(StatusCode::OK)
+            .with_header(CONTENT_TYPE, media_type)
+            .with_header(
+                HeaderName::from_static("docker-content-digest"),
+                digest.to_string(),
+            )
+            .with_body(Body::Bytes(data)))
+    }
+
+    fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        headers: &HeaderMap,
+        body: &mut dyn Read,
+    ) -> Result<Response, ApiError> {
+        validate_reference(reference)?;
+
+        let mut data = Vec::new();
+        body.take(MAX_MANIFEST_SIZE + 1).read_to_end(&mut data)?;
+
+        if data.len() as u64 > MAX_MANIFEST_SIZE {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                ErrorCode::SizeInvalid,
+                "manifest too large",
+            ));
+        }
+
+        let invalid = |message: &str| ApiError::bad_request(ErrorCode::ManifestInvalid, message);
+
+        let manifest: ManifestReferences =
+            serde_json::from_slice(&data).map_err(|e| invalid(&e.to_string()))?;
+
+        let media_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+            .or_else(|| manifest.media_type.clone())
+            .ok_or_else(|| invalid("missing media type"))?;
+
+        if MANIFEST_V2_MEDIA_TYPES.contains(&media_type.as_str()) {
+            String::from_utf8(data.clone())
+                .map_err(|e| invalid(&e.to_string()))?
+                .parse::<ManifestV2>()
+                .map_err(|e| invalid(&e.to_string()))?;
+        }
+
+        match reference_digest(reference) {
+            Some(digest) if !digest.verify(&data) => {
+                return Err(ApiError::bad_request(
+                    ErrorCode::DigestInvalid,
+                    "manifest does not match digest",
+                ))
+            }
+            _ => {}
+        }
+
+        let blobs = manifest.config.iter().chain(manifest.layers.iter());
+        for descriptor in blobs {
+            if !self.storage.blob_exists(name, &descriptor.digest) {
+                return Err(ApiError::bad_request(
+                    ErrorCode::ManifestBlobUnknown,
+                    &format!("blob unknown: {}", descriptor.digest),
+                ));
+            }
+        }
+
+        for descriptor in &manifest.manifests {
+            let digest = descriptor.digest.to_string();
+            if self.storage.resolve(name, &digest)?.is_none() {
+                return Err(ApiError::bad_request(
+                    ErrorCode::ManifestBlobUnknown,
+                    &format!("manifest unknown: {}", digest),
+                ));
+            }
+        }
+
+        let digest = self
+            .storage
+            .put_manifest(name, reference, &media_type, &data)?;
+
+        let mut response = Response::new(StatusCode::CREATED)
+            .with_header(LOCATION, format!("/v2/{}/manifests/{}", name, digest))
+            .with_header(
+                HeaderName::from_static("docker-content-digest"),
+                digest.to_string(),
+            );
+
+        if let Some(subject) = manifest.subject {
+            response = response.with_header(
+                HeaderName::from_static("oci-subject"),
+                subject.digest.to_string(),
+            );
+        }
+
+        Ok(response)
+    }
+
+    fn delete_manifest(&self, name: &str, reference: &str) -> Result<Response, ApiError> {
+        validate_reference(reference)?;
+
+        let deleted = match reference_digest(reference) {
+            Some(digest) => self.storage.delete_manifest(name, &digest)?,
+            None => self.storage.delete_tag(name, reference)?,
+        };
+
+        if !deleted {
+            return Err(ApiError::not_found(
+                ErrorCode::ManifestUnknown,
+                "manifest unknown",
+            ));
+        }
+
+        Ok(Response::new(StatusCode::ACCEPTED))
+    }
+
+    fn get_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+        headers: &HeaderMap,
+    ) -> Result<Response, ApiError> {
+        let (mut file, size) = self
+            .storage
+            .open_blob(name, digest)?
+            .ok_or_else(|| ApiError::not_found(ErrorCode::BlobUnknown, "blob unknown"))?;
+
+        let response = Response::new(StatusCode::OK)
+            .with_header(CONTENT_TYPE, "application/octet-stream")
+            .with_header(ACCEPT_RANGES, "bytes")
+            .with_header(
+                HeaderName::from_static("docker-content-digest"),
+                digest.to_string(),
+            );
+
+        let range = headers
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_range);
+
+        let (start, end) = match range {
+            None => return Ok(response.with_body(Body::File(file, size))),
+            Some((start, _)) if start >= size => {
+                return Ok(Response::new(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .with_header(CONTENT_RANGE, format!("bytes */{}", size)))
+            }
+            Some((start, end)) => (start, end.unwrap_or(size - 1).min(size - 1)),
+        };
+
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut response = response
+            .with_header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+            .with_body(Body::File(file, end - start + 1));
+        response.status = StatusCode::PARTIAL_CONTENT;
+
+        Ok(response)
+    }
+
+    fn delete_blob(&self, name: &str, digest: &Digest) -> Result<Response, ApiError> {
+        if !self.storage.delete_blob(name, digest)? {
+            return Err(ApiError::not_found(ErrorCode::BlobUnknown, "blob unknown"));
+        }
+
+        Ok(Response::new(StatusCode::ACCEPTED))
+    }
+
+    fn start_upload(
+        &self,
+        name: &str,
+        url: &Url,
+        body: &mut dyn Read,
+    ) -> Result<Response, ApiError> {
+        let mount = query(url, "mount").map(|digest| parse_digest(&digest));
+        let from = query(url, "from");
+
+        if let (Some(digest), Some(from)) = (mount, from) {
+            let digest = digest?;
+            validate_name(&from)?;
+
+            if self.storage.mount_blob(name, &digest, &from)? {
+                return Ok(blob_created(name, &digest));
+            }
+        }
+
+        let uuid = self.storage.start_upload(name)?;
+
+        // Monolithic upload in a single request
+        if let Some(digest) = query(url, "digest") {
+            let digest = parse_digest(&digest)?;
+            self.storage.append_upload(name, &uuid, body)?;
+            return self.complete_upload(name, &uuid, &digest);
+        }
+
+        Ok(upload_accepted(name, &uuid, 0))
+    }
+
+    fn upload_size(&self, name: &str, uuid: &str) -> Result<u64, ApiError> {
+        self.storage
+            .upload_size(name, uuid)?
+            .ok_or_else(|| ApiError::not_found(ErrorCode::BlobUploadUnknown, "blob upload unknown"))
+    }
+
+    fn upload_status(&self, name: &str, uuid: &str) -> Result<Response, ApiError> {
+        let size = self.upload_size(name, uuid)?;
+
+        let mut response = upload_accepted(name, uuid, size);
+        response.status = StatusCode::NO_CONTENT;
+        Ok(response)
+    }
+
+    fn patch_upload(
+        &self,
+        name: &str,
+        uuid: &str,
+        headers: &HeaderMap,
+        body: &mut dyn Read,
+    ) -> Result<Response, ApiError> {
+        let size = self.upload_size(name, uuid)?;
+
+        let start = headers
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.split('-').next())
+            .and_then(|start| start.trim().parse::<u64>().ok());
+
+        if let Some(start) = start {
+            if start != size {
+                return Err(ApiError::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    ErrorCode::BlobUploadInvalid,
+                    format!("expected chunk starting at {}", size),
+                ));
+            }
+        }
+
+        let size = self.storage.append_upload(name, uuid, body)?;
+
+        Ok(upload_accepted(name, uuid, size))
+    }
+
+    fn finish_upload(
+        &self,
+        name: &str,
+        uuid: &str,
+        url: &Url,
+        body: &mut dyn Read,
+    ) -> Result<Response, ApiError> {
+        self.upload_size(name, uuid)?;
+
+        let digest = query(url, "digest")
+            .ok_or_else(|| ApiError::bad_request(ErrorCode::DigestInvalid, "missing digest"))
+            .and_then(|digest| parse_digest(&digest))?;
+
+        self.storage.append_upload(name, uuid, body)?;
+        self.complete_upload(name, uuid, &digest)
+    }
+
+    fn complete_upload(
+        &self,
+        name: &str,
+        uuid: &str,
+        digest: &Digest,
+    ) -> Result<Response, ApiError> {
+        if !self.storage.finish_upload(name, uuid, digest)? {
+            return Err(ApiError::bad_request(
+                ErrorCode::DigestInvalid,
+                "content does not match digest",
+            ));
+        }
+
+        Ok(blob_created(name, digest))
+    }
+
+    fn cancel_upload(&self, name: &str, uuid: &str) -> Result<Response, ApiError> {
+        if !self.storage.cancel_upload(name, uuid)? {
+            return Err(ApiError::not_found(
+                ErrorCode::BlobUploadUnknown,
+                "blob upload unknown",
+            ));
+        }
+
+        Ok(Response::new(StatusCode::NO_CONTENT))
+    }
+
+    fn tags(&self, name: &str, url: &Url) -> Result<Response, ApiError> {
+        if !self.storage.repository_exists(name) {
+            return Err(ApiError::not_found(
+                ErrorCode::NameUnknown,
+                "repository name not known to registry",
+            ));
+        }
+
+        let (tags, link) = paginate(self.storage.tags(name)?, url);
+
+        let response = Response::json(
+            StatusCode::OK,
+            &TagList {
+                name: name.to_owned(),
+                tags: Some(tags),
+            },
+        );
+
+        Ok(with_link(response, link))
+    }
+
+    fn referrers(&self, name: &str, digest: &Digest, url: &Url) -> Result<Response, ApiError> {
+        let artifact_type = query(url, "artifactType");
+        let mut referrers = Vec::new();
+
+        for manifest_digest in self.storage.manifests(name)? {
+            let (media_type, data) = match self.storage.get_manifest(name, &manifest_digest)? {
+                Some(manifest) => manifest,
+                None => continue,
+            };
+
+            let manifest: ManifestReferences = match serde_json::from_slice(&data) {
+                Ok(manifest) => manifest,
+                Err(_) => continue,
+            };
+
+            if manifest.subject.as_ref().map(|s| &s.digest) != Some(digest) {
+                continue;
+            }
+
+            // Manifests without an artifact type are typed by their config
+            let config_type = manifest.config.map(|config| config.media_type);
+
+            let descriptor = Descriptor {
+                media_type,
+                digest: manifest_digest,
+                size: data.len(),
+                artifact_type: manifest.artifact_type.or(config_type),
+                annotations: manifest.annotations,
+            };
+
+            if artifact_type.is_none() || descriptor.artifact_type == artifact_type {
+                referrers.push(descriptor);
+            }
+        }
+
+        referrers.sort_by_key(|descriptor| descriptor.digest.to_string());
+
+        let index = ImageIndex {
+            schema_version: 2,
+            media_type: Some(IMAGE_INDEX_MEDIA_TYPE.into()),
+            manifests: referrers,
+            annotations: None,
+        };
+
+        let mut response = Response::json(StatusCode::OK, &index)
+            .with_header(CONTENT_TYPE, IMAGE_INDEX_MEDIA_TYPE);
+
+        if artifact_type.is_some() {
+            response = response.with_header(
+                HeaderName::from_static("oci-filters-applied"),
+                "artifactType",
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+fn method_not_allowed() -> ApiError {
+    ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        ErrorCode::Unsupported,
+        "the operation is unsupported",
+    )
+}
+
+/// Response to a completed blob upload or mount.
+fn blob_created(name: &str, digest: &Digest) -> Response {
+    Response::new(StatusCode::CREATED)
+        .with_header(LOCATION, format!("/v2/{}/blobs/{}", name, digest))
+        .with_header(
+            HeaderName::from_static("docker-content-digest"),
+            digest.to_string(),
+        )
+}
+
+/// Response to a request to an upload session that has received `size`
+/// bytes.
+fn upload_accepted(name: &str, uuid: &str, size: u64) -> Response {
+    Response::new(StatusCode::ACCEPTED)
+        .with_header(LOCATION, format!("/v2/{}/blobs/uploads/{}", name, uuid))
+        .with_header(RANGE, format!("0-{}", size.saturating_sub(1)))
+        .with_header(HeaderName::from_static("docker-upload-uuid"), uuid)
+}
+
+/// Return the value of a query parameter.
+pub(crate) fn query(url: &Url, key: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Apply the `n` and `last` query parameters to a sorted list, returning
+/// the page and the `Link` to the next page, if any.
+pub(crate) fn paginate(entries: Vec<String>, url: &Url) -> (Vec<String>, Option<String>) {
+    let last = query(url, "last");
+    let n = query(url, "n").and_then(|n| n.parse::<usize>().ok());
+
+    let mut entries: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| !matches!(last, Some(ref last) if entry <= last))
+        .collect();
+
+    let n = match n {
+        Some(n) if n < entries.len() => n,
+        _ => return (entries, None),
+    };
+
+    entries.truncate(n);
+
+    let mut next = Url::parse("http://localhost").expect("URL is valid");
+    next.set_path(url.path());
+    next.query_pairs_mut()
+        .append_pair("n", &n.to_string())
+        .append_pair("last", entries.last().map_or("", String::as_str));
+
+    let link = format!(
+        "<{}?{}>; rel=\"next\"",
+        next.path(),
+        next.query().unwrap_or_default()
+    );
+
+    (entries, Some(link))
+}
+
+pub(crate) fn with_link(response: Response, link: Option<String>) -> Response {
+    match link {
+        Some(link) => response.with_header(LINK, link),
+        None => response,
+    }
+}
+
+/// Parse a `Range` header such as `bytes=100-` or `bytes=100-199`.
+fn parse_range(range: &str) -> Option<(u64, Option<u64>)> {
+    let mut bounds = range.strip_prefix("bytes=")?.splitn(2, '-');
+    let start = bounds.next()?.trim().parse().ok()?;
+    let end = match bounds.next()?.trim() {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+
+    match end {
+        Some(end) if end < start => None,
+        _ => Some((start, end)),
+    }
+}
+
+/// Parse a digest, rejecting anything that is not exactly a digest.
+pub(crate) fn parse_digest(digest: &str) -> Result<Digest, ApiError> {
+    reference_digest(digest)
+        .ok_or_else(|| ApiError::bad_request(ErrorCode::DigestInvalid, "invalid digest"))
+}
+
+/// Check that a repository name matches the grammar of the spec.
+pub(crate) fn validate_name(name: &str) -> Result<(), ApiError> {
+    let valid_component = |component: &str| {
+        let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+        component.starts_with(alphanumeric)
+            && component.ends_with(alphanumeric)
+            && component
+                .chars()
+                .all(|c| alphanumeric(c) || c == '.' || c == '_' || c == '-')
+    };
+
+    if name.len() > 255 || !name.split('/').all(valid_component) {
+        return Err(ApiError::bad_request(
+            ErrorCode::NameInvalid,
+            "invalid repository name",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that a manifest reference is either a digest or a tag matching
+/// the grammar of the spec.
+pub(crate) fn validate_reference(reference: &str) -> Result<(), ApiError> {
+    if reference_digest(reference).is_some() || is_valid_tag(reference) {
+        return Ok(());
+    }
+
+    Err(ApiError::bad_request(ErrorCode::TagInvalid, "invalid tag"))
+}
+
+/// Check that an upload ID was issued by the storage.
+fn validate_uuid(uuid: &str) -> Result<(), ApiError> {
+    if uuid.is_empty() || !uuid.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return Err(ApiError::not_found(
+            ErrorCode::BlobUploadUnknown,
+            "blob upload unknown",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distribution::Registry;
+    use crate::server::Server;
+
+    use std::io::Cursor;
+
+    const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    fn server() -> (tempfile::TempDir, Server, Registry) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let server = Server::start("127.0.0.1:0", LocalRegistry::new(storage)).unwrap();
+        let registry = Registry::new(&server.url());
+        (dir, server, registry)
+    }
+
+    fn image_manifest(config: &Digest, layer: &Digest, subject: Option<&Digest>) -> Vec<u8> {
+        let mut manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+            "config": {
+                "mediaType": "application/vnd.example.config.v1+json",
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": layer,
+                "size": 5,
+            }],
+        });
+
+        if let Some(subject) = subject {
+            manifest["subject"] = serde_json::json!({
+                "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+                "digest": subject,
+                "size": 1,
+            });
+        }
+
+        serde_json::to_vec(&manifest).unwrap()
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(Route::parse("/v2/"), Some(Route::Base));
+        assert_eq!(
+            Route::parse("/v2/foo/bar/manifests/latest"),
+            Some(Route::Manifest("foo/bar", "latest"))
+        );
+        assert_eq!(
+            Route::parse("/v2/foo/blobs/uploads/"),
+            Some(Route::Upload("foo", None))
+        );
+        assert_eq!(
+            Route::parse("/v2/foo/blobs/uploads/1234"),
+            Some(Route::Upload("foo", Some("1234")))
+        );
+        assert_eq!(
+            Route::parse("/v2/foo/blobs/sha256:abcd"),
+            Some(Route::Blob("foo", "sha256:abcd"))
+        );
+        assert_eq!(Route::parse("/v2/foo/tags/list"), Some(Route::Tags("foo")));
+        assert_eq!(Route::parse("/v1/foo"), None);
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("library/hello-world").is_ok());
+        assert!(validate_name("a.b_c/d").is_ok());
+        assert!(validate_name("Foo").is_err());
+        assert!(validate_name("foo/../bar").is_err());
+        assert!(validate_name("foo//bar").is_err());
+        assert!(validate_name("_foo").is_err());
+    }
+
+    #[test]
+    fn test_push_pull() {
+        let (_dir, _server, registry) = server();
+
+        let config = Digest::sha256(b"{}");
+        let layer = Digest::sha256(b"hello");
+        registry.push_blob("foo", &config, b"{}").unwrap();
+        registry
+            .push_blob_chunked("foo", &layer, Cursor::new(b"hello"), 2)
+            .unwrap();
+        assert!(registry.blob_exists("foo", &layer).unwrap());
+
+        let mut data = Vec::new();
+        registry
+            .get_blob("foo", &layer)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"hello");
+
+        let manifest = image_manifest(&config, &layer, None);
+        let digest = registry
+            .push_manifest_raw("foo", "latest", IMAGE_MANIFEST_MEDIA_TYPE, &manifest)
+            .unwrap();
+        assert!(digest.verify(&manifest));
+
+        let (media_type, data) = registry.get_manifest_raw("foo", "latest").unwrap();
+        assert_eq!(media_type, IMAGE_MANIFEST_MEDIA_TYPE);
+        assert_eq!(data, manifest);
+        assert_eq!(registry.resolve("foo", "latest").unwrap().digest, digest);
+
+        registry.tag("foo", "latest", "stable").unwrap();
+        let tags: Vec<_> = registry
+            .tags_paginated("foo", Some(1), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tags, vec!["latest", "stable"]);
+
+        registry.copy_blob("bar", &layer, "foo", 1024).unwrap();
+        let repositories: Vec<_> = registry
+            .catalog()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(repositories, vec!["bar", "foo"]);
+
+        registry.delete_manifest("foo", &digest).unwrap();
+        let error = registry.get_manifest_raw("foo", "stable").unwrap_err();
+        assert!(error.has_code(&ErrorCode::ManifestUnknown));
+
+        registry.delete_blob("foo", &layer).unwrap();
+        assert!(!registry.blob_exists("foo", &layer).unwrap());
+        assert!(registry.blob_exists("bar", &layer).unwrap());
+    }
+
+    #[test]
+    fn test_manifest_blob_unknown() {
+        let (_dir, _server, registry) = server();
+
+        let manifest = image_manifest(&Digest::sha256(b"{}"), &Digest::sha256(b"hello"), None);
+        let error = registry
+            .push_manifest_raw("foo", "latest", IMAGE_MANIFEST_MEDIA_TYPE, &manifest)
+            .unwrap_err();
+        assert!(error.has_code(&ErrorCode::ManifestBlobUnknown));
+    }
+
+    #[test]
+    fn test_invalid_tag() {
+        let (dir, _server, registry) = server();
+
+        let error = registry.get_manifest_raw("foo", ".hidden").unwrap_err();
+        assert!(error.has_code(&ErrorCode::TagInvalid));
+
+        let reference = format!("{}junk", Digest::sha256(b"{}"));
+        let error = registry.get_manifest_raw("foo", &reference).unwrap_err();
+        assert!(error.has_code(&ErrorCode::TagInvalid));
+
+        let manifest = image_manifest(&Digest::sha256(b"{}"), &Digest::sha256(b"hello"), None);
+        let error = registry
+            .push_manifest_raw("foo", "-latest", IMAGE_MANIFEST_MEDIA_TYPE, &manifest)
+            .unwrap_err();
+        assert!(error.has_code(&ErrorCode::TagInvalid));
+
+        let local = LocalRegistry::new(Storage::new(dir.path()).unwrap());
+        let response = local.handle(Request {
+            method: Method::DELETE,
+            url: "http://localhost/v2/foo/manifests/-latest".parse().unwrap(),
+            headers: HeaderMap::new(),
+            body: &mut std::io::empty(),
+        });
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_invalid_digest() {
+        let (_dir, _server, registry) = server();
+
+        let error = registry
+            .push_blob("foo", &Digest::sha256(b"hello"), b"world")
+            .unwrap_err();
+        assert!(error.has_code(&ErrorCode::DigestInvalid));
+    }
+
+    #[test]
+    fn test_referrers() {
+        let (_dir, _server, registry) = server();
+
+        let config = Digest::sha256(b"{}");
+        let layer = Digest::sha256(b"hello");
+        registry.push_blob("foo", &config, b"{}").unwrap();
+        registry.push_blob("foo", &layer, b"hello").unwrap();
+
+        let manifest = image_manifest(&config, &layer, None);
+        let subject = registry
+            .push_manifest_raw("foo", "latest", IMAGE_MANIFEST_MEDIA_TYPE, &manifest)
+            .unwrap();
+
+        let signature = image_manifest(&config, &layer, Some(&subject));
+        let digest = Digest::sha256(&signature);
+        registry
+            .push_manifest_raw(
+                "foo",
+                &digest.to_string(),
+                IMAGE_MANIFEST_MEDIA_TYPE,
+                &signature,
+            )
+            .unwrap();
+
+        let referrers = registry.referrers("foo", &subject, None).unwrap();
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].digest, digest);
+        assert_eq!(
+            referrers[0].artifact_type.as_deref(),
+            Some("application/vnd.example.config.v1+json")
+        );
+
+        let referrers = registry
+            .referrers("foo", &subject, Some("application/vnd.example.other"))
+            .unwrap();
+        assert!(referrers.is_empty());
+    }
+
+    #[test]
+    fn test_blob_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = LocalRegistry::new(Storage::new(dir.path()).unwrap());
+        let digest = Digest::sha256(b"hello world");
+
+        let uuid = registry.storage().start_upload("foo").unwrap();
+        registry
+            .storage()
+            .append_upload("foo", &uuid, &mut &b"hello world"[..])
+            .unwrap();
+        registry
+            .storage()
+            .finish_upload("foo", &uuid, &digest)
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, "bytes=6-".parse().unwrap());
+
+        let response = registry.handle(Request {
+            method: Method::GET,
+            url: format!("http://localhost/v2/foo/blobs/{}", digest)
+                .parse()
+                .unwrap(),
+            headers,
+            body: &mut io::empty(),
+        });
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers[CONTENT_RANGE], "bytes 6-10/11");
+
+        let mut data = String::new();
+        match response.body {
+            Body::File(file, length) => file.take(length).read_to_string(&mut data).unwrap(),
+            body => panic!("Unexpected body {:?}", body),
+        };
+        assert_eq!(data, "world");
+    }
+}
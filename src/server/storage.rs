@@ -0,0 +1,512 @@
+//! Content storage in a local directory.
+//!
+//! The directory is laid out as follows:
+//!
+//! ```text
+//! blobs/<algorithm>/<hex>                          content of blobs and manifests
+//! repositories/<name>/_blobs/<algorithm>/<hex>     blobs linked into a repository
+//! repositories/<name>/_manifests/<algorithm>/<hex> media types of manifests
+//! repositories/<name>/_tags/<tag>                  digests of tagged manifests
+//! repositories/<name>/_uploads/<uuid>              data of unfinished uploads
+//! ```
+//!
+//! Since path components of repository names always start with a letter or
+//! digit, they can not collide with the directories starting with `_`.
+
+use crate::image::manifest::{Digest, DigestAlgorithm};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Content of a registry, stored in a local directory.
+///
+/// Clones refer to the same directory.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: PathBuf,
+    ids: Arc<AtomicUsize>,
+}
+
+impl Storage {
+    /// Open the storage in a directory, creating it if necessary.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().to_owned();
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("repositories"))?;
+
+        Ok(Storage {
+            root,
+            ids: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Return the directory the content is stored in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join(digest.algorithm.to_string())
+            .join(&digest.hex)
+    }
+
+    fn repository_path(&self, name: &str) -> PathBuf {
+        self.root.join("repositories").join(name)
+    }
+
+    fn link_path(&self, name: &str, kind: &str, digest: &Digest) -> PathBuf {
+        self.repository_path(name)
+            .join(kind)
+            .join(digest.algorithm.to_string())
+            .join(&digest.hex)
+    }
+
+    fn tag_path(&self, name: &str, tag: &str) -> PathBuf {
+        self.repository_path(name).join("_tags").join(tag)
+    }
+
+    fn upload_path(&self, name: &str, uuid: &str) -> PathBuf {
+        self.repository_path(name).join("_uploads").join(uuid)
+    }
+
+    /// Check whether a repository exists.
+    pub fn repository_exists(&self, name: &str) -> bool {
+        ["_blobs", "_manifests", "_tags", "_uploads"]
+            .iter()
+            .any(|kind| self.repository_path(name).join(kind).is_dir())
+    }
+
+    /// List all repositories, sorted by name.
+    pub fn repositories(&self) -> io::Result<Vec<String>> {
+        let mut repositories = Vec::new();
+        self.find_repositories(&self.root.join("repositories"), "", &mut repositories)?;
+        repositories.sort();
+        Ok(repositories)
+    }
+
+    fn find_repositories(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        repositories: &mut Vec<String>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+
+            if file_name.starts_with('_') || !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = format!("{}{}", prefix, file_name);
+            if self.repository_exists(&name) {
+                repositories.push(name.clone());
+            }
+
+            self.find_repositories(&entry.path(), &format!("{}/", name), repositories)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether a blob exists in a repository.
+    pub fn blob_exists(&self, name: &str, digest: &Digest) -> bool {
+        self.link_path(name, "_blobs", digest).exists() && self.blob_path(digest).exists()
+    }
+
+    /// Open a blob in a repository, returning the file and its size.
+    pub fn open_blob(&self, name: &str, digest: &Digest) -> io::Result<Option<(File, u64)>> {
+        if !self.blob_exists(name, digest) {
+            return Ok(None);
+        }
+
+        let file = File::open(self.blob_path(digest))?;
+        let size = file.metadata()?.len();
+        Ok(Some((file, size)))
+    }
+
+    /// Make a blob stored for another repository available in a repository.
+    ///
+    /// Returns `false` if the blob does not exist in the other repository.
+    pub fn mount_blob(&self, name: &str, digest: &Digest, from: &str) -> io::Result<bool> {
+        if !self.blob_exists(from, digest) {
+            return Ok(false);
+        }
+
+        self.link(name, "_blobs", digest, b"")?;
+        Ok(true)
+    }
+
+    /// Remove a blob from a repository.
+    ///
+    /// The content is kept, since other repositories may refer to it.
+    pub fn delete_blob(&self, name: &str, digest: &Digest) -> io::Result<bool> {
+        remove_if_exists(&self.link_path(name, "_blobs", digest))
+    }
+
+    /// Store content, returning its digest.
+    fn store(&self, data: &[u8]) -> io::Result<Digest> {
+        let digest = Digest::sha256(data);
+        let path = self.blob_path(&digest);
+
+        if !path.exists() {
+            let temp = self.temp_path(&path);
+            fs::create_dir_all(path.parent().expect("Blob path has a parent"))?;
+            fs::write(&temp, data)?;
+            fs::rename(&temp, &path)?;
+        }
+
+        Ok(digest)
+    }
+
+    /// Return a unique path next to a file, to write to before renaming.
+    fn temp_path(&self, path: &Path) -> PathBuf {
+        path.with_extension(format!("tmp-{}", self.unique_id()))
+    }
+
+    fn unique_id(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let count = self.ids.fetch_add(1, Ordering::SeqCst);
+
+        format!("{:x}-{:x}-{:x}", now.as_secs(), now.subsec_nanos(), count)
+    }
+
+    /// Create a link file in a repository.
+    fn link(&self, name: &str, kind: &str, digest: &Digest, data: &[u8]) -> io::Result<()> {
+        let path = self.link_path(name, kind, digest);
+        fs::create_dir_all(path.parent().expect("Link path has a parent"))?;
+        fs::write(path, data)
+    }
+
+    /// Start an upload session, returning its ID.
+    pub fn start_upload(&self, name: &str) -> io::Result<String> {
+        let uuid = self.unique_id();
+        let path = self.upload_path(name, &uuid);
+
+        fs::create_dir_all(path.parent().expect("Upload path has a parent"))?;
+        File::create(path)?;
+
+        Ok(uuid)
+    }
+
+    /// Return the number of bytes received by an upload session, or `None`
+    /// if it does not exist.
+    pub fn upload_size(&self, name: &str, uuid: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.upload_path(name, uuid)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Append data to an upload session, returning its new size.
+    pub fn append_upload(&self, name: &str, uuid: &str, data: &mut dyn Read) -> io::Result<u64> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.upload_path(name, uuid))?;
+
+        io::copy(data, &mut file)?;
+        file.flush()?;
+
+        Ok(file.metadata()?.len())
+    }
+
+    /// Complete an upload session, storing the data if it matches the
+    /// digest.
+    ///
+    /// Returns `false` and discards the upload if the digest does not
+    /// match.
+    pub fn finish_upload(&self, name: &str, uuid: &str, digest: &Digest) -> io::Result<bool> {
+        let upload = self.upload_path(name, uuid);
+
+        if digest_file(&upload, digest.algorithm)? != *digest {
+            fs::remove_file(&upload)?;
+            return Ok(false);
+        }
+
+        let path = self.blob_path(digest);
+        fs::create_dir_all(path.parent().expect("Blob path has a parent"))?;
+        fs::rename(&upload, &path)?;
+        self.link(name, "_blobs", digest, b"")?;
+
+        Ok(true)
+    }
+
+    /// Cancel an upload session.
+    pub fn cancel_upload(&self, name: &str, uuid: &str) -> io::Result<bool> {
+        remove_if_exists(&self.upload_path(name, uuid))
+    }
+
+    /// Store a manifest in a repository, tagging it if the reference is a
+    /// tag.
+    pub fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> io::Result<Digest> {
+        let is_tag = reference_digest(reference).is_none();
+        if is_tag && !is_valid_tag(reference) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid tag"));
+        }
+
+        let digest = self.store(data)?;
+        self.link(name, "_blobs", &digest, b"")?;
+        self.link(name, "_manifests", &digest, media_type.as_bytes())?;
+
+        if is_tag {
+            let path = self.tag_path(name, reference);
+            fs::create_dir_all(path.parent().expect("Tag path has a parent"))?;
+            fs::write(path, digest.to_string())?;
+        }
+
+        Ok(digest)
+    }
+
+    /// Resolve a tag or digest to the digest of a manifest in a repository.
+    ///
+    /// Invalid tags never resolve to a manifest.
+    pub fn resolve(&self, name: &str, reference: &str) -> io::Result<Option<Digest>> {
+        let digest = match reference_digest(reference) {
+            Some(digest) => digest,
+            None if !is_valid_tag(reference) => return Ok(None),
+            None => match fs::read_to_string(self.tag_path(name, reference)) {
+                Ok(digest) => digest
+                    .trim()
+                    .parse::<Digest>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            },
+        };
+
+        if self.link_path(name, "_manifests", &digest).exists() {
+            Ok(Some(digest))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Return the media type and content of a manifest.
+    pub fn get_manifest(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> io::Result<Option<(String, Vec<u8>)>> {
+        let media_type = match fs::read_to_string(self.link_path(name, "_manifests", digest)) {
+            Ok(media_type) => media_type,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let data = fs::read(self.blob_path(digest))?;
+        Ok(Some((media_type, data)))
+    }
+
+    /// List the digests of all manifests in a repository.
+    pub fn manifests(&self, name: &str) -> io::Result<Vec<Digest>> {
+        let dir = self.repository_path(name).join("_manifests");
+        let mut digests = Vec::new();
+
+        for algorithm in read_dir_names(&dir)? {
+            for hex in read_dir_names(&dir.join(&algorithm))? {
+                if let Ok(digest) = format!("{}:{}", algorithm, hex).parse() {
+                    digests.push(digest);
+                }
+            }
+        }
+
+        Ok(digests)
+    }
+
+    /// Delete a manifest and all tags referring to it.
+    pub fn delete_manifest(&self, name: &str, digest: &Digest) -> io::Result<bool> {
+        if !remove_if_exists(&self.link_path(name, "_manifests", digest))? {
+            return Ok(false);
+        }
+
+        for tag in self.tags(name)? {
+            if self.resolve(name, &tag)?.is_none() {
+                remove_if_exists(&self.tag_path(name, &tag))?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Delete a tag, keeping the manifest.
+    pub fn delete_tag(&self, name: &str, tag: &str) -> io::Result<bool> {
+        if !is_valid_tag(tag) {
+            return Ok(false);
+        }
+
+        remove_if_exists(&self.tag_path(name, tag))
+    }
+
+    /// List the tags of a repository, sorted by name.
+    pub fn tags(&self, name: &str) -> io::Result<Vec<String>> {
+        let mut tags = read_dir_names(&self.repository_path(name).join("_tags"))?;
+        tags.sort();
+        Ok(tags)
+    }
+}
+
+/// Parse a manifest reference as a digest, if it is exactly a digest.
+///
+/// Parsing a [Digest] alone also accepts strings that merely start with a
+/// digest.
+pub(crate) fn reference_digest(reference: &str) -> Option<Digest> {
+    reference
+        .parse::<Digest>()
+        .ok()
+        .filter(|digest| digest.to_string() == reference)
+}
+
+/// Check that a tag matches the grammar of the spec.
+pub(crate) fn is_valid_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && !tag.starts_with('.')
+        && !tag.starts_with('-')
+        && !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// List the names of the entries of a directory, which may not exist.
+fn read_dir_names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    entries
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Remove a file, returning whether it existed.
+fn remove_if_exists(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compute the digest of a file.
+fn digest_file(path: &Path, algorithm: DigestAlgorithm) -> io::Result<Digest> {
+    use sha2::Digest as _;
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+
+    match algorithm {
+        DigestAlgorithm::Sha256 => {
+            let mut hasher = sha2::Sha256::new();
+
+            loop {
+                match file.read(&mut buf)? {
+                    0 => break,
+                    n => hasher.input(&buf[..n]),
+                }
+            }
+
+            Ok(Digest {
+                algorithm,
+                hex: format!("{:x}", hasher.result()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let digest = Digest::sha256(b"hello world");
+
+        let uuid = storage.start_upload("foo/bar").unwrap();
+        assert_eq!(storage.upload_size("foo/bar", &uuid).unwrap(), Some(0));
+        storage
+            .append_upload("foo/bar", &uuid, &mut &b"hello "[..])
+            .unwrap();
+        let size = storage
+            .append_upload("foo/bar", &uuid, &mut &b"world"[..])
+            .unwrap();
+        assert_eq!(size, 11);
+
+        assert!(storage.finish_upload("foo/bar", &uuid, &digest).unwrap());
+        assert_eq!(storage.upload_size("foo/bar", &uuid).unwrap(), None);
+        assert!(storage.blob_exists("foo/bar", &digest));
+        assert!(!storage.blob_exists("foo", &digest));
+
+        assert!(storage.mount_blob("foo", &digest, "foo/bar").unwrap());
+        assert!(storage.blob_exists("foo", &digest));
+        assert_eq!(storage.repositories().unwrap(), vec!["foo", "foo/bar"]);
+
+        let uuid = storage.start_upload("foo").unwrap();
+        storage
+            .append_upload("foo", &uuid, &mut &b"hello"[..])
+            .unwrap();
+        assert!(!storage.finish_upload("foo", &uuid, &digest).unwrap());
+    }
+
+    #[test]
+    fn test_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let media_type = "application/vnd.oci.image.manifest.v1+json";
+
+        let digest = storage
+            .put_manifest("foo", "latest", media_type, b"{}")
+            .unwrap();
+        storage
+            .put_manifest("foo", "stable", media_type, b"{}")
+            .unwrap();
+        assert_eq!(storage.tags("foo").unwrap(), vec!["latest", "stable"]);
+        assert_eq!(
+            storage.resolve("foo", "latest").unwrap(),
+            Some(digest.clone())
+        );
+        assert_eq!(
+            storage.get_manifest("foo", &digest).unwrap(),
+            Some((media_type.to_owned(), b"{}".to_vec()))
+        );
+
+        assert!(storage.delete_tag("foo", "stable").unwrap());
+        assert_eq!(storage.tags("foo").unwrap(), vec!["latest"]);
+
+        assert!(storage.delete_manifest("foo", &digest).unwrap());
+        assert!(storage.tags("foo").unwrap().is_empty());
+        assert_eq!(storage.resolve("foo", &digest.to_string()).unwrap(), None);
+
+        assert_eq!(storage.resolve("foo", "..").unwrap(), None);
+        assert_eq!(
+            storage.resolve("foo", &format!("{}-", digest)).unwrap(),
+            None
+        );
+        assert!(!storage.delete_tag("foo", "..").unwrap());
+        assert!(storage
+            .put_manifest("foo", "../latest", media_type, b"{}")
+            .is_err());
+        assert!(storage
+            .put_manifest("foo", &format!("{}junk", digest), media_type, b"{}")
+            .is_err());
+        assert_eq!(storage.resolve("foo", &digest.to_string()).unwrap(), None);
+    }
+}